@@ -0,0 +1,221 @@
+pub mod lattice_aead {
+    use zeroize::Zeroizing;
+
+    // XChaCha20-Poly1305: the ChaCha20-Poly1305 construction of RFC 8439 with the 24 byte nonce of
+    // draft-irtf-cfrg-xchacha, so nonces can be picked at random. Sealed messages are the ciphertext followed by the
+    // 16 byte tag.
+
+    const TAG_LEN: usize = 16;
+
+    fn quarter_round(state: &mut [u32; 16], a: usize, b: usize, c: usize, d: usize) {
+        state[a] = state[a].wrapping_add(state[b]);
+        state[d] = (state[d] ^ state[a]).rotate_left(16);
+        state[c] = state[c].wrapping_add(state[d]);
+        state[b] = (state[b] ^ state[c]).rotate_left(12);
+        state[a] = state[a].wrapping_add(state[b]);
+        state[d] = (state[d] ^ state[a]).rotate_left(8);
+        state[c] = state[c].wrapping_add(state[d]);
+        state[b] = (state[b] ^ state[c]).rotate_left(7);
+    }
+
+    fn initial_state(key: &[u8; 32], input: &[u8; 16]) -> Zeroizing<[u32; 16]> {
+        let mut state = Zeroizing::new([0u32; 16]);
+        state[..4].copy_from_slice(&[0x61707865, 0x3320646e, 0x79622d32, 0x6b206574]);
+        for (word, bytes) in state[4..].iter_mut().zip(key.chunks_exact(4).chain(input.chunks_exact(4))) {
+            *word = u32::from_le_bytes(bytes.try_into().unwrap());
+        }
+        state
+    }
+
+    fn rounds(state: &mut [u32; 16]) {
+        for _ in 0..10 {
+            quarter_round(state, 0, 4, 8, 12);
+            quarter_round(state, 1, 5, 9, 13);
+            quarter_round(state, 2, 6, 10, 14);
+            quarter_round(state, 3, 7, 11, 15);
+            quarter_round(state, 0, 5, 10, 15);
+            quarter_round(state, 1, 6, 11, 12);
+            quarter_round(state, 2, 7, 8, 13);
+            quarter_round(state, 3, 4, 9, 14);
+        }
+    }
+
+    // Derives the ChaCha20 key for the last 8 bytes of an extended nonce from its first 16
+    fn hchacha20(key: &[u8; 32], nonce: &[u8; 16]) -> Zeroizing<[u8; 32]> {
+        let mut state = initial_state(key, nonce);
+        rounds(&mut state);
+        let mut subkey = Zeroizing::new([0u8; 32]);
+        for (bytes, word) in subkey.chunks_exact_mut(4).zip(state[..4].iter().chain(state[12..].iter())) {
+            bytes.copy_from_slice(&word.to_le_bytes());
+        }
+        subkey
+    }
+
+    // XORs the ChaCha20 keystream starting at block counter into data
+    fn chacha20(key: &[u8; 32], nonce: &[u8; 12], counter: u32, data: &mut [u8]) {
+        let mut input = [0u8; 16];
+        input[4..].copy_from_slice(nonce);
+        for (block, chunk) in data.chunks_mut(64).enumerate() {
+            input[..4].copy_from_slice(&counter.wrapping_add(block as u32).to_le_bytes());
+            let initial = initial_state(key, &input);
+            let mut state = initial.clone();
+            rounds(&mut state);
+            let mut keystream = Zeroizing::new([0u8; 64]);
+            for ((bytes, word), start) in keystream.chunks_exact_mut(4).zip(state.iter()).zip(initial.iter()) {
+                bytes.copy_from_slice(&word.wrapping_add(*start).to_le_bytes());
+            }
+            for (byte, key_byte) in chunk.iter_mut().zip(keystream.iter()) {
+                *byte ^= key_byte;
+            }
+        }
+    }
+
+    // Poly1305 with 26 bit limbs, as in poly1305-donna
+    fn poly1305(key: &[u8; 32], message: &[u8]) -> [u8; TAG_LEN] {
+        let le = |bytes: &[u8]| u32::from_le_bytes(bytes[..4].try_into().unwrap());
+        let r = [
+            le(&key[0..]) & 0x3ffffff,
+            (le(&key[3..]) >> 2) & 0x3ffff03,
+            (le(&key[6..]) >> 4) & 0x3ffc0ff,
+            (le(&key[9..]) >> 6) & 0x3f03fff,
+            (le(&key[12..]) >> 8) & 0x00fffff,
+        ].map(u64::from);
+        let s = [r[1] * 5, r[2] * 5, r[3] * 5, r[4] * 5];
+        let mut h = [0u64; 5];
+
+        for chunk in message.chunks(16) {
+            let mut block = [0u8; 17];
+            block[..chunk.len()].copy_from_slice(chunk);
+            block[chunk.len()] = 1;
+            h[0] += u64::from(le(&block[0..]) & 0x3ffffff);
+            h[1] += u64::from((le(&block[3..]) >> 2) & 0x3ffffff);
+            h[2] += u64::from((le(&block[6..]) >> 4) & 0x3ffffff);
+            h[3] += u64::from((le(&block[9..]) >> 6) & 0x3ffffff);
+            h[4] += u64::from(le(&block[12..]) >> 8) | (u64::from(block[16]) << 24);
+
+            let d = [
+                h[0] * r[0] + h[1] * s[3] + h[2] * s[2] + h[3] * s[1] + h[4] * s[0],
+                h[0] * r[1] + h[1] * r[0] + h[2] * s[3] + h[3] * s[2] + h[4] * s[1],
+                h[0] * r[2] + h[1] * r[1] + h[2] * r[0] + h[3] * s[3] + h[4] * s[2],
+                h[0] * r[3] + h[1] * r[2] + h[2] * r[1] + h[3] * r[0] + h[4] * s[3],
+                h[0] * r[4] + h[1] * r[3] + h[2] * r[2] + h[3] * r[1] + h[4] * r[0],
+            ];
+            let mut carry = 0;
+            for i in 0..5 {
+                let value = d[i] + carry;
+                h[i] = value & 0x3ffffff;
+                carry = value >> 26;
+            }
+            h[0] += carry * 5;
+            h[1] += h[0] >> 26;
+            h[0] &= 0x3ffffff;
+        }
+
+        // Fully carry h, then subtract p = 2^130 - 5 when h is at least p
+        let mut carry = 0;
+        for limb in h.iter_mut().skip(1) {
+            *limb += carry;
+            carry = *limb >> 26;
+            *limb &= 0x3ffffff;
+        }
+        h[0] += carry * 5;
+        h[1] += h[0] >> 26;
+        h[0] &= 0x3ffffff;
+        let mut g = [0u64; 5];
+        let mut carry = 5;
+        for i in 0..5 {
+            g[i] = h[i] + carry;
+            carry = g[i] >> 26;
+            g[i] &= 0x3ffffff;
+        }
+        let use_g = 0u64.wrapping_sub(carry);
+        for i in 0..5 {
+            h[i] = (h[i] & !use_g) | (g[i] & use_g);
+        }
+
+        // The tag is (h + s) mod 2^128, so bits shifted out of the u128 don't matter
+        let tag = h.iter().enumerate()
+            .fold(u128::from_le_bytes(key[16..].try_into().unwrap()), |tag, (i, limb)| tag.wrapping_add(u128::from(*limb) << (26 * i)));
+        tag.to_le_bytes()
+    }
+
+    fn tag(key: &[u8; 32], nonce: &[u8; 12], aad: &[u8], ciphertext: &[u8]) -> [u8; TAG_LEN] {
+        let mut poly_key = Zeroizing::new([0u8; 32]);
+        chacha20(key, nonce, 0, &mut *poly_key);
+        let padding = |length: usize| (16 - length % 16) % 16;
+        let mut mac_data = Vec::with_capacity(aad.len() + ciphertext.len() + 48);
+        mac_data.extend_from_slice(aad);
+        mac_data.resize(mac_data.len() + padding(aad.len()), 0);
+        mac_data.extend_from_slice(ciphertext);
+        mac_data.resize(mac_data.len() + padding(ciphertext.len()), 0);
+        mac_data.extend_from_slice(&(aad.len() as u64).to_le_bytes());
+        mac_data.extend_from_slice(&(ciphertext.len() as u64).to_le_bytes());
+        poly1305(&poly_key, &mac_data)
+    }
+
+    fn subkey_and_nonce(key: &[u8; 32], nonce: &[u8; 24]) -> (Zeroizing<[u8; 32]>, [u8; 12]) {
+        let mut chacha_nonce = [0u8; 12];
+        chacha_nonce[4..].copy_from_slice(&nonce[16..]);
+        (hchacha20(key, nonce[..16].try_into().unwrap()), chacha_nonce)
+    }
+
+    // Encrypts plaintext and authenticates it together with aad
+    pub fn seal(key: &[u8; 32], nonce: &[u8; 24], aad: &[u8], plaintext: &[u8]) -> Vec<u8> {
+        let (subkey, chacha_nonce) = subkey_and_nonce(key, nonce);
+        let mut sealed = plaintext.to_vec();
+        chacha20(&subkey, &chacha_nonce, 1, &mut sealed);
+        let tag = tag(&subkey, &chacha_nonce, aad, &sealed);
+        sealed.extend_from_slice(&tag);
+        sealed
+    }
+
+    // Decrypts a sealed message, or returns None when it or aad was changed or the key is wrong
+    pub fn open(key: &[u8; 32], nonce: &[u8; 24], aad: &[u8], sealed: &[u8]) -> Option<Zeroizing<Vec<u8>>> {
+        let ciphertext_len = sealed.len().checked_sub(TAG_LEN)?;
+        let (ciphertext, expected) = sealed.split_at(ciphertext_len);
+        let (subkey, chacha_nonce) = subkey_and_nonce(key, nonce);
+        // Compared without stopping at the first difference, so timing does not tell how much of a forged tag matched
+        let difference = tag(&subkey, &chacha_nonce, aad, ciphertext).iter().zip(expected).fold(0, |difference, (a, b)| difference | (a ^ b));
+        if difference != 0 {
+            return None;
+        }
+        let mut plaintext = Zeroizing::new(ciphertext.to_vec());
+        chacha20(&subkey, &chacha_nonce, 1, &mut plaintext);
+        Some(plaintext)
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        fn from_hex(text: &str) -> Vec<u8> {
+            (0..text.len()).step_by(2).map(|i| u8::from_str_radix(&text[i..i + 2], 16).unwrap()).collect()
+        }
+
+        #[test]
+        fn poly1305_matches_rfc_8439() {
+            let key = from_hex("85d6be7857556d337f4452fe42d506a80103808afb0db2fd4abff6af4149f51b");
+            let tag = poly1305(&key.try_into().unwrap(), b"Cryptographic Forum Research Group");
+            assert_eq!(tag.to_vec(), from_hex("a8061dc1305136c6c22b8baf0c0127a9"));
+        }
+
+        #[test]
+        fn xchacha20_poly1305_matches_the_draft_test_vector() {
+            let key: [u8; 32] = from_hex("808182838485868788898a8b8c8d8e8f909192939495969798999a9b9c9d9e9f").try_into().unwrap();
+            let nonce: [u8; 24] = from_hex("404142434445464748494a4b4c4d4e4f5051525354555657").try_into().unwrap();
+            let aad = from_hex("50515253c0c1c2c3c4c5c6c7");
+            let plaintext = b"Ladies and Gentlemen of the class of '99: If I could offer you only one tip for the future, sunscreen would be it.";
+            let sealed = seal(&key, &nonce, &aad, plaintext);
+            assert_eq!(sealed, from_hex("bd6d179d3e83d43b9576579493c0e939572a1700252bfaccbed2902c21396cbb731c7f1b0b4aa6440bf3a82f4eda7e39\
+                ae64c6708c54c216cb96b72e1213b4522f8c9ba40db5d945b11b69b982c1bb9e3f3fac2bc369488f76b2383565d3fff921f9664c97637da9768812f6\
+                15c68b13b52ec0875924c1c7987947deafd8780acf49"));
+            assert_eq!(open(&key, &nonce, &aad, &sealed).unwrap().as_slice(), plaintext);
+
+            let mut tampered = sealed.clone();
+            tampered[0] ^= 1;
+            assert!(open(&key, &nonce, &aad, &tampered).is_none());
+            assert!(open(&key, &nonce, b"other", &sealed).is_none());
+            assert!(open(&key, &nonce, &aad, &sealed[..15]).is_none());
+        }
+    }
+}
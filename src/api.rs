@@ -0,0 +1,727 @@
+pub mod lattice_api {
+    use std::convert::Infallible;
+    use std::fmt;
+    use std::fs;
+    use std::time::Duration;
+    use actix_web::rt::time::sleep;
+    use futures::stream;
+    use actix_web::http::StatusCode;
+    use actix_web::{delete, get, post, put, web, HttpRequest, HttpResponse, ResponseError, Scope};
+    use base64::engine::general_purpose::STANDARD;
+    use base64::Engine;
+    use serde::{Deserialize, Serialize};
+    use std::future::{ready, Ready};
+    use actix_web::dev::Payload;
+    use actix_web::FromRequest;
+    use crate::auth::lattice_auth::{is_valid_user_name, Auth, User};
+    use crate::keystore::lattice_keystore::{Keystore, StoredKey};
+    use crate::decrypted::lattice_decrypt::read_key_fingerprint;
+    use crate::jobs::lattice_jobs::Job;
+    use crate::queue::lattice_queue::{JobQueue, JobState, JobStatus, QueueError, TaskContext};
+    use crate::encrypted::lattice_encrypt::{generate_key_matrices, key_fingerprint, lattice_encrypt_csv_returning_secret_key, lattice_encrypt_csv_with_key, lattice_encrypt_numeric_csv_returning_secret_key, lattice_encrypt_numeric_csv_with_key};
+    use crate::decrypted::lattice_decrypt::lattice_decrypt_to_writer;
+    use crate::keyfile::lattice_keyfile::{parse_secret_key, wrap_secret_key};
+    use crate::sharing::lattice_sharing::{combine_key_shares, parse_key_share};
+    use crate::verification::lattice_verification::verify_key_contents;
+    use crate::inspect::lattice_inspect::{inspect_contents, FileSummary};
+    use crate::config::lattice_config::LimitsConfig;
+    use crate::progress::lattice_progress::Progress;
+    use crate::secret::lattice_secret::SecretMatrix;
+    use crate::archive::lattice_archive::{chunk_stream, read_chunks};
+    use crate::audit::lattice_audit::{AuditEvent, AuditLog, Operation};
+    use crate::metrics::lattice_metrics::ErrorKind;
+    use zeroize::Zeroizing;
+
+    // Header carrying the passphrase when /encrypt is sent a raw CSV body instead of JSON
+    pub const PASSPHRASE_HEADER: &str = "X-Lattice-Passphrase";
+
+    // Key generation inverts a size x size matrix, so the size is capped to keep one request from tying up the server
+    const MAX_KEY_SIZE: usize = 1024;
+
+    // All JSON endpoints, mounted under /api/v1. Payloads are base64 in JSON, except that /encrypt also takes the
+    // CSV as a raw application/octet-stream body and /decrypt returns the raw CSV when asked for octet-stream.
+    // Every endpoint needs an API token in Authorization: Bearer <token>, or a login session.
+    // /encrypt and /decrypt run in the background when asked with "async": true (or ?async=true for a raw body),
+    // answering 202 with a job id to follow on /jobs/{id}.
+    pub fn api_scope(max_body_bytes: usize) -> Scope {
+        web::scope("/api/v1")
+            .app_data(web::JsonConfig::default()
+                .limit(max_body_bytes)
+                .error_handler(move |error, _| match error {
+                    actix_web::error::JsonPayloadError::Overflow { .. } | actix_web::error::JsonPayloadError::OverflowKnownLength { .. } =>
+                        ApiError::too_large(max_body_bytes).into(),
+                    error => ApiError::bad_request(error.to_string()).into(),
+                }))
+            .app_data(web::PayloadConfig::new(max_body_bytes))
+            .service(keygen_handler)
+            .service(encrypt_handler)
+            .service(decrypt_handler)
+            .service(verify_handler)
+            .service(inspect_handler)
+            .service(job_handler)
+            .service(job_events_handler)
+            .service(cancel_job_handler)
+            .service(keys_handler)
+            .service(delete_key_handler)
+            .service(grant_key_handler)
+            .service(revoke_key_handler)
+    }
+
+    // Every error is returned as {"error": {"code": ..., "message": ...}} with a matching status
+    #[derive(Debug)]
+    pub struct ApiError {
+        status: StatusCode,
+        code: &'static str,
+        message: String,
+    }
+
+    #[derive(Serialize)]
+    struct ErrorBody<'a> {
+        code: &'a str,
+        message: &'a str,
+    }
+
+    #[derive(Serialize)]
+    struct ErrorResponse<'a> {
+        error: ErrorBody<'a>,
+    }
+
+    impl ApiError {
+        fn bad_request(message: impl Into<String>) -> ApiError {
+            ApiError { status: StatusCode::BAD_REQUEST, code: "invalid_request", message: message.into() }
+        }
+
+        fn unauthorized() -> ApiError {
+            ApiError { status: StatusCode::UNAUTHORIZED, code: "unauthorized", message: "Send an API token as Authorization: Bearer <token>".to_string() }
+        }
+
+        fn keystore_disabled() -> ApiError {
+            ApiError { status: StatusCode::BAD_REQUEST, code: "keystore_disabled", message: "The keystore is not enabled on this server".to_string() }
+        }
+
+        fn key_not_found(key_id: &str) -> ApiError {
+            ApiError { status: StatusCode::NOT_FOUND, code: "key_not_found", message: format!("No stored key {}", key_id) }
+        }
+
+        fn job_not_found(id: &str) -> ApiError {
+            ApiError { status: StatusCode::NOT_FOUND, code: "job_not_found", message: format!("No job {}", id) }
+        }
+
+        pub fn rate_limited(message: impl Into<String>) -> ApiError {
+            ApiError { status: StatusCode::TOO_MANY_REQUESTS, code: "rate_limited", message: message.into() }
+        }
+
+        fn too_large(limit: usize) -> ApiError {
+            ApiError { status: StatusCode::PAYLOAD_TOO_LARGE, code: "payload_too_large", message: format!("Request body is larger than the limit of {} bytes", limit) }
+        }
+
+        // The lattice library reports failures as Box<dyn Error> or io::Error, and its messages are meant for users
+        fn failed(code: &'static str, error: impl fmt::Display) -> ApiError {
+            ApiError { status: StatusCode::UNPROCESSABLE_ENTITY, code, message: error.to_string() }
+        }
+
+        fn internal(error: impl fmt::Display) -> ApiError {
+            eprintln!("API request failed: {}", error);
+            ApiError { status: StatusCode::INTERNAL_SERVER_ERROR, code: "internal_error", message: "Internal server error".to_string() }
+        }
+    }
+
+    impl From<QueueError> for ApiError {
+        fn from(error: QueueError) -> ApiError {
+            match error {
+                QueueError::Full => ApiError { status: StatusCode::SERVICE_UNAVAILABLE, code: "queue_full", message: error.to_string() },
+                QueueError::Cancelled => ApiError { status: StatusCode::CONFLICT, code: "job_cancelled", message: error.to_string() },
+                QueueError::Failed(message) => ApiError::internal(message),
+            }
+        }
+    }
+
+    impl fmt::Display for ApiError {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            write!(f, "{}: {}", self.code, self.message)
+        }
+    }
+
+    impl ErrorKind for ApiError {
+        fn kind(&self) -> &'static str {
+            self.code
+        }
+    }
+
+    impl ResponseError for ApiError {
+        fn status_code(&self) -> StatusCode {
+            self.status
+        }
+
+        fn error_response(&self) -> HttpResponse {
+            HttpResponse::build(self.status).json(ErrorResponse { error: ErrorBody { code: self.code, message: &self.message } })
+        }
+    }
+
+    // The user making an API request. Unlike User it rejects unauthenticated requests with the API's JSON error.
+    struct ApiUser(User);
+
+    impl FromRequest for ApiUser {
+        type Error = ApiError;
+        type Future = Ready<Result<ApiUser, ApiError>>;
+
+        fn from_request(request: &HttpRequest, _: &mut Payload) -> Self::Future {
+            let user = request.app_data::<web::Data<Auth>>().and_then(|auth| auth.authenticate(request));
+            ready(user.map(ApiUser).ok_or_else(ApiError::unauthorized))
+        }
+    }
+
+    fn decode(field: &str, value: &str) -> Result<Vec<u8>, ApiError> {
+        STANDARD.decode(value).map_err(|_| ApiError::bad_request(format!("{} is not valid base64", field)))
+    }
+
+    fn read_output(job: &Job, name: &str) -> Result<String, ApiError> {
+        fs::read(job.output_path(name)).map(|contents| STANDARD.encode(contents)).map_err(ApiError::internal)
+    }
+
+    fn write_input(job: &Job, name: &str, contents: &[u8]) -> Result<String, ApiError> {
+        let path = job.input_path(name);
+        fs::write(&path, contents).map_err(ApiError::internal)?;
+        Ok(path)
+    }
+
+    // The keystore is only registered when it is turned on
+    fn keystore(keystore: &Option<web::Data<Keystore>>) -> Result<&Keystore, ApiError> {
+        keystore.as_ref().map(|keystore| keystore.get_ref()).ok_or_else(ApiError::keystore_disabled)
+    }
+
+    fn load_key(keystore: &Keystore, key_id: &str, user: &str) -> Result<SecretMatrix, ApiError> {
+        match keystore.load(key_id, user).map_err(ApiError::internal)? {
+            Some((_, secret_key)) => Ok(secret_key),
+            None => Err(ApiError::key_not_found(key_id)),
+        }
+    }
+
+    // Runs a request's work on the job queue in its own job, so it does not hold up the server's workers. The job is
+    // removed afterwards unless keep is set and the request succeeded.
+    async fn queued_job<T: Send + 'static>(user: &ApiUser, queue: &JobQueue, keep: bool, run: impl FnOnce(&Job, &TaskContext) -> Result<T, ApiError> + Send + 'static) -> Result<T, ApiError> {
+        let job = Job::create(&user.0.name).map_err(ApiError::internal)?;
+        let task_job = job.clone();
+        let result = match queue.run(&job, &user.0.name, move |context| run(&task_job, context)).await {
+            Ok(result) => result,
+            Err(error) => Err(error.into()),
+        };
+        if !keep || result.is_err() {
+            job.remove();
+        }
+        result
+    }
+
+    #[derive(Serialize)]
+    struct JobStatusResponse {
+        job_id: String,
+        #[serde(flatten)]
+        status: JobStatus,
+    }
+
+    // Queues a request's work in its own job and answers 202 at once. The work leaves its results in the job.
+    fn background_job(user: &ApiUser, queue: &JobQueue, run: impl FnOnce(&Job, &TaskContext) -> Result<(), ApiError> + Send + 'static) -> Result<HttpResponse, ApiError> {
+        let job = Job::create(&user.0.name).map_err(ApiError::internal)?;
+        let task_job = job.clone();
+        if let Err(error) = queue.submit(&job, &user.0.name, move |context| run(&task_job, context)) {
+            job.remove();
+            return Err(error.into());
+        }
+        let status = queue.status(&job.id).unwrap_or(JobStatus { state: JobState::Queued, progress: 0, stage: None, error: None });
+        Ok(HttpResponse::Accepted()
+            .insert_header(("Location", format!("/api/v1/jobs/{}", job.id)))
+            .json(JobStatusResponse { job_id: job.id.clone(), status }))
+    }
+
+    #[derive(Deserialize)]
+    struct KeygenRequest {
+        size: usize,
+        passphrase: Option<String>,
+        // Keep the secret key in the keystore instead of returning it
+        #[serde(default)]
+        store: bool,
+    }
+
+    #[derive(Serialize)]
+    struct KeygenResponse {
+        fingerprint: String,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        key_id: Option<String>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        secret_key: Option<String>,
+        public_key: String,
+    }
+
+    // Generates a key pair. It is either returned, or stored and referred to by its key_id from then on.
+    #[post("/keygen")]
+    async fn keygen_handler(user: ApiUser, request: web::Json<KeygenRequest>, keystore: Option<web::Data<Keystore>>, queue: web::Data<JobQueue>, audit: web::Data<AuditLog>) -> Result<HttpResponse, ApiError> {
+        if request.size == 0 || request.size > MAX_KEY_SIZE {
+            return Err(ApiError::bad_request(format!("size must be between 1 and {}", MAX_KEY_SIZE)));
+        }
+        let request = request.into_inner();
+        if request.store {
+            self::keystore(&keystore)?;
+            if request.passphrase.as_deref().is_some_and(|passphrase| !passphrase.is_empty()) {
+                return Err(ApiError::bad_request("A stored key is sealed under the keystore's master key, passphrase can't be used with store"));
+            }
+        }
+        let owner = user.0.name.clone();
+        let response = queued_job(&user, &queue, false, move |_, context| {
+            let mut event = AuditEvent::start(&owner, Operation::Keygen);
+            let response = generate_key(&request, &keystore, &owner, context);
+            if let Ok(response) = &response {
+                event.key(Some(response.fingerprint.clone()));
+            }
+            audit.record(event, &response);
+            response
+        }).await?;
+        Ok(HttpResponse::Ok().json(response))
+    }
+
+    fn generate_key(request: &KeygenRequest, keystore: &Option<web::Data<Keystore>>, owner: &str, progress: &dyn Progress) -> Result<KeygenResponse, ApiError> {
+        // The key is generated and sent back from memory, it never touches the job folder
+        let (_, secret_key) = generate_key_matrices(request.size, progress).ok_or_else(|| ApiError::failed("keygen_failed", "Failed to generate keys"))?;
+        let public_key = STANDARD.encode(secret_key.norm().to_string());
+        if request.store {
+            let stored = self::keystore(keystore)?.store(owner, &secret_key).map_err(ApiError::internal)?;
+            return Ok(KeygenResponse {
+                fingerprint: stored.key_id.clone(),
+                key_id: Some(stored.key_id),
+                secret_key: None,
+                public_key,
+            });
+        }
+
+        let secret_key_file = match request.passphrase.as_deref().filter(|passphrase| !passphrase.is_empty()) {
+            Some(passphrase) => Zeroizing::new(wrap_secret_key(&secret_key.to_text(), passphrase).map_err(ApiError::internal)?),
+            None => secret_key.to_text(),
+        };
+        Ok(KeygenResponse {
+            fingerprint: key_fingerprint(&secret_key),
+            key_id: None,
+            secret_key: Some(STANDARD.encode(secret_key_file.as_bytes())),
+            public_key,
+        })
+    }
+
+    #[derive(Deserialize)]
+    struct EncryptRequest {
+        csv: String,
+        #[serde(default)]
+        numeric: bool,
+        passphrase: Option<String>,
+        // Encrypt with a stored key instead of a new one
+        key_id: Option<String>,
+        // Keep a new key in the keystore instead of returning it
+        #[serde(default)]
+        store: bool,
+        #[serde(default, rename = "async")]
+        background: bool,
+    }
+
+    #[derive(Deserialize)]
+    struct EncryptQuery {
+        #[serde(default)]
+        numeric: bool,
+        key_id: Option<String>,
+        #[serde(default)]
+        store: bool,
+        #[serde(default, rename = "async")]
+        background: bool,
+    }
+
+    #[derive(Serialize)]
+    struct EncryptResponse {
+        job_id: String,
+        fingerprint: String,
+        encrypted_matrix: String,
+        // Only for a new key that was stored
+        #[serde(skip_serializing_if = "Option::is_none")]
+        key_id: Option<String>,
+        // Only for a new key that was not stored, a stored key stays on the server
+        #[serde(skip_serializing_if = "Option::is_none")]
+        secret_key: Option<String>,
+        public_key: String,
+    }
+
+    // What becomes of a new key once a CSV is encrypted with it
+    enum NewKey {
+        // Returned to the client, wrapped under the passphrase when one is given
+        Return(Option<String>),
+        // Kept in the keystore
+        Store(web::Data<Keystore>),
+    }
+
+    // Encrypts a CSV into the job's output folder and records the encryption in the audit log. Returns the key's
+    // fingerprint and, for a new key, the contents of its secret key file.
+    #[allow(clippy::too_many_arguments)]
+    fn encrypt_csv(job: &Job, csv: &[u8], numeric: bool, stored_key: Option<&SecretMatrix>, new_key: &NewKey, audit: &AuditLog, user: &str, progress: &dyn Progress) -> Result<(String, Option<Zeroizing<String>>), ApiError> {
+        let mut event = AuditEvent::start(user, Operation::Encrypt);
+        event.input(csv);
+        event.key(stored_key.map(|key| key_fingerprint(key)));
+        let result = encrypt_csv_with(job, csv, numeric, stored_key, new_key, user, progress);
+        if let Ok((fingerprint, _)) = &result {
+            event.key(Some(fingerprint.clone()));
+        }
+        audit.record(event, &result);
+        result
+    }
+
+    fn encrypt_csv_with(job: &Job, csv: &[u8], numeric: bool, stored_key: Option<&SecretMatrix>, new_key: &NewKey, user: &str, progress: &dyn Progress) -> Result<(String, Option<Zeroizing<String>>), ApiError> {
+        let input_path = write_input(job, "input.csv", csv)?;
+        let output_dir = job.output_dir_str();
+        let generated = match stored_key {
+            Some(key) if numeric => lattice_encrypt_numeric_csv_with_key(&input_path, &output_dir, key, progress).map(|_| None),
+            Some(key) => lattice_encrypt_csv_with_key(&input_path, &output_dir, key, progress).map(|_| None),
+            None if numeric => lattice_encrypt_numeric_csv_returning_secret_key(&input_path, &output_dir, progress).map(Some),
+            None => lattice_encrypt_csv_returning_secret_key(&input_path, &output_dir, progress).map(Some),
+        }.map_err(|error| ApiError::failed("encryption_failed", error))?;
+        let _ = fs::remove_file(&input_path);
+
+        let (secret_key, secret_key_file) = match (stored_key, &generated) {
+            (Some(key), _) => (key, None),
+            (None, Some(key)) => match new_key {
+                NewKey::Store(keystore) => {
+                    keystore.store(user, key).map_err(ApiError::internal)?;
+                    (key, None)
+                },
+                NewKey::Return(Some(passphrase)) => (key, Some(Zeroizing::new(wrap_secret_key(&key.to_text(), passphrase).map_err(ApiError::internal)?))),
+                NewKey::Return(None) => (key, Some(key.to_text())),
+            },
+            (None, None) => return Err(ApiError::internal("Encryption returned no key")),
+        };
+        Ok((key_fingerprint(secret_key), secret_key_file))
+    }
+
+    // Encrypts a CSV. The encrypted matrix and public key stay downloadable from /jobs/{job_id}/{file}; the secret
+    // key of a new key is only returned here, or kept in the keystore with store. It is never left in the job.
+    #[post("/encrypt")]
+    async fn encrypt_handler(user: ApiUser, http_request: HttpRequest, body: Result<web::Bytes, actix_web::Error>, limits: web::Data<LimitsConfig>, keystore: Option<web::Data<Keystore>>, queue: web::Data<JobQueue>, audit: web::Data<AuditLog>) -> Result<HttpResponse, ApiError> {
+        // A raw body has no JSON error handler, so its overflow is turned into the API's error here
+        let body = body.map_err(|error| match error.as_response_error().status_code() {
+            StatusCode::PAYLOAD_TOO_LARGE => ApiError::too_large(limits.max_api_body_bytes),
+            _ => ApiError::bad_request(error.to_string()),
+        })?;
+        let content_type = http_request.headers().get("Content-Type").and_then(|value| value.to_str().ok()).unwrap_or("");
+        let (csv, numeric, passphrase, key_id, store, background) = if content_type.starts_with("application/octet-stream") {
+            let query = web::Query::<EncryptQuery>::from_query(http_request.query_string())
+                .map_err(|error| ApiError::bad_request(error.to_string()))?;
+            let passphrase = http_request.headers().get(PASSPHRASE_HEADER)
+                .map(|value| value.to_str().map(str::to_string).map_err(|_| ApiError::bad_request(format!("{} is not valid text", PASSPHRASE_HEADER))))
+                .transpose()?;
+            let query = query.into_inner();
+            (body.to_vec(), query.numeric, passphrase, query.key_id, query.store, query.background)
+        } else if content_type.starts_with("application/json") {
+            let request: EncryptRequest = serde_json::from_slice(&body).map_err(|error| ApiError::bad_request(error.to_string()))?;
+            (decode("csv", &request.csv)?, request.numeric, request.passphrase, request.key_id, request.store, request.background)
+        } else {
+            return Err(ApiError::bad_request("Content-Type must be application/json or application/octet-stream"));
+        };
+        let passphrase = passphrase.filter(|passphrase| !passphrase.is_empty());
+        let stored_key = match key_id.as_deref().filter(|key_id| !key_id.is_empty()) {
+            Some(key_id) => Some(load_key(self::keystore(&keystore)?, key_id, &user.0.name)?),
+            None => None,
+        };
+        let new_key = if store && stored_key.is_none() {
+            if passphrase.is_some() {
+                return Err(ApiError::bad_request("A stored key is sealed under the keystore's master key, passphrase can't be used with store"));
+            }
+            NewKey::Store(keystore.clone().ok_or_else(ApiError::keystore_disabled)?)
+        } else {
+            NewKey::Return(passphrase)
+        };
+
+        let owner = user.0.name.clone();
+        if background {
+            // Nobody waits for the response, so nothing would hand a new secret key back
+            if stored_key.is_none() && !matches!(new_key, NewKey::Store(_)) {
+                return Err(ApiError::bad_request("A new secret key is only returned to a request that waits for it. Encrypt without async, or with store to keep the key in the keystore"));
+            }
+            return background_job(&user, &queue, move |job, context| {
+                encrypt_csv(job, &csv, numeric, stored_key.as_ref(), &new_key, &audit, &owner, context).map(|_| ())
+            });
+        }
+        let response = queued_job(&user, &queue, true, move |job, context| {
+            let (fingerprint, secret_key_file) = encrypt_csv(job, &csv, numeric, stored_key.as_ref(), &new_key, &audit, &owner, context)?;
+            Ok(EncryptResponse {
+                job_id: job.id.clone(),
+                key_id: matches!(new_key, NewKey::Store(_)).then(|| fingerprint.clone()),
+                fingerprint,
+                encrypted_matrix: read_output(job, "encrypted_matrix.csv")?,
+                secret_key: secret_key_file.map(|contents| STANDARD.encode(contents.as_bytes())),
+                public_key: read_output(job, "public_key.key")?,
+            })
+        }).await?;
+        Ok(HttpResponse::Ok().json(response))
+    }
+
+    #[derive(Deserialize)]
+    struct DecryptRequest {
+        encrypted_matrix: String,
+        // Either the secret key or at least the threshold number of key shares. With neither, the key is looked up
+        // in the keystore by the fingerprint the encrypted matrix carries.
+        secret_key: Option<String>,
+        #[serde(default)]
+        key_shares: Vec<String>,
+        passphrase: Option<String>,
+        #[serde(default, rename = "async")]
+        background: bool,
+    }
+
+    #[derive(Serialize)]
+    struct DecryptResponse<'a> {
+        csv: &'a str,
+    }
+
+    // Key material sent in a request, decoded and parsed in memory so it is never written to the job folder
+    fn decode_secret(field: &str, value: &str) -> Result<Zeroizing<String>, ApiError> {
+        let bytes = Zeroizing::new(decode(field, value)?);
+        std::str::from_utf8(&bytes).map(|text| Zeroizing::new(text.to_string()))
+            .map_err(|_| ApiError::bad_request(format!("{} is not valid text", field)))
+    }
+
+    // Decrypts a request's matrix into output and records the decryption in the audit log. Only the encrypted matrix
+    // is written to the job's input folder.
+    fn decrypt_matrix(job: &Job, request: &DecryptRequest, keystore: &Option<web::Data<Keystore>>, user: &str, audit: &AuditLog, output: impl std::io::Write, progress: &dyn Progress) -> Result<(), ApiError> {
+        let mut event = AuditEvent::start(user, Operation::Decrypt);
+        let result = decrypt_matrix_with(job, request, keystore, user, &mut event, output, progress);
+        audit.record(event, &result);
+        result
+    }
+
+    fn decrypt_matrix_with(job: &Job, request: &DecryptRequest, keystore: &Option<web::Data<Keystore>>, user: &str, event: &mut AuditEvent, output: impl std::io::Write, progress: &dyn Progress) -> Result<(), ApiError> {
+        let encrypted_matrix_path = write_input(job, "encrypted_matrix.csv", &decode("encrypted_matrix", &request.encrypted_matrix)?)?;
+        event.input_file(&encrypted_matrix_path);
+        event.key(read_key_fingerprint(&encrypted_matrix_path).ok().flatten());
+        let secret_key = match &request.secret_key {
+            Some(secret_key) => {
+                parse_secret_key(&decode_secret("secret_key", secret_key)?, request.passphrase.as_deref().filter(|passphrase| !passphrase.is_empty()))
+                    .map_err(|error| ApiError::failed("invalid_key", error))?
+            }
+            None if !request.key_shares.is_empty() => {
+                let mut shares = Vec::new();
+                for (index, share) in request.key_shares.iter().enumerate() {
+                    shares.push(parse_key_share(&decode_secret("key_shares", share)?, &format!("key_shares[{}]", index))
+                        .map_err(|error| ApiError::failed("invalid_key", error))?);
+                }
+                combine_key_shares(&shares).map_err(|error| ApiError::failed("invalid_key", error))?
+            }
+            None if keystore.is_some() => {
+                let fingerprint = read_key_fingerprint(&encrypted_matrix_path).map_err(ApiError::internal)?
+                    .ok_or_else(|| ApiError::bad_request("The encrypted matrix names no key, send secret_key or key_shares"))?;
+                load_key(self::keystore(keystore)?, &fingerprint, user)?
+            }
+            None => return Err(ApiError::bad_request("secret_key or key_shares is required")),
+        };
+        event.key(Some(key_fingerprint(&secret_key)));
+
+        lattice_decrypt_to_writer(&encrypted_matrix_path, &secret_key, output, progress)
+            .map_err(|error| ApiError::failed("decryption_failed", error))
+    }
+
+    // Decrypts a matrix. The recovered CSV is kept in memory and never written to disk, except output.csv when run
+    // in the background, which can be downloaded once from /jobs/{job_id}/output.csv.
+    #[post("/decrypt")]
+    async fn decrypt_handler(user: ApiUser, http_request: HttpRequest, request: web::Json<DecryptRequest>, keystore: Option<web::Data<Keystore>>, queue: web::Data<JobQueue>, audit: web::Data<AuditLog>) -> Result<HttpResponse, ApiError> {
+        let request = request.into_inner();
+        let owner = user.0.name.clone();
+        if request.background {
+            return background_job(&user, &queue, move |job, context| {
+                let output = fs::File::create(job.output_path("output.csv")).map_err(ApiError::internal)?;
+                decrypt_matrix(job, &request, &keystore, &owner, &audit, output, context)
+            });
+        }
+        let csv = queued_job(&user, &queue, false, move |job, context| {
+            // The CSV is shorter than its encrypted matrix, so the buffer never grows and leaves copies behind
+            let mut csv = Zeroizing::new(Vec::with_capacity(request.encrypted_matrix.len()));
+            decrypt_matrix(job, &request, &keystore, &owner, &audit, &mut *csv, context)?;
+            Ok(csv)
+        }).await?;
+
+        let accept = http_request.headers().get("Accept").and_then(|value| value.to_str().ok()).unwrap_or("");
+        if accept.starts_with("application/octet-stream") {
+            // Streamed out of the buffer, which is zeroed once the response is done with it
+            return Ok(HttpResponse::Ok().content_type("application/octet-stream").streaming(chunk_stream(read_chunks(std::io::Cursor::new(csv)))));
+        }
+        // The base64 and the JSON around it are built in buffers of their final size, zeroed once sent
+        let encoded = Zeroizing::new(STANDARD.encode(&*csv));
+        let mut body = Zeroizing::new(Vec::with_capacity(encoded.len() + 16));
+        serde_json::to_writer(&mut *body, &DecryptResponse { csv: &encoded }).map_err(ApiError::internal)?;
+        Ok(HttpResponse::Ok().content_type("application/json").body(web::Bytes::from_owner(body)))
+    }
+
+    #[derive(Deserialize)]
+    struct VerifyRequest {
+        secret_key: String,
+        public_key: String,
+        passphrase: Option<String>,
+    }
+
+    #[derive(Serialize)]
+    struct VerifyResponse {
+        valid: bool,
+    }
+
+    // Checks that a public key belongs to a secret key
+    #[post("/verify")]
+    async fn verify_handler(_user: ApiUser, request: web::Json<VerifyRequest>) -> Result<HttpResponse, ApiError> {
+        // Both keys are checked in memory, the secret key is never written to disk. Unwrapping a passphrase protected
+        // key takes a slow key derivation, so it runs on the blocking thread pool.
+        let secret_key = decode_secret("secret_key", &request.secret_key)?;
+        let public_key = decode_secret("public_key", &request.public_key)?;
+        let passphrase = request.into_inner().passphrase.filter(|passphrase| !passphrase.is_empty()).map(Zeroizing::new);
+        let valid = web::block(move || verify_key_contents(&secret_key, &public_key, passphrase.as_deref().map(String::as_str)).map_err(|error| error.to_string()))
+            .await.map_err(ApiError::internal)?
+            .map_err(|error| ApiError::failed("verification_failed", error))?;
+        Ok(HttpResponse::Ok().json(VerifyResponse { valid }))
+    }
+
+    #[derive(Deserialize)]
+    struct InspectRequest {
+        file: String,
+        passphrase: Option<String>,
+    }
+
+    // Describes an encrypted matrix, key, key share or re-key token
+    #[post("/inspect")]
+    async fn inspect_handler(_user: ApiUser, request: web::Json<InspectRequest>) -> Result<HttpResponse, ApiError> {
+        // The file may be a secret key, so it is inspected in memory and never written to disk, on the blocking thread
+        // pool like verification
+        let contents = decode_secret("file", &request.file)?;
+        let passphrase = request.into_inner().passphrase.filter(|passphrase| !passphrase.is_empty()).map(Zeroizing::new);
+        let summary: FileSummary = web::block(move || inspect_contents(&contents, passphrase.as_deref().map(String::as_str)).map_err(|error| error.to_string()))
+            .await.map_err(ApiError::internal)?
+            .map_err(|error| ApiError::failed("inspection_failed", error))?;
+        Ok(HttpResponse::Ok().json(summary))
+    }
+
+    #[derive(Serialize)]
+    struct JobFile {
+        name: String,
+        size: u64,
+    }
+
+    #[derive(Serialize)]
+    struct JobResponse {
+        job_id: String,
+        created: u64,
+        #[serde(flatten)]
+        status: JobStatus,
+        // Each file can be downloaded from /jobs/{job_id}/{name}, listed once the job has succeeded
+        files: Vec<JobFile>,
+    }
+
+    // Describes a job's state, progress and output files. Jobs are only found by id, they are never listed.
+    #[get("/jobs/{id}")]
+    async fn job_handler(user: ApiUser, id: web::Path<String>, queue: web::Data<JobQueue>) -> Result<HttpResponse, ApiError> {
+        let job = Job::open(&id, &user.0.name).ok_or_else(|| ApiError::job_not_found(&id))?;
+        // Only a successful job keeps its folder, so the files of one the queue no longer knows are listed as well
+        let status = queue.status_or_unknown(&job.id);
+        let files = match status.state {
+            JobState::Succeeded | JobState::Unknown => job.output_files().map_err(ApiError::internal)?
+                .into_iter().map(|(name, size)| JobFile { name, size }).collect(),
+            _ => Vec::new(),
+        };
+        Ok(HttpResponse::Ok().json(JobResponse { job_id: job.id.clone(), created: job.created().map_err(ApiError::internal)?, status, files }))
+    }
+
+    // How often a job's status is checked for job_events_handler, and how many checks without a change pass before
+    // a comment is sent to keep the connection open
+    const EVENTS_POLL: Duration = Duration::from_millis(250);
+    const EVENTS_KEEP_ALIVE_POLLS: u32 = 60;
+
+    // Streams a job's status as server-sent events, one each time it changes, and ends after the job has finished.
+    // Browsers listen with EventSource, which sends the session cookie.
+    #[get("/jobs/{id}/events")]
+    async fn job_events_handler(user: ApiUser, id: web::Path<String>, queue: web::Data<JobQueue>) -> Result<HttpResponse, ApiError> {
+        // A job that failed or was cancelled has no folder left, but its status is still worth sending
+        let job_id = match Job::open(&id, &user.0.name) {
+            Some(job) => job.id,
+            None => queue.owned_status(&id, &user.0.name).map(|_| id.to_string()).ok_or_else(|| ApiError::job_not_found(&id))?,
+        };
+        let events = stream::unfold((Some(job_id), None), move |(job_id, last)| {
+            let queue = queue.clone();
+            async move {
+                let job_id = job_id?;
+                let mut polls = 0;
+                loop {
+                    let status = queue.status_or_unknown(&job_id);
+                    if last.as_ref() != Some(&status) {
+                        let event = serde_json::to_string(&JobStatusResponse { job_id: job_id.clone(), status: status.clone() }).ok()?;
+                        let next = if status.state.is_finished() { None } else { Some(job_id) };
+                        return Some((Ok::<_, Infallible>(web::Bytes::from(format!("data: {}\n\n", event))), (next, Some(status))));
+                    }
+                    polls += 1;
+                    if polls == EVENTS_KEEP_ALIVE_POLLS {
+                        return Some((Ok(web::Bytes::from_static(b": keep-alive\n\n")), (Some(job_id), last)));
+                    }
+                    sleep(EVENTS_POLL).await;
+                }
+            }
+        });
+        Ok(HttpResponse::Ok()
+            .content_type("text/event-stream")
+            .insert_header(("Cache-Control", "no-cache"))
+            .streaming(events))
+    }
+
+    // Cancels a queued or running job, or deletes a finished one with its files. A running job stops at its next
+    // check and gets 202, everything else is gone when this returns 204.
+    #[delete("/jobs/{id}")]
+    async fn cancel_job_handler(user: ApiUser, id: web::Path<String>, queue: web::Data<JobQueue>) -> Result<HttpResponse, ApiError> {
+        let job = Job::open(&id, &user.0.name).ok_or_else(|| ApiError::job_not_found(&id))?;
+        match queue.cancel(&job.id, &user.0.name) {
+            Some(status) if status.state == JobState::Cancelling => Ok(HttpResponse::Accepted().json(JobStatusResponse { job_id: job.id.clone(), status })),
+            Some(_) => Ok(HttpResponse::NoContent().finish()),
+            None => {
+                job.remove();
+                Ok(HttpResponse::NoContent().finish())
+            }
+        }
+    }
+
+    #[derive(Serialize)]
+    struct KeysResponse {
+        keys: Vec<StoredKey>,
+    }
+
+    // Lists the stored keys the user owns or was given
+    #[get("/keys")]
+    async fn keys_handler(user: ApiUser, keystore: Option<web::Data<Keystore>>) -> Result<HttpResponse, ApiError> {
+        let keys = self::keystore(&keystore)?.list(&user.0.name).map_err(ApiError::internal)?;
+        Ok(HttpResponse::Ok().json(KeysResponse { keys }))
+    }
+
+    // Deletes a stored key. Only its owner can.
+    #[delete("/keys/{key_id}")]
+    async fn delete_key_handler(user: ApiUser, key_id: web::Path<String>, keystore: Option<web::Data<Keystore>>) -> Result<HttpResponse, ApiError> {
+        if !self::keystore(&keystore)?.delete(&key_id, &user.0.name).map_err(ApiError::internal)? {
+            return Err(ApiError::key_not_found(&key_id));
+        }
+        Ok(HttpResponse::NoContent().finish())
+    }
+
+    // Lets another user encrypt and decrypt with a stored key. Only its owner can, and only to an existing user so a
+    // mistyped name doesn't stay on the key's list.
+    #[put("/keys/{key_id}/users/{user}")]
+    async fn grant_key_handler(user: ApiUser, path: web::Path<(String, String)>, keystore: Option<web::Data<Keystore>>, auth: web::Data<Auth>) -> Result<HttpResponse, ApiError> {
+        let (key_id, other) = path.into_inner();
+        if !is_valid_user_name(&other) {
+            return Err(ApiError::bad_request(format!("Invalid user name '{}'", other)));
+        }
+        if !auth.user_exists(&other).map_err(ApiError::internal)? {
+            return Err(ApiError::bad_request(format!("Unknown user '{}'", other)));
+        }
+        let key = self::keystore(&keystore)?.grant(&key_id, &user.0.name, &other).map_err(ApiError::internal)?
+            .ok_or_else(|| ApiError::key_not_found(&key_id))?;
+        Ok(HttpResponse::Ok().json(key))
+    }
+
+    #[delete("/keys/{key_id}/users/{user}")]
+    async fn revoke_key_handler(user: ApiUser, path: web::Path<(String, String)>, keystore: Option<web::Data<Keystore>>) -> Result<HttpResponse, ApiError> {
+        let (key_id, other) = path.into_inner();
+        let key = self::keystore(&keystore)?.revoke(&key_id, &user.0.name, &other).map_err(ApiError::internal)?
+            .ok_or_else(|| ApiError::key_not_found(&key_id))?;
+        Ok(HttpResponse::Ok().json(key))
+    }
+}
@@ -0,0 +1,222 @@
+pub mod lattice_archive {
+    use std::fs::File;
+    use std::future::Future;
+    use std::io::{self, Read};
+    use std::path::PathBuf;
+    use std::pin::Pin;
+    use std::task::{Context, Poll};
+    use std::time::{SystemTime, UNIX_EPOCH};
+    use actix_web::rt::task::{spawn_blocking, JoinHandle};
+    use bytes::Bytes;
+    use futures::Stream;
+    use zeroize::Zeroizing;
+
+    const BLOCK_SIZE: usize = 512;
+
+    // Writes an octal number into a header field, zero padded and terminated with NUL like tar expects
+    fn write_octal(field: &mut [u8], value: u64) {
+        let digits = format!("{:0width$o}", value, width = field.len() - 1);
+        field[..digits.len()].copy_from_slice(digits.as_bytes());
+        field[digits.len()] = 0;
+    }
+
+    // The 512 byte ustar header of a regular file
+    fn file_header(name: &str, size: u64, mtime: u64) -> Result<[u8; BLOCK_SIZE], String> {
+        if name.is_empty() || name.len() > 100 {
+            return Err(format!("Archive entry name '{}' must be between 1 and 100 bytes", name));
+        }
+        let mut header = [0u8; BLOCK_SIZE];
+        header[..name.len()].copy_from_slice(name.as_bytes());
+        write_octal(&mut header[100..108], 0o644);
+        write_octal(&mut header[108..116], 0);
+        write_octal(&mut header[116..124], 0);
+        write_octal(&mut header[124..136], size);
+        write_octal(&mut header[136..148], mtime);
+        header[156] = b'0';
+        header[257..263].copy_from_slice(b"ustar\0");
+        header[263..265].copy_from_slice(b"00");
+
+        // The checksum is the byte sum of the header with the checksum field itself read as spaces
+        header[148..156].copy_from_slice(b"        ");
+        let checksum: u64 = header.iter().map(|&b| b as u64).sum();
+        let digits = format!("{:06o}", checksum);
+        header[148..154].copy_from_slice(digits.as_bytes());
+        header[154] = 0;
+        header[155] = b' ';
+        Ok(header)
+    }
+
+    // Size of the pieces files are read and sent in
+    const CHUNK_SIZE: usize = 64 * 1024;
+
+    // A piece of a file or archive, zeroed when dropped as it may hold plaintext or a secret key
+    pub type Chunk = io::Result<Zeroizing<Vec<u8>>>;
+
+    // A file to put in an archive, read from disk only when the archive reaches it, or already in memory. Files in
+    // memory are secret keys, so they are zeroed once the archive has moved past them.
+    pub enum ArchiveEntry {
+        File(String, PathBuf),
+        Bytes(String, Zeroizing<Vec<u8>>),
+    }
+
+    // An uncompressed tar archive produced chunk by chunk, so files of any size can be sent without loading them
+    pub struct TarStream {
+        entries: std::vec::IntoIter<ArchiveEntry>,
+        current: Option<Box<dyn Read + Send>>,
+        remaining: u64,
+        padding: usize,
+        mtime: u64,
+        finished: bool,
+    }
+
+    pub fn tar_stream(entries: Vec<ArchiveEntry>) -> TarStream {
+        let mtime = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+        TarStream { entries: entries.into_iter(), current: None, remaining: 0, padding: 0, mtime, finished: false }
+    }
+
+    impl TarStream {
+        // Starts the next entry and returns its header
+        fn open_entry(&mut self, entry: ArchiveEntry) -> Chunk {
+            let (name, size, reader): (String, u64, Box<dyn Read + Send>) = match entry {
+                ArchiveEntry::File(name, path) => {
+                    let file = File::open(path)?;
+                    let size = file.metadata()?.len();
+                    (name, size, Box::new(file.take(size)))
+                }
+                ArchiveEntry::Bytes(name, bytes) => (name, bytes.len() as u64, Box::new(io::Cursor::new(bytes))),
+            };
+            let header = file_header(&name, size, self.mtime).map_err(io::Error::other)?;
+            self.current = Some(reader);
+            self.remaining = size;
+            // File data is padded to whole blocks
+            self.padding = (BLOCK_SIZE - (size % BLOCK_SIZE as u64) as usize) % BLOCK_SIZE;
+            Ok(Zeroizing::new(header.to_vec()))
+        }
+    }
+
+    impl Iterator for TarStream {
+        type Item = Chunk;
+
+        fn next(&mut self) -> Option<Chunk> {
+            if let Some(reader) = &mut self.current {
+                let mut chunk = Zeroizing::new(vec![0u8; CHUNK_SIZE]);
+                match reader.read(&mut chunk) {
+                    Ok(0) if self.remaining > 0 => {
+                        self.finished = true;
+                        self.current = None;
+                        return Some(Err(io::Error::other("File shrank while it was being archived")));
+                    }
+                    Ok(0) => {
+                        self.current = None;
+                        if self.padding > 0 {
+                            return Some(Ok(Zeroizing::new(vec![0; std::mem::take(&mut self.padding)])));
+                        }
+                    }
+                    Ok(read) => {
+                        chunk.truncate(read);
+                        self.remaining -= read as u64;
+                        return Some(Ok(chunk));
+                    }
+                    Err(error) => {
+                        self.finished = true;
+                        self.current = None;
+                        return Some(Err(error));
+                    }
+                }
+            }
+            if self.finished {
+                return None;
+            }
+            match self.entries.next() {
+                Some(entry) => {
+                    let header = self.open_entry(entry);
+                    if header.is_err() {
+                        self.finished = true;
+                    }
+                    Some(header)
+                }
+                None => {
+                    // Two empty blocks mark the end of the archive
+                    self.finished = true;
+                    Some(Ok(Zeroizing::new(vec![0; 2 * BLOCK_SIZE])))
+                }
+            }
+        }
+    }
+
+    // Reads a file in chunks as they are consumed
+    pub fn read_chunks<R: Read + Send>(mut reader: R) -> impl Iterator<Item = Chunk> + Send {
+        let mut finished = false;
+        std::iter::from_fn(move || {
+            if finished {
+                return None;
+            }
+            let mut chunk = Zeroizing::new(vec![0u8; CHUNK_SIZE]);
+            match reader.read(&mut chunk) {
+                Ok(0) => None,
+                Ok(read) => {
+                    chunk.truncate(read);
+                    Some(Ok(chunk))
+                }
+                Err(error) => {
+                    finished = true;
+                    Some(Err(error))
+                }
+            }
+        })
+    }
+
+    type Chunks = Box<dyn Iterator<Item = Chunk> + Send>;
+
+    // A response body made of the chunks of a file or archive. Chunks are read on the blocking thread pool, like
+    // actix-files does, so a slow disk doesn't hold up the other requests of the worker. Each chunk is handed over as
+    // Bytes that zero it once the server has written it out, though the copies the server and TLS make while writing
+    // are out of reach.
+    pub struct ChunkStream<G = ()> {
+        chunks: Option<Chunks>,
+        reading: Option<JoinHandle<(Option<Chunk>, Chunks)>>,
+        // Dropped with the stream, once the response has been sent or the client has gone away
+        _guard: G,
+    }
+
+    pub fn chunk_stream(chunks: impl Iterator<Item = Chunk> + Send + 'static) -> ChunkStream {
+        ChunkStream { chunks: Some(Box::new(chunks)), reading: None, _guard: () }
+    }
+
+    impl ChunkStream {
+        // Keeps guard alive for as long as the response is being sent
+        pub fn holding<G>(self, guard: G) -> ChunkStream<G> {
+            ChunkStream { chunks: self.chunks, reading: self.reading, _guard: guard }
+        }
+    }
+
+    impl<G: Unpin> Stream for ChunkStream<G> {
+        type Item = io::Result<Bytes>;
+
+        fn poll_next(self: Pin<&mut Self>, context: &mut Context<'_>) -> Poll<Option<io::Result<Bytes>>> {
+            let this = self.get_mut();
+            let reading = match &mut this.reading {
+                Some(reading) => reading,
+                None => {
+                    let Some(mut chunks) = this.chunks.take() else {
+                        return Poll::Ready(None);
+                    };
+                    this.reading.insert(spawn_blocking(move || (chunks.next(), chunks)))
+                }
+            };
+            let read = match Pin::new(reading).poll(context) {
+                Poll::Ready(read) => read,
+                Poll::Pending => return Poll::Pending,
+            };
+            this.reading = None;
+            Poll::Ready(match read {
+                Ok((Some(chunk), chunks)) => {
+                    this.chunks = Some(chunks);
+                    Some(chunk.map(Bytes::from_owner))
+                }
+                Ok((None, _)) => None,
+                Err(error) => Some(Err(io::Error::other(error))),
+            })
+        }
+    }
+}
@@ -0,0 +1,328 @@
+pub mod lattice_audit {
+    use std::error::Error;
+    use std::fs::{File, OpenOptions};
+    use std::io::{self, BufRead, BufReader, Read, Write};
+    use std::sync::Mutex;
+    use std::time::{Instant, SystemTime, UNIX_EPOCH};
+    use log::error;
+    use serde::{Deserialize, Serialize};
+    use sha2::{Digest, Sha256};
+    use crate::keyfile::lattice_keyfile::to_hex;
+    use crate::metrics::lattice_metrics::{self, ErrorKind, BYTES_PROCESSED, OPERATIONS, OPERATION_DURATION, OPERATION_FAILURES};
+
+    // prev_hash of the first entry of a log
+    const GENESIS_HASH: &str = "0000000000000000000000000000000000000000000000000000000000000000";
+
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+    #[serde(rename_all = "snake_case")]
+    pub enum Operation {
+        Keygen,
+        Encrypt,
+        Decrypt,
+        Compute,
+        RekeyToken,
+        Rekey,
+    }
+
+    impl Operation {
+        // As written to the log and used as the operation label of the metrics
+        pub fn name(&self) -> &'static str {
+            match self {
+                Operation::Keygen => "keygen",
+                Operation::Encrypt => "encrypt",
+                Operation::Decrypt => "decrypt",
+                Operation::Compute => "compute",
+                Operation::RekeyToken => "rekey_token",
+                Operation::Rekey => "rekey",
+            }
+        }
+    }
+
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+    #[serde(rename_all = "snake_case")]
+    pub enum Outcome {
+        Success,
+        Failure,
+    }
+
+    // One line of the audit log. hash is the SHA-256 of the entry's JSON with hash left empty, and that JSON
+    // includes the hash of the entry before, so changing, removing or reordering an entry breaks every hash after it.
+    #[derive(Debug, Clone, Serialize, Deserialize)]
+    #[serde(deny_unknown_fields)]
+    pub struct AuditEntry {
+        pub seq: u64,
+        // Unix seconds when the operation started
+        pub timestamp: u64,
+        pub user: String,
+        pub operation: Operation,
+        // SHA-256 of the CSV, encrypted matrix or key the operation was given
+        pub input_hash: Option<String>,
+        pub key_fingerprint: Option<String>,
+        pub result: Outcome,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        pub error: Option<String>,
+        pub duration_ms: u64,
+        pub prev_hash: String,
+        pub hash: String,
+    }
+
+    impl AuditEntry {
+        fn compute_hash(&self) -> String {
+            let unhashed = AuditEntry { hash: String::new(), ..self.clone() };
+            to_hex(&Sha256::digest(serde_json::to_vec(&unhashed).unwrap_or_default()))
+        }
+    }
+
+    // An operation that has started, recorded once it has finished
+    pub struct AuditEvent {
+        user: String,
+        operation: Operation,
+        input_hash: Option<String>,
+        input_bytes: u64,
+        key_fingerprint: Option<String>,
+        timestamp: u64,
+        started: Instant,
+    }
+
+    impl AuditEvent {
+        pub fn start(user: &str, operation: Operation) -> AuditEvent {
+            AuditEvent {
+                user: user.to_string(),
+                operation,
+                input_hash: None,
+                input_bytes: 0,
+                key_fingerprint: None,
+                timestamp: SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0),
+                started: Instant::now(),
+            }
+        }
+
+        pub fn input(&mut self, bytes: &[u8]) {
+            self.input_hash = Some(to_hex(&Sha256::digest(bytes)));
+            self.input_bytes = bytes.len() as u64;
+        }
+
+        // Hashes an input file as it is read. A file that can't be read is left without a hash.
+        pub fn input_file(&mut self, path: &str) {
+            let mut hasher = Sha256::new();
+            let mut input_bytes = 0;
+            let hashed = File::open(path).and_then(|mut file| {
+                let mut buffer = [0u8; 64 * 1024];
+                loop {
+                    match file.read(&mut buffer)? {
+                        0 => return Ok(()),
+                        read => {
+                            hasher.update(&buffer[..read]);
+                            input_bytes += read as u64;
+                        }
+                    }
+                }
+            });
+            self.input_hash = hashed.ok().map(|_| to_hex(&hasher.finalize()));
+            self.input_bytes = input_bytes;
+        }
+
+        pub fn key(&mut self, fingerprint: Option<String>) {
+            if fingerprint.is_some() {
+                self.key_fingerprint = fingerprint;
+            }
+        }
+    }
+
+    struct Chain {
+        file: File,
+        seq: u64,
+        last_hash: String,
+    }
+
+    // The append-only audit log of every key generation, encryption, decryption, computation and re-key the server
+    // runs. Entries are written one JSON line at a time and synced before the next is written.
+    pub struct AuditLog {
+        chain: Option<Mutex<Chain>>,
+    }
+
+    impl AuditLog {
+        // Records nothing, for when audit.enabled is false
+        pub fn disabled() -> AuditLog {
+            AuditLog { chain: None }
+        }
+
+        // Opens a log for appending, continuing the chain from its last entry
+        pub fn open(path: &str) -> Result<AuditLog, Box<dyn Error>> {
+            let (seq, last_hash) = match File::open(path) {
+                Ok(file) => match last_entry(file).map_err(|error| format!("Cannot read audit log {}: {}", path, error))? {
+                    Some(entry) => (entry.seq + 1, entry.hash),
+                    None => (0, GENESIS_HASH.to_string()),
+                },
+                Err(error) if error.kind() == io::ErrorKind::NotFound => (0, GENESIS_HASH.to_string()),
+                Err(error) => return Err(format!("Cannot read audit log {}: {}", path, error).into()),
+            };
+            let mut options = OpenOptions::new();
+            options.append(true).create(true);
+            #[cfg(unix)]
+            std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+            let file = options.open(path).map_err(|error| format!("Cannot open audit log {}: {}", path, error))?;
+            Ok(AuditLog { chain: Some(Mutex::new(Chain { file, seq, last_hash })) })
+        }
+
+        // Appends the entry for a finished operation. The operation has already happened, so a failure to write is
+        // logged rather than returned. The operation is counted in the metrics even when the log is disabled.
+        pub fn record<T, E: ErrorKind>(&self, event: AuditEvent, result: &Result<T, E>) {
+            let operation = event.operation.name();
+            let outcome = if result.is_ok() { "success" } else { "failure" };
+            lattice_metrics::increment(&OPERATIONS, &[("operation", operation), ("result", outcome)]);
+            if let Err(error) = result {
+                lattice_metrics::increment(&OPERATION_FAILURES, &[("operation", operation), ("kind", error.kind())]);
+            }
+            lattice_metrics::observe_duration(&OPERATION_DURATION, &[("operation", operation)], event.started.elapsed());
+            lattice_metrics::add(&BYTES_PROCESSED, &[("operation", operation)], event.input_bytes as f64);
+
+            let Some(chain) = &self.chain else {
+                return;
+            };
+            let mut chain = chain.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+            let mut entry = AuditEntry {
+                seq: chain.seq,
+                timestamp: event.timestamp,
+                user: event.user,
+                operation: event.operation,
+                input_hash: event.input_hash,
+                key_fingerprint: event.key_fingerprint,
+                result: if result.is_ok() { Outcome::Success } else { Outcome::Failure },
+                error: result.as_ref().err().map(|error| error.to_string()),
+                duration_ms: event.started.elapsed().as_millis() as u64,
+                prev_hash: chain.last_hash.clone(),
+                hash: String::new(),
+            };
+            entry.hash = entry.compute_hash();
+
+            let written = serde_json::to_string(&entry).map_err(io::Error::other)
+                .and_then(|line| writeln!(chain.file, "{}", line))
+                .and_then(|_| chain.file.sync_data());
+            match written {
+                Ok(()) => {
+                    chain.seq += 1;
+                    chain.last_hash = entry.hash;
+                }
+                Err(error) => error!("Failed to write audit entry {} for {:?} by {}: {}", entry.seq, entry.operation, entry.user, error),
+            }
+        }
+    }
+
+    fn last_entry(file: File) -> Result<Option<AuditEntry>, Box<dyn Error>> {
+        let mut last = None;
+        for line in BufReader::new(file).lines() {
+            let line = line?;
+            if !line.trim().is_empty() {
+                last = Some(line);
+            }
+        }
+        match last {
+            Some(line) => serde_json::from_str(&line).map(Some)
+                .map_err(|error| format!("the last entry is not valid, check the log with audit-verify: {}", error).into()),
+            None => Ok(None),
+        }
+    }
+
+    // Checks that every entry of a log is numbered in order, matches its hash and is chained to the entry before.
+    // Returns the number of entries and the last hash. Removing entries from the end keeps the rest of the chain
+    // valid, so the last hash should be compared with one noted down earlier.
+    pub fn verify_log(path: &str) -> Result<(u64, String), Box<dyn Error>> {
+        let file = File::open(path).map_err(|error| format!("Cannot read audit log {}: {}", path, error))?;
+        let mut prev_hash = GENESIS_HASH.to_string();
+        let mut count = 0;
+        for (index, line) in BufReader::new(file).lines().enumerate() {
+            let line = line?;
+            let number = index + 1;
+            let entry: AuditEntry = serde_json::from_str(&line).map_err(|error| format!("Line {} is not an audit entry: {}", number, error))?;
+            if entry.seq != count {
+                return Err(format!("Line {} has sequence number {} where {} was expected, entries were removed or reordered", number, entry.seq, count).into());
+            }
+            if entry.prev_hash != prev_hash {
+                return Err(format!("Line {} is not chained to the entry before it, entries were removed, reordered or changed", number).into());
+            }
+            if entry.hash != entry.compute_hash() {
+                return Err(format!("Line {} does not match its hash, the entry was changed", number).into());
+            }
+            prev_hash = entry.hash;
+            count += 1;
+        }
+        Ok((count, prev_hash))
+    }
+
+    pub fn lattice_verify_audit_log(path: &str) -> Result<String, Box<dyn Error>> {
+        let (count, last_hash) = verify_log(path)?;
+        Ok(format!("Audit log {} is intact: {} entries, last hash {}", path, count, last_hash))
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+        use std::fs;
+
+        // A log of three encryptions by alice, bob and carol, returned as its lines
+        fn write_log(name: &str) -> (String, Vec<String>) {
+            let path = std::env::temp_dir().join(format!("lattice-test-audit-{}-{}.log", name, std::process::id())).to_string_lossy().into_owned();
+            let _ = fs::remove_file(&path);
+            let log = AuditLog::open(&path).unwrap();
+            for user in ["alice", "bob", "carol"] {
+                let mut event = AuditEvent::start(user, Operation::Encrypt);
+                event.input(user.as_bytes());
+                log.record(event, &Ok::<(), Box<dyn Error>>(()));
+            }
+            let lines = fs::read_to_string(&path).unwrap().lines().map(str::to_string).collect();
+            (path, lines)
+        }
+
+        fn verify_lines(path: &str, lines: &[String]) -> Result<(u64, String), Box<dyn Error>> {
+            fs::write(path, lines.join("\n") + "\n").unwrap();
+            let result = verify_log(path);
+            let _ = fs::remove_file(path);
+            result
+        }
+
+        #[test]
+        fn intact_log_verifies() {
+            let (path, lines) = write_log("intact");
+            let last: AuditEntry = serde_json::from_str(&lines[2]).unwrap();
+            let (count, last_hash) = verify_lines(&path, &lines).unwrap();
+            assert_eq!(count, 3);
+            assert_eq!(last_hash, last.hash);
+        }
+
+        #[test]
+        fn edited_entry_is_detected() {
+            let (path, mut lines) = write_log("edited");
+            lines[1] = lines[1].replace("\"user\":\"bob\"", "\"user\":\"eve\"");
+            let error = verify_lines(&path, &lines).unwrap_err().to_string();
+            assert!(error.contains("Line 2 does not match its hash"), "{}", error);
+        }
+
+        #[test]
+        fn edited_entry_with_a_new_hash_is_detected() {
+            let (path, mut lines) = write_log("rehashed");
+            let mut entry: AuditEntry = serde_json::from_str(&lines[1]).unwrap();
+            entry.user = "eve".to_string();
+            entry.hash = entry.compute_hash();
+            lines[1] = serde_json::to_string(&entry).unwrap();
+            let error = verify_lines(&path, &lines).unwrap_err().to_string();
+            assert!(error.contains("Line 3 is not chained"), "{}", error);
+        }
+
+        #[test]
+        fn deleted_entry_is_detected() {
+            let (path, mut lines) = write_log("deleted");
+            lines.remove(1);
+            let error = verify_lines(&path, &lines).unwrap_err().to_string();
+            assert!(error.contains("Line 2 has sequence number 2 where 1 was expected"), "{}", error);
+        }
+
+        #[test]
+        fn reordered_entries_are_detected() {
+            let (path, mut lines) = write_log("reordered");
+            lines.swap(1, 2);
+            let error = verify_lines(&path, &lines).unwrap_err().to_string();
+            assert!(error.contains("Line 2 has sequence number 2 where 1 was expected"), "{}", error);
+        }
+    }
+}
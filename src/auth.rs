@@ -0,0 +1,344 @@
+pub mod lattice_auth {
+    use std::collections::HashMap;
+    use std::error::Error;
+    use std::fs;
+    use std::future::{ready, Ready};
+    use std::io::Write;
+    use std::path::Path;
+    use std::sync::Mutex;
+    use std::time::{Duration, Instant};
+    use actix_web::dev::Payload;
+    use actix_web::{web, FromRequest, HttpMessage, HttpRequest};
+    use base64::engine::general_purpose::STANDARD_NO_PAD;
+    use base64::Engine;
+    use serde::{Deserialize, Serialize};
+    use sha2::{Digest, Sha256};
+    use crate::config::lattice_config::AuthConfig;
+    use crate::kdf::lattice_kdf::argon2id;
+
+    // Cookie holding the session of a user logged in through the web page
+    pub const SESSION_COOKIE: &str = "lattice_session";
+
+    // Environment variable user-add reads the password from, before falling back to standard input
+    pub const PASSWORD_ENV: &str = "LATTICE_PASSWORD";
+
+    // Owner of every job while authentication is turned off. It can't be added as a real user.
+    pub const ANONYMOUS_USER: &str = "anonymous";
+
+    const MIN_PASSWORD_LENGTH: usize = 8;
+
+    // Argon2id settings for new password hashes, the same OWASP recommendation wrapped key files use
+    const PASSWORD_MEMORY_KIB: u32 = 19456;
+    const PASSWORD_PASSES: u32 = 2;
+    const PASSWORD_LANES: u32 = 1;
+
+    // The users file is JSON:
+    //   {"users": [{"name": ..., "password_hash": <argon2id PHC string>, "tokens": [{"label": ..., "sha256": <hex>}]}]}
+    // API tokens are random, so only their SHA-256 is stored.
+    #[derive(Default, Serialize, Deserialize)]
+    struct UsersFile {
+        users: Vec<UserEntry>,
+    }
+
+    #[derive(Serialize, Deserialize)]
+    struct UserEntry {
+        name: String,
+        password_hash: String,
+        #[serde(default)]
+        tokens: Vec<TokenEntry>,
+    }
+
+    #[derive(Serialize, Deserialize)]
+    struct TokenEntry {
+        label: String,
+        sha256: String,
+    }
+
+    fn to_hex(bytes: &[u8]) -> String {
+        bytes.iter().map(|b| format!("{:02x}", b)).collect()
+    }
+
+    fn sha256_hex(text: &str) -> String {
+        to_hex(&Sha256::digest(text.as_bytes()))
+    }
+
+    // 256 random bits in hex, used for API tokens and session cookies
+    fn new_secret() -> String {
+        let bytes: [u8; 32] = rand::random();
+        to_hex(&bytes)
+    }
+
+    // User names end up in job folders, so they are kept to plain characters
+    pub fn is_valid_user_name(name: &str) -> bool {
+        (1..=64).contains(&name.len())
+            && !name.starts_with('.')
+            && name.bytes().all(|b| b.is_ascii_alphanumeric() || matches!(b, b'_' | b'-' | b'.'))
+    }
+
+    fn read_users(path: &str) -> Result<UsersFile, Box<dyn Error>> {
+        if !Path::new(path).exists() {
+            return Ok(UsersFile::default());
+        }
+        let contents = fs::read_to_string(path).map_err(|error| format!("Cannot read users file {}: {}", path, error))?;
+        serde_json::from_str(&contents).map_err(|error| format!("Invalid users file {}: {}", path, error).into())
+    }
+
+    // Replaces the users file in one step, readable only by its owner since it holds password hashes
+    fn write_users(path: &str, users: &UsersFile) -> Result<(), Box<dyn Error>> {
+        let temp_path = format!("{}.tmp", path);
+        let mut options = fs::OpenOptions::new();
+        options.write(true).create(true).truncate(true);
+        #[cfg(unix)]
+        std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+        let mut file = options.open(&temp_path)?;
+        file.write_all(serde_json::to_string_pretty(users)?.as_bytes())?;
+        file.sync_all()?;
+        fs::rename(&temp_path, path)?;
+        Ok(())
+    }
+
+    // Hashes a password into a PHC string: $argon2id$v=19$m=<KiB>,t=<passes>,p=<lanes>$<salt>$<hash>, with the salt
+    // and hash in base64 without padding
+    fn hash_password(password: &str) -> Result<String, Box<dyn Error>> {
+        let salt: [u8; 16] = rand::random();
+        let mut hash = [0u8; 32];
+        argon2id(password.as_bytes(), &salt, PASSWORD_MEMORY_KIB, PASSWORD_PASSES, PASSWORD_LANES, &mut hash)?;
+        Ok(format!("$argon2id$v=19$m={},t={},p={}${}${}", PASSWORD_MEMORY_KIB, PASSWORD_PASSES, PASSWORD_LANES,
+            STANDARD_NO_PAD.encode(salt), STANDARD_NO_PAD.encode(hash)))
+    }
+
+    // Checks a password against a PHC string, using the settings stored in it
+    fn verify_password(password: &str, hash: &str) -> bool {
+        let fields: Vec<&str> = hash.split('$').collect();
+        let ["", "argon2id", "v=19", params, salt, expected] = fields[..] else {
+            return false;
+        };
+        let mut settings = [0u32; 3];
+        for param in params.split(',') {
+            let (index, value) = match param.split_once('=') {
+                Some(("m", value)) => (0, value),
+                Some(("t", value)) => (1, value),
+                Some(("p", value)) => (2, value),
+                _ => return false,
+            };
+            match value.parse() {
+                Ok(value) => settings[index] = value,
+                Err(_) => return false,
+            }
+        }
+        let [memory, passes, lanes] = settings;
+        let (Ok(salt), Ok(expected)) = (STANDARD_NO_PAD.decode(salt), STANDARD_NO_PAD.decode(expected)) else {
+            return false;
+        };
+        let mut actual = vec![0u8; expected.len()];
+        if argon2id(password.as_bytes(), &salt, memory, passes, lanes, &mut actual).is_err() {
+            return false;
+        }
+        // Compared without stopping at the first difference
+        actual.iter().zip(&expected).fold(0, |difference, (a, b)| difference | (a ^ b)) == 0
+    }
+
+    // Adds a user, or sets a new password for an existing one
+    pub fn lattice_add_user(users_file: &str, name: &str, password: &str) -> Result<String, Box<dyn Error>> {
+        if !is_valid_user_name(name) || name == ANONYMOUS_USER {
+            return Err(format!("Invalid user name '{}', use up to 64 letters, digits, '_', '-' or '.'", name).into());
+        }
+        if password.chars().count() < MIN_PASSWORD_LENGTH {
+            return Err(format!("The password must be at least {} characters", MIN_PASSWORD_LENGTH).into());
+        }
+        let mut users = read_users(users_file)?;
+        let password_hash = hash_password(password)?;
+        let message = match users.users.iter_mut().find(|user| user.name == name) {
+            Some(user) => {
+                user.password_hash = password_hash;
+                format!("Changed the password of {}", name)
+            }
+            None => {
+                users.users.push(UserEntry { name: name.to_string(), password_hash, tokens: Vec::new() });
+                format!("Added user {}", name)
+            }
+        };
+        write_users(users_file, &users)?;
+        Ok(message)
+    }
+
+    pub fn lattice_remove_user(users_file: &str, name: &str) -> Result<String, Box<dyn Error>> {
+        let mut users = read_users(users_file)?;
+        let count = users.users.len();
+        users.users.retain(|user| user.name != name);
+        if users.users.len() == count {
+            return Err(format!("No user {}", name).into());
+        }
+        write_users(users_file, &users)?;
+        Ok(format!("Removed user {}", name))
+    }
+
+    // Creates an API token. Only its hash is stored, so this is the one time it is shown.
+    pub fn lattice_create_token(users_file: &str, name: &str, label: &str) -> Result<String, Box<dyn Error>> {
+        let mut users = read_users(users_file)?;
+        let user = users.users.iter_mut().find(|user| user.name == name).ok_or(format!("No user {}", name))?;
+        if user.tokens.iter().any(|token| token.label == label) {
+            return Err(format!("{} already has a token labelled '{}'", name, label).into());
+        }
+        let token = new_secret();
+        user.tokens.push(TokenEntry { label: label.to_string(), sha256: sha256_hex(&token) });
+        write_users(users_file, &users)?;
+        Ok(token)
+    }
+
+    pub fn lattice_revoke_token(users_file: &str, name: &str, label: &str) -> Result<String, Box<dyn Error>> {
+        let mut users = read_users(users_file)?;
+        let user = users.users.iter_mut().find(|user| user.name == name).ok_or(format!("No user {}", name))?;
+        let count = user.tokens.len();
+        user.tokens.retain(|token| token.label != label);
+        if user.tokens.len() == count {
+            return Err(format!("{} has no token labelled '{}'", name, label).into());
+        }
+        write_users(users_file, &users)?;
+        Ok(format!("Revoked token '{}' of {}", label, name))
+    }
+
+    // The user a request is made by
+    #[derive(Clone)]
+    pub struct User {
+        pub name: String,
+    }
+
+    // The outcome of authenticating a request, kept in its extensions so the rate limiter and the handler don't each
+    // read the users file
+    struct Authenticated(Option<User>);
+
+    // Authentication state of the web server. The users file is read on every check, so users and tokens added
+    // or revoked from the command line take effect without a restart.
+    pub struct Auth {
+        enabled: bool,
+        users_file: String,
+        session_ttl: Duration,
+        // Hashes of session cookies, mapped to their user and expiry. Sessions only live in memory, so a restart
+        // logs everyone out.
+        sessions: Mutex<HashMap<String, (String, Instant)>>,
+    }
+
+    impl Auth {
+        pub fn new(config: &AuthConfig) -> Auth {
+            Auth {
+                enabled: config.enabled,
+                users_file: config.users_file.clone(),
+                session_ttl: Duration::from_secs(config.session_ttl_secs),
+                sessions: Mutex::new(HashMap::new()),
+            }
+        }
+
+        pub fn enabled(&self) -> bool {
+            self.enabled
+        }
+
+        pub fn session_ttl(&self) -> Duration {
+            self.session_ttl
+        }
+
+        // Checks a user name and password and starts a session, returning the session cookie value
+        pub fn login(&self, name: &str, password: &str) -> Option<String> {
+            let users = match read_users(&self.users_file) {
+                Ok(users) => users,
+                Err(error) => {
+                    eprintln!("{}", error);
+                    return None;
+                }
+            };
+            let user = users.users.iter().find(|user| user.name == name)?;
+            if !verify_password(password, &user.password_hash) {
+                return None;
+            }
+            let session = new_secret();
+            let now = Instant::now();
+            let mut sessions = self.sessions.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+            sessions.retain(|_, (_, expires)| *expires > now);
+            sessions.insert(sha256_hex(&session), (user.name.clone(), now + self.session_ttl));
+            Some(session)
+        }
+
+        pub fn logout(&self, session: &str) {
+            self.sessions.lock().unwrap_or_else(|poisoned| poisoned.into_inner()).remove(&sha256_hex(session));
+        }
+
+        fn session_user(&self, session: &str) -> Option<String> {
+            let sessions = self.sessions.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+            let (name, expires) = sessions.get(&sha256_hex(session))?;
+            (*expires > Instant::now()).then(|| name.clone())
+        }
+
+        // Whether a name belongs to a user in the users file. Without authentication there is only the anonymous user.
+        pub fn user_exists(&self, name: &str) -> Result<bool, Box<dyn Error>> {
+            if !self.enabled {
+                return Ok(name == ANONYMOUS_USER);
+            }
+            Ok(read_users(&self.users_file)?.users.iter().any(|user| user.name == name))
+        }
+
+        fn token_user(&self, token: &str) -> Option<String> {
+            let users = read_users(&self.users_file).map_err(|error| eprintln!("{}", error)).ok()?;
+            let hash = sha256_hex(token);
+            let user = users.users.into_iter().find(|user| user.tokens.iter().any(|entry| entry.sha256 == hash))?;
+            Some(user.name)
+        }
+
+        // The user behind an Authorization: Bearer token or a session cookie, worked out once per request. Sessions
+        // of removed users end on their next request.
+        pub fn authenticate(&self, request: &HttpRequest) -> Option<User> {
+            if let Some(Authenticated(user)) = request.extensions().get::<Authenticated>() {
+                return user.clone();
+            }
+            let user = self.authenticate_uncached(request);
+            request.extensions_mut().insert(Authenticated(user.clone()));
+            user
+        }
+
+        fn authenticate_uncached(&self, request: &HttpRequest) -> Option<User> {
+            if !self.enabled {
+                return Some(User { name: ANONYMOUS_USER.to_string() });
+            }
+            let bearer = request.headers().get("Authorization")
+                .and_then(|value| value.to_str().ok())
+                .and_then(|value| value.strip_prefix("Bearer "));
+            if let Some(token) = bearer {
+                return self.token_user(token.trim()).map(|name| User { name });
+            }
+            let name = self.session_user(request.cookie(SESSION_COOKIE)?.value())?;
+            let users = read_users(&self.users_file).ok()?;
+            users.users.iter().any(|user| user.name == name).then_some(User { name })
+        }
+    }
+
+    // Handlers that take a User only run for authenticated requests; the rest get 401
+    impl FromRequest for User {
+        type Error = actix_web::Error;
+        type Future = Ready<Result<User, actix_web::Error>>;
+
+        fn from_request(request: &HttpRequest, _: &mut Payload) -> Self::Future {
+            let user = request.app_data::<web::Data<Auth>>().and_then(|auth| auth.authenticate(request));
+            ready(user.ok_or_else(|| actix_web::error::ErrorUnauthorized("Login required")))
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        #[test]
+        fn password_hashes_round_trip() {
+            let hash = hash_password("correct horse").unwrap();
+            assert!(hash.starts_with("$argon2id$v=19$m=19456,t=2,p=1$"), "{}", hash);
+            assert!(verify_password("correct horse", &hash));
+            assert!(!verify_password("wrong horse", &hash));
+            assert!(!verify_password("correct horse", "not a hash"));
+        }
+
+        #[test]
+        fn existing_users_file_hashes_still_verify() {
+            let hash = "$argon2id$v=19$m=19456,t=2,p=1$KdTzzsrT8atlagsAZ5e/+w$IWOSwN5llAYaEfaEf1cnqKSO76kbpWz1sWiZh9u0IVE";
+            assert!(verify_password("hunter2", hash));
+            assert!(!verify_password("hunter3", hash));
+        }
+    }
+}
@@ -0,0 +1,224 @@
+pub mod lattice_cli {
+    use std::cell::Cell;
+    use std::error::Error;
+    use std::io::{IsTerminal, Write};
+    use crate::encrypted::lattice_encrypt::{lattice_encrypt_csv, lattice_encrypt_numeric_csv, lattice_generate_keys};
+    use crate::decrypted::lattice_decrypt::{lattice_decrypt_csv, lattice_decrypt_csv_with_shares};
+    use crate::sharing::lattice_sharing::lattice_split_key_files;
+    use crate::rekey::lattice_rekey::{lattice_rekey_csv, lattice_rekey_token_files};
+    use crate::rotation::lattice_rotation::lattice_rotate_directory;
+    use crate::config::lattice_config::{CONFIG_ENV, DEFAULT_CONFIG_FILE};
+    use crate::keystore::lattice_keystore::lattice_create_master_key;
+    use crate::auth::lattice_auth::{lattice_add_user, lattice_create_token, lattice_remove_user, lattice_revoke_token, PASSWORD_ENV};
+    use crate::progress::lattice_progress::{Progress, Stage};
+    use crate::audit::lattice_audit::lattice_verify_audit_log;
+
+    // Environment variable read when --passphrase is given without a value, keeping it out of the process list
+    pub const PASSPHRASE_ENV: &str = "LATTICE_PASSPHRASE";
+
+    fn usage(program: &str) -> String {
+        format!("Usage:\n  \
+            {program} [--config <file>] [--static-dir <dir>] [--self-signed]\n      \
+            start the web server, configured from file, {CONFIG_ENV} or {DEFAULT_CONFIG_FILE}, and serving dir/frontend.html instead of the built in page when given. --self-signed serves HTTPS with a certificate made at startup, for development\n  \
+            {program} keygen <size> <output_dir> [--passphrase [<passphrase>]]\n      \
+            generate a size x size key pair\n  \
+            {program} encrypt <csv_file> <output_dir> [--numeric] [--passphrase [<passphrase>]]\n      \
+            encrypt a CSV file into output_dir\n  \
+            {program} decrypt <encrypted_matrix> <secret_key> <output_csv> [--passphrase [<passphrase>]]\n      \
+            decrypt into output_csv\n  \
+            {program} split-key <secret_key> <threshold> <shares> <output_dir> [--passphrase [<passphrase>]]\n      \
+            split a secret key into key share files, any threshold of which can decrypt\n  \
+            {program} decrypt-shares <encrypted_matrix> <output_csv> <key_share>...\n      \
+            decrypt with key shares instead of a secret key\n  \
+            {program} rekey-token <old_secret_key> <output_dir> [--passphrase [<passphrase>]]\n      \
+            generate a re-key token and new key pair\n  \
+            {program} rekey <encrypted_matrix> <rekey_token> <output>\n      \
+            move an encrypted matrix onto the new key\n  \
+            {program} rotate <dir> <old_secret_key> <key_output_dir> [--dry-run] [--passphrase [<passphrase>]]\n      \
+            re-encrypt every file in dir made with the old key onto a new key, resuming an interrupted rotation\n  \
+            {program} user-add <users_file> <name>\n      \
+            add a web server user or change their password, read from {PASSWORD_ENV} or the first line of standard input\n  \
+            {program} user-remove <users_file> <name>\n      \
+            remove a user and their API tokens\n  \
+            {program} token-create <users_file> <name> <label>\n      \
+            create an API token for Authorization: Bearer <token>, shown only this once\n  \
+            {program} token-revoke <users_file> <name> <label>\n      \
+            revoke an API token\n  \
+            {program} keystore-init <master_key_file>\n      \
+            create the master key the server keystore seals stored keys under\n  \
+            {program} audit-verify <audit_log_file>\n      \
+            check that no entry of the server's audit log was changed, removed or reordered\n\
+            \n\
+            --passphrase without a value reads the passphrase from {PASSPHRASE_ENV}.")
+    }
+
+    // Returns the arguments of a subcommand, or an error with the usage text when too few were given
+    fn expect_args(args: &[String], count: usize) -> Result<&[String], Box<dyn Error>> {
+        if args.len() < count + 2 || args[2..2 + count].iter().any(|arg| arg.starts_with("--")) {
+            return Err(usage(&args[0]).into());
+        }
+        Ok(&args[2..2 + count])
+    }
+
+    // The arguments after the subcommand that are neither flags nor the value of --passphrase
+    fn positional(args: &[String]) -> Vec<&String> {
+        let mut positional = Vec::new();
+        let mut rest = args.iter().skip(2).peekable();
+        while let Some(arg) = rest.next() {
+            if arg == "--passphrase" {
+                rest.next_if(|value| !value.starts_with("--"));
+            } else if !arg.starts_with("--") {
+                positional.push(arg);
+            }
+        }
+        positional
+    }
+
+    fn has_flag(args: &[String], flag: &str) -> bool {
+        args.iter().any(|arg| arg == flag)
+    }
+
+    // The value of --passphrase, falling back to the environment when the flag has no value
+    fn passphrase(args: &[String]) -> Result<Option<String>, Box<dyn Error>> {
+        let Some(position) = args.iter().position(|arg| arg == "--passphrase") else {
+            return Ok(None);
+        };
+        match args.get(position + 1).filter(|value| !value.starts_with("--")) {
+            Some(value) => Ok(Some(value.clone())),
+            None => std::env::var(PASSPHRASE_ENV)
+                .map(Some)
+                .map_err(|_| format!("--passphrase was given without a value and {} is not set", PASSPHRASE_ENV).into()),
+        }
+    }
+
+    // The password for user-add, kept out of the process list like --passphrase
+    fn password() -> Result<String, Box<dyn Error>> {
+        if let Ok(password) = std::env::var(PASSWORD_ENV) {
+            return Ok(password);
+        }
+        let mut line = String::new();
+        std::io::stdin().read_line(&mut line)?;
+        Ok(line.trim_end_matches(['\r', '\n']).to_string())
+    }
+
+    // Draws a progress bar on standard error while a command runs, or nothing when standard error is not a terminal
+    struct ProgressBar {
+        enabled: bool,
+        // What was drawn last, so the line is only redrawn when it changes
+        drawn: Cell<Option<(Stage, u8)>>,
+    }
+
+    impl ProgressBar {
+        const WIDTH: usize = 30;
+
+        fn new() -> ProgressBar {
+            ProgressBar { enabled: std::io::stderr().is_terminal(), drawn: Cell::new(None) }
+        }
+    }
+
+    impl Progress for ProgressBar {
+        fn report(&self, stage: Stage, done: u64, total: u64) {
+            let percent = stage.percent(done, total);
+            if !self.enabled || self.drawn.get() == Some((stage, percent)) {
+                return;
+            }
+            self.drawn.set(Some((stage, percent)));
+            let filled = Self::WIDTH * percent as usize / 100;
+            let stage = format!("{:?}", stage).to_lowercase();
+            let mut stderr = std::io::stderr().lock();
+            let _ = write!(stderr, "\r[{}{}] {:>3}% {:<15}", "#".repeat(filled), " ".repeat(Self::WIDTH - filled), percent, stage);
+            let _ = stderr.flush();
+        }
+    }
+
+    impl Drop for ProgressBar {
+        // Ends the bar's line so later output starts on a fresh one
+        fn drop(&mut self) {
+            if self.drawn.get().is_some() {
+                eprintln!();
+            }
+        }
+    }
+
+    // Runs the subcommand named by the first argument. Returns None when no subcommand was given so main
+    // falls through to starting the web server.
+    pub fn run(args: &[String]) -> Option<Result<(), Box<dyn Error>>> {
+        let command = args.get(1)?;
+        // Flags are left for the web server
+        if command.starts_with("--") && command != "--help" {
+            return None;
+        }
+        // The server sets up logging from its configuration instead
+        env_logger::init();
+        let passphrase = match passphrase(args) {
+            Ok(passphrase) => passphrase,
+            Err(error) => return Some(Err(error)),
+        };
+        let passphrase = passphrase.as_deref();
+
+        let result = match command.as_str() {
+            "keygen" => expect_args(args, 2).and_then(|a| {
+                let size = a[0].parse().map_err(|_| format!("Invalid key size '{}'", a[0]))?;
+                lattice_generate_keys(size, &a[1], passphrase, &ProgressBar::new())
+            }),
+            "encrypt" => expect_args(args, 2).and_then(|a| {
+                std::fs::create_dir_all(&a[1])?;
+                if has_flag(args, "--numeric") {
+                    lattice_encrypt_numeric_csv(&a[0], &a[1], passphrase, &ProgressBar::new())
+                } else {
+                    lattice_encrypt_csv(&a[0], &a[1], passphrase, &ProgressBar::new())
+                }
+            }),
+            "decrypt" => expect_args(args, 3).and_then(|a| {
+                lattice_decrypt_csv(&a[0], &a[1], &a[2], passphrase, &ProgressBar::new())?;
+                Ok(format!("Decrypted into {}", a[2]))
+            }),
+            "split-key" => expect_args(args, 4).and_then(|a| {
+                let threshold = a[1].parse().map_err(|_| format!("Invalid threshold '{}'", a[1]))?;
+                let shares = a[2].parse().map_err(|_| format!("Invalid number of shares '{}', at most 255", a[2]))?;
+                lattice_split_key_files(&a[0], threshold, shares, &a[3], passphrase)
+            }),
+            "decrypt-shares" => expect_args(args, 3).and_then(|a| {
+                let share_paths = &positional(args)[2..];
+                lattice_decrypt_csv_with_shares(&a[0], share_paths, &a[1], &ProgressBar::new())?;
+                Ok(format!("Decrypted into {}", a[1]))
+            }),
+            "rekey-token" => expect_args(args, 2)
+                .and_then(|a| lattice_rekey_token_files(&a[0], &a[1], passphrase)),
+            "rekey" => expect_args(args, 3)
+                .and_then(|a| lattice_rekey_csv(&a[0], &a[1], &a[2])),
+            "rotate" => expect_args(args, 3)
+                .and_then(|a| lattice_rotate_directory(&a[0], &a[1], &a[2], has_flag(args, "--dry-run"), passphrase)),
+            "user-add" => expect_args(args, 2)
+                .and_then(|a| lattice_add_user(&a[0], &a[1], &password()?)),
+            "user-remove" => expect_args(args, 2)
+                .and_then(|a| lattice_remove_user(&a[0], &a[1])),
+            "token-create" => expect_args(args, 3)
+                .and_then(|a| lattice_create_token(&a[0], &a[1], &a[2])),
+            "token-revoke" => expect_args(args, 3)
+                .and_then(|a| lattice_revoke_token(&a[0], &a[1], &a[2])),
+            "keystore-init" => expect_args(args, 1)
+                .and_then(|a| lattice_create_master_key(&a[0])),
+            "audit-verify" => expect_args(args, 1)
+                .and_then(|a| lattice_verify_audit_log(&a[0])),
+            "help" | "--help" | "-h" => Ok(usage(&args[0])),
+            other => Err(format!("Unknown command '{}'\n{}", other, usage(&args[0])).into()),
+        };
+        Some(result.map(|message| println!("{}", message)))
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        fn args(line: &str) -> Vec<String> {
+            line.split(' ').map(str::to_string).collect()
+        }
+
+        #[test]
+        fn passphrase_values_are_not_positional() {
+            let args = args("lattice decrypt-shares m.csv out.csv a.share --passphrase secret b.share --passphrase --dry-run c.share");
+            assert_eq!(positional(&args), ["m.csv", "out.csv", "a.share", "b.share", "c.share"]);
+        }
+    }
+}
@@ -0,0 +1,323 @@
+pub mod lattice_compute {
+    use std::error::Error;
+    use nalgebra::DMatrix;
+    use log::info;
+    use crate::decrypted::lattice_decrypt::{read_key_fingerprint, read_numeric_matrix};
+    use crate::encrypted::lattice_encrypt::write_numeric_matrix;
+
+    // Encryption is encrypted = original * encrypt_matrix, so anything multiplied in from the left is applied to
+    // the original as well: A * encrypted = (A * original) * encrypt_matrix, without the secret key.
+
+    // Applies a public matrix from the left, one encrypted output row per row of the public matrix
+    pub fn apply_public_matrix(public_matrix: &DMatrix<f64>, encrypted_matrix: &DMatrix<f64>) -> Result<DMatrix<f64>, Box<dyn Error>> {
+        if public_matrix.ncols() != encrypted_matrix.nrows() {
+            return Err(format!("Public matrix has {} columns but the encrypted matrix has {} rows",
+                public_matrix.ncols(), encrypted_matrix.nrows()).into());
+        }
+        Ok(public_matrix * encrypted_matrix)
+    }
+
+    // Value of an expression while it is being evaluated: either a plain number or a weight per input column
+    #[derive(Debug, Clone)]
+    enum Value {
+        Constant(f64),
+        Linear(Vec<f64>),
+    }
+
+    #[derive(Debug, Clone, PartialEq)]
+    enum Token {
+        Number(f64),
+        Name(String),
+        Symbol(char),
+    }
+
+    fn tokenize(expression: &str) -> Result<Vec<Token>, Box<dyn Error>> {
+        let mut tokens = Vec::new();
+        let mut chars = expression.chars().peekable();
+        while let Some(&c) = chars.peek() {
+            if c.is_whitespace() {
+                chars.next();
+            } else if c.is_ascii_digit() || c == '.' {
+                let mut number = String::new();
+                while let Some(&d) = chars.peek() {
+                    if d.is_ascii_digit() || d == '.' || d == 'e' || d == 'E'
+                        || ((d == '-' || d == '+') && number.ends_with(['e', 'E'])) {
+                        number.push(d);
+                        chars.next();
+                    } else {
+                        break;
+                    }
+                }
+                tokens.push(Token::Number(number.parse().map_err(|_| format!("Invalid number '{}'", number))?));
+            } else if c.is_alphabetic() || c == '_' {
+                let mut name = String::new();
+                while let Some(&d) = chars.peek() {
+                    if d.is_alphanumeric() || d == '_' || d == '.' {
+                        name.push(d);
+                        chars.next();
+                    } else {
+                        break;
+                    }
+                }
+                tokens.push(Token::Name(name));
+            } else if c == '`' || c == '"' {
+                // Quoted column names may contain spaces and operators
+                chars.next();
+                let mut name = String::new();
+                loop {
+                    match chars.next() {
+                        Some(d) if d == c => break,
+                        Some(d) => name.push(d),
+                        None => return Err(format!("Missing closing {} after column name {}{}", c, c, name).into()),
+                    }
+                }
+                tokens.push(Token::Name(name));
+            } else if "+-*/()".contains(c) {
+                tokens.push(Token::Symbol(c));
+                chars.next();
+            } else {
+                return Err(format!("Unexpected character '{}' in expression", c).into());
+            }
+        }
+        Ok(tokens)
+    }
+
+    // Recursive descent parser evaluating straight into column weights
+    struct Parser<'a> {
+        tokens: Vec<Token>,
+        position: usize,
+        columns: &'a [String],
+    }
+
+    impl Parser<'_> {
+        fn peek(&self) -> Option<&Token> {
+            self.tokens.get(self.position)
+        }
+
+        fn next(&mut self) -> Option<Token> {
+            let token = self.tokens.get(self.position).cloned();
+            self.position += 1;
+            token
+        }
+
+        fn expression(&mut self) -> Result<Value, Box<dyn Error>> {
+            let mut value = self.term()?;
+            while let Some(Token::Symbol(op)) = self.peek().cloned() {
+                if op != '+' && op != '-' {
+                    break;
+                }
+                self.next();
+                let right = self.term()?;
+                let sign = if op == '+' { 1.0 } else { -1.0 };
+                value = match (value, right) {
+                    (Value::Constant(a), Value::Constant(b)) => Value::Constant(a + sign * b),
+                    (Value::Linear(a), Value::Linear(b)) => {
+                        Value::Linear(a.iter().zip(&b).map(|(x, y)| x + sign * y).collect())
+                    }
+                    _ => return Err("Adding a constant to a column is not supported on encrypted data".into()),
+                };
+            }
+            Ok(value)
+        }
+
+        fn term(&mut self) -> Result<Value, Box<dyn Error>> {
+            let mut value = self.factor()?;
+            while let Some(Token::Symbol(op)) = self.peek().cloned() {
+                if op != '*' && op != '/' {
+                    break;
+                }
+                self.next();
+                let right = self.factor()?;
+                if op == '/' && matches!(right, Value::Constant(b) if b == 0.0) {
+                    return Err("Division by zero".into());
+                }
+                value = match (op, value, right) {
+                    ('*', Value::Constant(a), Value::Constant(b)) => Value::Constant(a * b),
+                    ('*', Value::Constant(a), Value::Linear(b)) | ('*', Value::Linear(b), Value::Constant(a)) => {
+                        Value::Linear(b.iter().map(|x| x * a).collect())
+                    }
+                    ('/', Value::Constant(a), Value::Constant(b)) => Value::Constant(a / b),
+                    ('/', Value::Linear(a), Value::Constant(b)) => Value::Linear(a.iter().map(|x| x / b).collect()),
+                    _ => return Err("Only multiplication and division by constants is supported on encrypted data".into()),
+                };
+            }
+            Ok(value)
+        }
+
+        fn factor(&mut self) -> Result<Value, Box<dyn Error>> {
+            match self.next() {
+                Some(Token::Number(number)) => Ok(Value::Constant(number)),
+                Some(Token::Name(name)) => {
+                    let index = self.columns.iter().position(|column| *column == name)
+                        .ok_or_else(|| format!("Unknown column '{}'", name))?;
+                    let mut weights = vec![0.0; self.columns.len()];
+                    weights[index] = 1.0;
+                    Ok(Value::Linear(weights))
+                }
+                Some(Token::Symbol('-')) => match self.factor()? {
+                    Value::Constant(a) => Ok(Value::Constant(-a)),
+                    Value::Linear(a) => Ok(Value::Linear(a.iter().map(|x| -x).collect())),
+                },
+                Some(Token::Symbol('(')) => {
+                    let value = self.expression()?;
+                    match self.next() {
+                        Some(Token::Symbol(')')) => Ok(value),
+                        _ => Err("Missing closing parenthesis".into()),
+                    }
+                }
+                Some(token) => Err(format!("Unexpected {:?} in expression", token).into()),
+                None => Err("Unexpected end of expression".into()),
+            }
+        }
+    }
+
+    // Parses a single linear expression over column names such as `0.3 * Math + 0.7 * Science` into one weight per column
+    pub fn parse_linear_expression(expression: &str, columns: &[String]) -> Result<Vec<f64>, Box<dyn Error>> {
+        let mut parser = Parser { tokens: tokenize(expression)?, position: 0, columns };
+        let value = parser.expression()?;
+        if let Some(token) = parser.peek() {
+            return Err(format!("Unexpected {:?} after expression '{}'", token, expression).into());
+        }
+        match value {
+            Value::Linear(weights) => Ok(weights),
+            Value::Constant(_) => Err(format!("Expression '{}' does not reference any column", expression).into()),
+        }
+    }
+
+    // The characters of a program that are not inside a quoted column name, with their byte positions
+    fn unquoted(text: &str) -> impl Iterator<Item = (usize, char)> + '_ {
+        let mut quote = None;
+        text.char_indices().filter(move |&(_, c)| match quote {
+            Some(open) => {
+                if c == open {
+                    quote = None;
+                }
+                false
+            }
+            None if c == '`' || c == '"' => {
+                quote = Some(c);
+                false
+            }
+            None => true,
+        })
+    }
+
+    // Splits a program into statements at the newlines and ';' outside quoted column names
+    fn split_statements(program: &str) -> Vec<&str> {
+        let mut statements = Vec::new();
+        let mut start = 0;
+        for (position, c) in unquoted(program) {
+            if c == ';' || c == '\n' {
+                statements.push(&program[start..position]);
+                start = position + 1;
+            }
+        }
+        statements.push(&program[start..]);
+        statements
+    }
+
+    // Parses a program of `name = expression` statements separated by newlines or ';' into the output names and
+    // the public matrix that computes them. A statement without a name is labelled with its own text.
+    pub fn parse_program(program: &str, columns: &[String]) -> Result<(Vec<String>, DMatrix<f64>), Box<dyn Error>> {
+        let mut labels = Vec::new();
+        let mut rows = Vec::new();
+        for statement in split_statements(program).into_iter().map(str::trim).filter(|s| !s.is_empty()) {
+            let (label, expression) = match unquoted(statement).find(|&(_, c)| c == '=') {
+                Some((position, _)) => (statement[..position].trim().trim_matches(['`', '"']).to_string(), statement[position + 1..].trim()),
+                None => (statement.to_string(), statement),
+            };
+            rows.push(parse_linear_expression(expression, columns)?);
+            labels.push(label);
+        }
+
+        if rows.is_empty() {
+            return Err("No expressions to compute".into());
+        }
+
+        let public_matrix = DMatrix::from_fn(rows.len(), columns.len(), |i, j| rows[i][j]);
+        Ok((labels, public_matrix))
+    }
+
+    // Evaluates a program over a column-wise encrypted matrix without the secret key and writes the encrypted result,
+    // which decrypts with the original secret key
+    pub fn lattice_compute_csv(encrypted_matrix_path: &str, program: &str, output_path: &str) -> Result<String, Box<dyn Error>> {
+        let (columns, encrypted_matrix) = read_numeric_matrix(encrypted_matrix_path)?;
+        let (labels, public_matrix) = parse_program(program, &columns)?;
+        info!("Computing {} expressions over {} encrypted columns", labels.len(), columns.len());
+
+        let result = apply_public_matrix(&public_matrix, &encrypted_matrix)?;
+        // The result decrypts with the same key, so it keeps the input's fingerprint
+        let fingerprint = read_key_fingerprint(encrypted_matrix_path)?;
+        write_numeric_matrix(output_path, &labels, &result, fingerprint.as_deref())?;
+
+        Ok("Computation on encrypted data successful".to_string())
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        fn columns(names: &[&str]) -> Vec<String> {
+            names.iter().map(|name| name.to_string()).collect()
+        }
+
+        #[test]
+        fn linear_expressions_become_column_weights() {
+            let columns = columns(&["Math", "Science", "Art class"]);
+            assert_eq!(parse_linear_expression("0.3 * Math + 0.7 * Science", &columns).unwrap(), [0.3, 0.7, 0.0]);
+            assert_eq!(parse_linear_expression("(Math - Science) / 2 - -`Art class`", &columns).unwrap(), [0.5, -0.5, 1.0]);
+            assert_eq!(parse_linear_expression("2 * 3 * \"Art class\" + 1e-1 * Math", &columns).unwrap(), [0.1, 0.0, 6.0]);
+        }
+
+        #[test]
+        fn invalid_expressions_are_errors() {
+            let columns = columns(&["Math", "Science"]);
+            for (expression, error) in [
+                ("Math + 1", "Adding a constant"),
+                ("Math * Science", "Only multiplication and division by constants"),
+                ("2 / Math", "Only multiplication and division by constants"),
+                ("Music", "Unknown column 'Music'"),
+                ("(Math + Science", "Missing closing parenthesis"),
+                ("Math Science", "Unexpected Name(\"Science\") after expression"),
+                ("3 * 4", "does not reference any column"),
+                ("Math %", "Unexpected character '%'"),
+                ("Math / 0", "Division by zero"),
+                ("Math / (2 - 2)", "Division by zero"),
+                ("(1 / 0) * Math", "Division by zero"),
+                ("`Math + Science", "Missing closing ` after column name `Math + Science"),
+                ("\"Math", "Missing closing \" after column name \"Math"),
+            ] {
+                let message = parse_linear_expression(expression, &columns).unwrap_err().to_string();
+                assert!(message.contains(error), "'{}' gave '{}'", expression, message);
+            }
+        }
+
+        #[test]
+        fn programs_become_one_row_per_statement() {
+            let columns = columns(&["a", "b"]);
+            let (labels, matrix) = parse_program("total = a + b; `half a` = a / 2\n b - a", &columns).unwrap();
+            assert_eq!(labels, ["total", "half a", "b - a"]);
+            assert_eq!(matrix, DMatrix::from_row_slice(3, 2, &[1.0, 1.0, 0.5, 0.0, -1.0, 1.0]));
+            assert!(parse_program(" ; \n", &columns).unwrap_err().to_string().contains("No expressions"));
+            assert!(parse_program("total = a + c", &columns).is_err());
+        }
+
+        #[test]
+        fn separators_inside_quoted_names_do_not_split() {
+            let columns = columns(&["a=b", "c;d"]);
+            let (labels, matrix) = parse_program("`x;y` = `a=b` + \"c;d\"; \"p=q\" = 2 * `c;d`", &columns).unwrap();
+            assert_eq!(labels, ["x;y", "p=q"]);
+            assert_eq!(matrix, DMatrix::from_row_slice(2, 2, &[1.0, 1.0, 0.0, 2.0]));
+        }
+
+        #[test]
+        fn computing_on_encrypted_rows_matches_computing_on_the_original() {
+            let original = DMatrix::from_row_slice(2, 3, &[1.0, 2.0, 3.0, 10.0, 20.0, 30.0]);
+            let key = DMatrix::from_row_slice(3, 3, &[2.0, 1.0, 0.0, 0.0, 1.0, 1.0, 1.0, 0.0, 3.0]);
+            let (_, public_matrix) = parse_program("s = a + 2 * b", &columns(&["a", "b"])).unwrap();
+            let computed = apply_public_matrix(&public_matrix, &(&original * &key)).unwrap();
+            assert_eq!(computed, (&public_matrix * &original) * &key);
+            assert!(apply_public_matrix(&DMatrix::zeros(1, 3), &original).is_err());
+        }
+    }
+}
@@ -0,0 +1,431 @@
+pub mod lattice_config {
+    use std::error::Error;
+    use std::fs;
+    use std::net::ToSocketAddrs;
+    use std::path::Path;
+    use serde::Deserialize;
+
+    // Read when neither --config nor LATTICE_CONFIG names a file, and skipped when it does not exist
+    pub const DEFAULT_CONFIG_FILE: &str = "lattice.toml";
+    pub const CONFIG_ENV: &str = "LATTICE_CONFIG";
+
+    // Server settings, read from a TOML file and then overridden by LATTICE_* environment variables.
+    // lattice.example.toml lists every setting with its default.
+    #[derive(Debug, Clone, Default, Deserialize)]
+    #[serde(default, deny_unknown_fields)]
+    pub struct Config {
+        pub server: ServerConfig,
+        pub storage: StorageConfig,
+        pub limits: LimitsConfig,
+        pub retention: RetentionConfig,
+        pub logging: LoggingConfig,
+        pub endpoints: EndpointsConfig,
+        pub auth: AuthConfig,
+        pub keystore: KeystoreConfig,
+        pub queue: QueueConfig,
+        pub audit: AuditConfig,
+        pub tls: TlsConfig,
+        pub rate_limit: RateLimitConfig,
+    }
+
+    #[derive(Debug, Clone, Deserialize)]
+    #[serde(default, deny_unknown_fields)]
+    pub struct ServerConfig {
+        pub bind: String,
+        // 0 starts one worker per CPU core
+        pub workers: usize,
+    }
+
+    #[derive(Debug, Clone, Deserialize)]
+    #[serde(default, deny_unknown_fields)]
+    pub struct StorageConfig {
+        // Holds one folder per job plus the uploads folder
+        pub jobs_dir: String,
+        // Serve <static_dir>/frontend.html instead of the built in page
+        pub static_dir: Option<String>,
+    }
+
+    #[derive(Debug, Clone, Deserialize)]
+    #[serde(default, deny_unknown_fields)]
+    pub struct LimitsConfig {
+        // Largest multipart upload, all files of one request together
+        pub max_upload_bytes: usize,
+        // Largest single uploaded file
+        pub max_file_bytes: usize,
+        // Largest text field of a form, such as a passphrase or compute expression, which is held in memory
+        pub max_text_field_bytes: usize,
+        // Largest JSON or raw body sent to /api/v1
+        pub max_api_body_bytes: usize,
+    }
+
+    #[derive(Debug, Clone, Deserialize)]
+    #[serde(default, deny_unknown_fields)]
+    pub struct RetentionConfig {
+        // How long a job's encrypted matrices, public keys and other outputs are kept for download
+        pub job_ttl_secs: u64,
+        // How long secret keys, key shares and re-key tokens left in a job are kept
+        pub secret_key_ttl_secs: u64,
+        // How long a decrypted CSV is kept when it is not downloaded, downloading it deletes it at once
+        pub plaintext_ttl_secs: u64,
+        // How long inputs and partial uploads are kept once no job is working on them
+        pub upload_ttl_secs: u64,
+        // How often expired files are looked for
+        pub sweep_interval_secs: u64,
+    }
+
+    #[derive(Debug, Clone, Deserialize)]
+    #[serde(default, deny_unknown_fields)]
+    pub struct LoggingConfig {
+        // error, warn, info, debug, trace or off. RUST_LOG still takes precedence when set.
+        pub level: String,
+    }
+
+    #[derive(Debug, Clone, Deserialize)]
+    #[serde(default, deny_unknown_fields)]
+    pub struct EndpointsConfig {
+        pub frontend: bool,
+        pub encrypt: bool,
+        pub decrypt: bool,
+        pub compute: bool,
+        pub rekey: bool,
+        pub jobs: bool,
+        pub api: bool,
+        // Prometheus metrics at /metrics, served without authentication
+        pub metrics: bool,
+    }
+
+    #[derive(Debug, Clone, Deserialize)]
+    #[serde(default, deny_unknown_fields)]
+    pub struct AuthConfig {
+        // When false every request is served as one shared anonymous user. Off by default so a new server starts
+        // without a users file, with a warning at startup.
+        pub enabled: bool,
+        // Users with their hashed passwords and API tokens, managed with the user-add and token-create commands
+        pub users_file: String,
+        // How long a login session lasts
+        pub session_ttl_secs: u64,
+    }
+
+    #[derive(Debug, Clone, Deserialize)]
+    #[serde(default, deny_unknown_fields)]
+    pub struct KeystoreConfig {
+        // Keeps generated keys on the server, so requests can name a key instead of uploading it
+        pub enabled: bool,
+        // One file per key, sealed under the master key
+        pub dir: String,
+        // 32 random bytes in hex, created with the keystore-init command
+        pub master_key_file: String,
+    }
+
+    #[derive(Debug, Clone, Deserialize)]
+    #[serde(default, deny_unknown_fields)]
+    pub struct QueueConfig {
+        // Threads running encryption, decryption and key generation, 0 starts one per CPU core
+        pub workers: usize,
+        // Jobs waiting for a free worker before further requests are turned away with 503
+        pub max_queued: usize,
+    }
+
+    #[derive(Debug, Clone, Deserialize)]
+    #[serde(default, deny_unknown_fields)]
+    pub struct AuditConfig {
+        // Records every key generation, encryption, decryption, computation and re-key
+        pub enabled: bool,
+        // Hash-chained JSON lines, appended to and never rewritten. Check it with the audit-verify command.
+        pub file: String,
+    }
+
+    #[derive(Debug, Clone, Deserialize)]
+    #[serde(default, deny_unknown_fields)]
+    pub struct TlsConfig {
+        // Serves HTTPS instead of HTTP. Send the server SIGHUP to reload the files once the certificate is renewed.
+        pub enabled: bool,
+        // PEM certificate chain, the server's own certificate first
+        pub cert_file: String,
+        // PEM private key, PKCS#8, PKCS#1 or SEC1
+        pub key_file: String,
+    }
+
+    #[derive(Debug, Clone, Deserialize)]
+    #[serde(default, deny_unknown_fields)]
+    pub struct RateLimitConfig {
+        // Turns clients sending keygen, encrypt, decrypt, compute, rekey, verify, inspect or login requests too fast
+        // away with 429
+        pub enabled: bool,
+        // Those requests being served at once across all clients, 0 for no cap. A background job only counts until
+        // it is queued, after which queue.workers limits it.
+        pub max_concurrent: usize,
+        pub keygen: EndpointLimit,
+        pub encrypt: EndpointLimit,
+        pub decrypt: EndpointLimit,
+        pub compute: EndpointLimit,
+        // Both /rekey and /rekey/token
+        pub rekey: EndpointLimit,
+        // Verifying and inspecting a passphrase protected key each derive the key wrapping key
+        pub verify: EndpointLimit,
+        pub inspect: EndpointLimit,
+        // Logins check a password hash and are where passwords are guessed, so they are limited more tightly
+        pub login: EndpointLimit,
+    }
+
+    // A token bucket per client, shared by the web and API versions of an endpoint
+    #[derive(Debug, Clone, Copy, Deserialize)]
+    #[serde(default, deny_unknown_fields)]
+    pub struct EndpointLimit {
+        // Requests a client can make per minute once its burst is used up, 0 for no limit
+        pub per_minute: u32,
+        // Requests a client that has been idle can make at once
+        pub burst: u32,
+    }
+
+    impl Default for ServerConfig {
+        fn default() -> ServerConfig {
+            ServerConfig { bind: "localhost:8080".to_string(), workers: 0 }
+        }
+    }
+
+    impl Default for StorageConfig {
+        fn default() -> StorageConfig {
+            StorageConfig { jobs_dir: "./temp_jobs".to_string(), static_dir: None }
+        }
+    }
+
+    impl Default for LimitsConfig {
+        fn default() -> LimitsConfig {
+            LimitsConfig {
+                max_upload_bytes: 1024 * 1024 * 1024,
+                max_file_bytes: 1024 * 1024 * 1024,
+                max_text_field_bytes: 64 * 1024,
+                max_api_body_bytes: 50 * 1024 * 1024,
+            }
+        }
+    }
+
+    impl Default for RetentionConfig {
+        fn default() -> RetentionConfig {
+            RetentionConfig {
+                job_ttl_secs: 60 * 60,
+                secret_key_ttl_secs: 15 * 60,
+                plaintext_ttl_secs: 10 * 60,
+                upload_ttl_secs: 60 * 60,
+                sweep_interval_secs: 60,
+            }
+        }
+    }
+
+    impl Default for LoggingConfig {
+        fn default() -> LoggingConfig {
+            LoggingConfig { level: "info".to_string() }
+        }
+    }
+
+    impl Default for EndpointsConfig {
+        fn default() -> EndpointsConfig {
+            EndpointsConfig { frontend: true, encrypt: true, decrypt: true, compute: true, rekey: true, jobs: true, api: true, metrics: true }
+        }
+    }
+
+    impl Default for AuthConfig {
+        fn default() -> AuthConfig {
+            AuthConfig { enabled: false, users_file: "./lattice_users.json".to_string(), session_ttl_secs: 24 * 60 * 60 }
+        }
+    }
+
+    impl Default for KeystoreConfig {
+        fn default() -> KeystoreConfig {
+            KeystoreConfig { enabled: false, dir: "./keystore".to_string(), master_key_file: "./lattice_master.key".to_string() }
+        }
+    }
+
+    impl Default for QueueConfig {
+        fn default() -> QueueConfig {
+            QueueConfig { workers: 0, max_queued: 64 }
+        }
+    }
+
+    impl Default for AuditConfig {
+        fn default() -> AuditConfig {
+            AuditConfig { enabled: true, file: "./lattice_audit.log".to_string() }
+        }
+    }
+
+    impl Default for RateLimitConfig {
+        fn default() -> RateLimitConfig {
+            let limit = EndpointLimit::default();
+            RateLimitConfig {
+                enabled: true, max_concurrent: 16, keygen: limit, encrypt: limit, decrypt: limit, compute: limit, rekey: limit,
+                verify: limit, inspect: limit, login: EndpointLimit { per_minute: 10, burst: 5 },
+            }
+        }
+    }
+
+    impl Default for EndpointLimit {
+        fn default() -> EndpointLimit {
+            EndpointLimit { per_minute: 30, burst: 10 }
+        }
+    }
+
+    impl Default for TlsConfig {
+        fn default() -> TlsConfig {
+            TlsConfig { enabled: false, cert_file: "./lattice_cert.pem".to_string(), key_file: "./lattice_key.pem".to_string() }
+        }
+    }
+
+    // Parses an environment variable, naming it in the error when its value is not valid
+    fn env_value<T: std::str::FromStr>(name: &str) -> Result<Option<T>, Box<dyn Error>> {
+        match std::env::var(name) {
+            Ok(value) => value.trim().parse().map(Some).map_err(|_| format!("{} has an invalid value '{}'", name, value).into()),
+            Err(std::env::VarError::NotPresent) => Ok(None),
+            Err(error) => Err(format!("{}: {}", name, error).into()),
+        }
+    }
+
+    fn override_from_env<T: std::str::FromStr>(field: &mut T, name: &str) -> Result<(), Box<dyn Error>> {
+        if let Some(value) = env_value(name)? {
+            *field = value;
+        }
+        Ok(())
+    }
+
+    impl RateLimitConfig {
+        // Each endpoint's limit with its name in the config file
+        pub fn endpoints(&self) -> [(&EndpointLimit, &'static str); 8] {
+            [(&self.keygen, "keygen"), (&self.encrypt, "encrypt"), (&self.decrypt, "decrypt"), (&self.compute, "compute"), (&self.rekey, "rekey"),
+                (&self.verify, "verify"), (&self.inspect, "inspect"), (&self.login, "login")]
+        }
+
+        fn endpoints_mut(&mut self) -> [(&mut EndpointLimit, &'static str); 8] {
+            [(&mut self.keygen, "keygen"), (&mut self.encrypt, "encrypt"), (&mut self.decrypt, "decrypt"), (&mut self.compute, "compute"), (&mut self.rekey, "rekey"),
+                (&mut self.verify, "verify"), (&mut self.inspect, "inspect"), (&mut self.login, "login")]
+        }
+    }
+
+    impl Config {
+        // Reads the config file if there is one, then applies environment overrides and validates the result
+        pub fn load(path: Option<&str>) -> Result<Config, Box<dyn Error>> {
+            let env_path = std::env::var(CONFIG_ENV).ok();
+            let mut config = match path.or(env_path.as_deref()) {
+                Some(path) => Config::from_file(path)?,
+                None if Path::new(DEFAULT_CONFIG_FILE).exists() => Config::from_file(DEFAULT_CONFIG_FILE)?,
+                None => Config::default(),
+            };
+            config.apply_env()?;
+            config.validate()?;
+            Ok(config)
+        }
+
+        pub fn from_file(path: &str) -> Result<Config, Box<dyn Error>> {
+            let contents = fs::read_to_string(path).map_err(|error| format!("Cannot read config file {}: {}", path, error))?;
+            toml::from_str(&contents).map_err(|error| format!("Invalid config file {}: {}", path, error).into())
+        }
+
+        fn apply_env(&mut self) -> Result<(), Box<dyn Error>> {
+            override_from_env(&mut self.server.bind, "LATTICE_BIND")?;
+            override_from_env(&mut self.server.workers, "LATTICE_WORKERS")?;
+            override_from_env(&mut self.storage.jobs_dir, "LATTICE_JOBS_DIR")?;
+            if let Some(static_dir) = env_value("LATTICE_STATIC_DIR")? {
+                self.storage.static_dir = Some(static_dir);
+            }
+            override_from_env(&mut self.limits.max_upload_bytes, "LATTICE_MAX_UPLOAD_BYTES")?;
+            override_from_env(&mut self.limits.max_file_bytes, "LATTICE_MAX_FILE_BYTES")?;
+            override_from_env(&mut self.limits.max_text_field_bytes, "LATTICE_MAX_TEXT_FIELD_BYTES")?;
+            override_from_env(&mut self.limits.max_api_body_bytes, "LATTICE_MAX_API_BODY_BYTES")?;
+            override_from_env(&mut self.retention.job_ttl_secs, "LATTICE_JOB_TTL_SECS")?;
+            override_from_env(&mut self.retention.secret_key_ttl_secs, "LATTICE_SECRET_KEY_TTL_SECS")?;
+            override_from_env(&mut self.retention.plaintext_ttl_secs, "LATTICE_PLAINTEXT_TTL_SECS")?;
+            override_from_env(&mut self.retention.upload_ttl_secs, "LATTICE_UPLOAD_TTL_SECS")?;
+            override_from_env(&mut self.retention.sweep_interval_secs, "LATTICE_SWEEP_INTERVAL_SECS")?;
+            override_from_env(&mut self.logging.level, "LATTICE_LOG_LEVEL")?;
+            let endpoints = &mut self.endpoints;
+            override_from_env(&mut endpoints.frontend, "LATTICE_ENDPOINT_FRONTEND")?;
+            override_from_env(&mut endpoints.encrypt, "LATTICE_ENDPOINT_ENCRYPT")?;
+            override_from_env(&mut endpoints.decrypt, "LATTICE_ENDPOINT_DECRYPT")?;
+            override_from_env(&mut endpoints.compute, "LATTICE_ENDPOINT_COMPUTE")?;
+            override_from_env(&mut endpoints.rekey, "LATTICE_ENDPOINT_REKEY")?;
+            override_from_env(&mut endpoints.jobs, "LATTICE_ENDPOINT_JOBS")?;
+            override_from_env(&mut endpoints.api, "LATTICE_ENDPOINT_API")?;
+            override_from_env(&mut endpoints.metrics, "LATTICE_ENDPOINT_METRICS")?;
+            override_from_env(&mut self.auth.enabled, "LATTICE_AUTH_ENABLED")?;
+            override_from_env(&mut self.auth.users_file, "LATTICE_USERS_FILE")?;
+            override_from_env(&mut self.auth.session_ttl_secs, "LATTICE_SESSION_TTL_SECS")?;
+            override_from_env(&mut self.keystore.enabled, "LATTICE_KEYSTORE_ENABLED")?;
+            override_from_env(&mut self.keystore.dir, "LATTICE_KEYSTORE_DIR")?;
+            override_from_env(&mut self.keystore.master_key_file, "LATTICE_MASTER_KEY_FILE")?;
+            override_from_env(&mut self.queue.workers, "LATTICE_QUEUE_WORKERS")?;
+            override_from_env(&mut self.queue.max_queued, "LATTICE_QUEUE_MAX_QUEUED")?;
+            override_from_env(&mut self.audit.enabled, "LATTICE_AUDIT_ENABLED")?;
+            override_from_env(&mut self.audit.file, "LATTICE_AUDIT_FILE")?;
+            override_from_env(&mut self.tls.enabled, "LATTICE_TLS_ENABLED")?;
+            override_from_env(&mut self.tls.cert_file, "LATTICE_TLS_CERT_FILE")?;
+            override_from_env(&mut self.tls.key_file, "LATTICE_TLS_KEY_FILE")?;
+            let rate_limit = &mut self.rate_limit;
+            override_from_env(&mut rate_limit.enabled, "LATTICE_RATE_LIMIT_ENABLED")?;
+            override_from_env(&mut rate_limit.max_concurrent, "LATTICE_RATE_LIMIT_MAX_CONCURRENT")?;
+            for (limit, endpoint) in rate_limit.endpoints_mut() {
+                override_from_env(&mut limit.per_minute, &format!("LATTICE_RATE_LIMIT_{}_PER_MINUTE", endpoint.to_uppercase()))?;
+                override_from_env(&mut limit.burst, &format!("LATTICE_RATE_LIMIT_{}_BURST", endpoint.to_uppercase()))?;
+            }
+            Ok(())
+        }
+
+        pub fn validate(&self) -> Result<(), Box<dyn Error>> {
+            if self.server.bind.to_socket_addrs().map(|mut addrs| addrs.next().is_none()).unwrap_or(true) {
+                return Err(format!("server.bind '{}' must be a host:port that resolves, e.g. localhost:8080", self.server.bind).into());
+            }
+            if self.server.workers > 1024 {
+                return Err(format!("server.workers is {}, at most 1024 are allowed", self.server.workers).into());
+            }
+            if self.storage.jobs_dir.trim().is_empty() {
+                return Err("storage.jobs_dir must not be empty".into());
+            }
+            if let Some(static_dir) = &self.storage.static_dir {
+                if !Path::new(static_dir).join("frontend.html").is_file() {
+                    return Err(format!("storage.static_dir '{}' has no frontend.html", static_dir).into());
+                }
+            }
+            let limits = &self.limits;
+            if limits.max_upload_bytes == 0 || limits.max_file_bytes == 0 || limits.max_text_field_bytes == 0 || limits.max_api_body_bytes == 0 {
+                return Err("limits.max_upload_bytes, max_file_bytes, max_text_field_bytes and max_api_body_bytes must be greater than 0".into());
+            }
+            let retention = &self.retention;
+            if retention.job_ttl_secs == 0 || retention.secret_key_ttl_secs == 0 || retention.plaintext_ttl_secs == 0
+                || retention.upload_ttl_secs == 0 || retention.sweep_interval_secs == 0 {
+                return Err("retention.job_ttl_secs, secret_key_ttl_secs, plaintext_ttl_secs, upload_ttl_secs and sweep_interval_secs must be greater than 0".into());
+            }
+            if !["error", "warn", "info", "debug", "trace", "off"].contains(&self.logging.level.as_str()) {
+                return Err(format!("logging.level '{}' must be one of error, warn, info, debug, trace or off", self.logging.level).into());
+            }
+            if self.auth.enabled && !Path::new(&self.auth.users_file).is_file() {
+                return Err(format!("auth.users_file '{}' does not exist. Add a user with the user-add command, or set auth.enabled = false to serve without logins", self.auth.users_file).into());
+            }
+            if self.auth.session_ttl_secs == 0 {
+                return Err("auth.session_ttl_secs must be greater than 0".into());
+            }
+            if self.keystore.enabled && !Path::new(&self.keystore.master_key_file).is_file() {
+                return Err(format!("keystore.master_key_file '{}' does not exist. Create one with the keystore-init command", self.keystore.master_key_file).into());
+            }
+            if self.queue.workers > 1024 {
+                return Err(format!("queue.workers is {}, at most 1024 are allowed", self.queue.workers).into());
+            }
+            if self.queue.max_queued == 0 {
+                return Err("queue.max_queued must be greater than 0".into());
+            }
+            if self.audit.enabled && self.audit.file.trim().is_empty() {
+                return Err("audit.file must not be empty while the audit log is enabled".into());
+            }
+            for (limit, endpoint) in self.rate_limit.endpoints() {
+                if limit.per_minute > 0 && limit.burst == 0 {
+                    return Err(format!("rate_limit.{}.burst must be greater than 0 while its per_minute is set", endpoint).into());
+                }
+            }
+            if self.tls.enabled {
+                for (name, file) in [("tls.cert_file", &self.tls.cert_file), ("tls.key_file", &self.tls.key_file)] {
+                    if !Path::new(file).is_file() {
+                        return Err(format!("{} '{}' does not exist", name, file).into());
+                    }
+                }
+            }
+            Ok(())
+        }
+    }
+}
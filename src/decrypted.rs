@@ -1,11 +1,18 @@
 pub mod lattice_decrypt {
     use std::fs::File;
-    use std::env;
     use std::error::Error;  
-    use std::io::{self, BufRead};
+    use std::io::{self, BufRead, Write};
     use std::path::Path;
     use nalgebra::DMatrix;
-    use csv::Writer;
+    use csv::{ReaderBuilder, Writer};
+    use zeroize::Zeroizing;
+    use crate::encrypted::lattice_encrypt::{key_fingerprint, KEY_FINGERPRINT_HEADER, NUMERIC_MATRIX_MARKER};
+    use crate::keyfile::lattice_keyfile::{read_secret_key, WRAPPED_KEY_MARKER};
+    use crate::rekey::lattice_rekey::REKEY_FROM_HEADER;
+    use crate::sharing::lattice_sharing::{lattice_combine_key_share_files, KEY_SHARE_MARKER};
+    use crate::progress::lattice_progress::{check_cancelled, Progress, Stage};
+    use crate::secret::lattice_secret::SecretMatrix;
+    use crate::metrics::lattice_metrics;
 
 
     fn initialize_file_reader<P: AsRef<Path>>(path: P) -> io::Result<io::BufReader<File>> {
@@ -13,144 +20,308 @@ pub mod lattice_decrypt {
         Ok(io::BufReader::new(file))
     }
 
-    fn process_file<P: AsRef<Path>>(path: P) -> Result<DMatrix<f64>, Box<dyn std::error::Error>> {
-        let reader = initialize_file_reader(&path)?;
+    pub fn process_file<P: AsRef<Path>>(path: P) -> Result<DMatrix<f64>, Box<dyn std::error::Error>> {
+        parse_matrix(initialize_file_reader(&path)?)
+    }
+
+    // Parses a matrix in the bracketed layout written by write_matrix_file, skipping '#' header lines
+    pub fn parse_matrix<R: BufRead>(reader: R) -> Result<DMatrix<f64>, Box<dyn std::error::Error>> {
         let mut matrix = Vec::new();
 
         for line in reader.lines() {
-            let line = line?;
-            let mut numbers = Vec::new();
-            let mut current_number = String::new();
-            
-            for fart in line.chars() {
-                if fart.is_digit(10) || fart == '.' || fart == '-' {
-                    current_number.push(fart);
-                } else if !current_number.is_empty() {
-                    if let Ok(num) = current_number.parse::<f64>() {
-                        numbers.push(num);
-                        current_number.clear();
-                    } else {
-                        // Handle parsing error here
-                    }
-                }
-            }
+            let numbers = parse_matrix_line(&line?);
             if !numbers.is_empty() {
                 matrix.push(numbers);
             }
-
-            // for row in &matrix {
-            //     println!("{:?}", row);
-            // }
         }
+        matrix_from_rows(&matrix)
+    }
+
+    // Parses the text of a secret key. Lines are borrowed from the text rather than copied, and the parsed rows
+    // are zeroed once the matrix is built.
+    pub fn parse_secret_matrix(text: &str) -> Result<SecretMatrix, Box<dyn std::error::Error>> {
+        let matrix: Vec<Zeroizing<Vec<f64>>> = text.lines().map(parse_matrix_line).filter(|numbers| !numbers.is_empty()).collect();
+        Ok(SecretMatrix::new(matrix_from_rows(&matrix)?))
+    }
+
+    fn matrix_from_rows(matrix: &[Zeroizing<Vec<f64>>]) -> Result<DMatrix<f64>, Box<dyn std::error::Error>> {
         // Convert pushed numbers array to matrix<f64>
         // Convert Vec<Vec<u64>> to nalgebra DMatrix
         let rows = matrix.len();
+        if rows == 0 {
+            return Err("File does not contain a matrix".into());
+        }
 
-        let cols = matrix[0].len(); // Assuming all inner vectors have the same length
+        let cols = matrix[0].len();
+        if let Some(row) = matrix.iter().position(|numbers| numbers.len() != cols) {
+            return Err(format!("Matrix row {} has {} values but the first row has {}", row + 1, matrix[row].len(), cols).into());
+        }
         let output_matrix = DMatrix::from_fn(rows, cols, |i, j| matrix[i][j]);
 
         Ok(output_matrix)
     }
 
-    fn decrypt(encrypted_matrix: Result<DMatrix<f64>, Box<dyn std::error::Error>>, secret_key: Result<DMatrix<f64>, Box<dyn std::error::Error>>) -> Vec<Vec<u64>> {
-        // Unwrap the results or handle the errors
-        let encrypted_matrix = match encrypted_matrix {
-            Ok(matrix) => matrix,
-            Err(_err) => return vec![vec![]], // Return an empty vector if there's an error
-        };
-        let secret_key = match secret_key {
-            Ok(matrix) => matrix,
-            Err(_err) => return vec![vec![]], // Return an empty vector if there's an error
-        };
+    // The numbers of one line of a matrix file. Header lines such as the key fingerprint and the bracket lines
+    // above and below the matrix have none.
+    fn parse_matrix_line(line: &str) -> Zeroizing<Vec<f64>> {
+        let mut numbers = Zeroizing::new(Vec::new());
+        if line.starts_with('#') {
+            return numbers;
+        }
+        // Sized so it never reallocates and leaves digits of a secret key behind
+        let mut current_number = Zeroizing::new(String::with_capacity(line.len()));
 
-        // Double check encryption isn't invalid
-        if encrypted_matrix.ncols() != secret_key.nrows() {
-            println!("Incompatible dimensions for matrix multiplication");
-            return vec![vec![]]; // Return an empty vector if dimensions are incompatible
+        for fart in line.chars() {
+            if fart.is_ascii_digit() || fart == '.' || fart == '-' {
+                current_number.push(fart);
+            } else if !current_number.is_empty() {
+                if let Ok(num) = current_number.parse::<f64>() {
+                    numbers.push(num);
+                }
+                current_number.clear();
+            }
+        }
+        if let Ok(num) = current_number.parse::<f64>() {
+            numbers.push(num);
         }
+        numbers
+    }
 
-        // Perform matrix multiplication
-        let testing_f64 = encrypted_matrix * secret_key;
+    // Decrypts a byte matrix one row at a time, writing each recovered CSV row as it goes so memory use does not
+    // grow with the size of the file
+    fn decrypt_rows<W: Write>(encrypted_matrix_path: &str, secret_key: &DMatrix<f64>, output: W, progress: &dyn Progress) -> Result<(), Box<dyn Error>> {
+        let file_size = std::fs::metadata(encrypted_matrix_path)?.len(); // Progress is counted in bytes read
+        let reader = initialize_file_reader(encrypted_matrix_path)?;
+        let mut wtr = Writer::from_writer(output);
+        let mut rows = 0;
+        let mut bytes_read = 0;
+
+        for line in reader.lines() {
+            let line = line?;
+            bytes_read += line.len() as u64 + 1;
+            progress.report(Stage::Decrypting, bytes_read, file_size);
+            check_cancelled(progress)?;
 
-        // Convert testing matrix back to u64 and maintain shape
-        let mut decrypted_matrix = Vec::new();
-        for row in testing_f64.row_iter() {
-            let mut row_values = Vec::new();
-            for &elem in row.iter() {
-                row_values.push(elem.round() as u64);
+            let encrypted_row = parse_matrix_line(&line);
+            if encrypted_row.is_empty() {
+                continue;
+            }
+            // Double check encryption isn't invalid
+            if encrypted_row.len() != secret_key.nrows() {
+                return Err("Incompatible dimensions for matrix multiplication".into());
             }
-            decrypted_matrix.push(row_values);
+
+            // Multiply the row by the secret key and convert back to the UTF-8 bytes of the CSV row
+            let bytes: Zeroizing<Vec<u8>> = Zeroizing::new(secret_key.column_iter()
+                .map(|column| column.iter().zip(encrypted_row.iter()).map(|(&entry, &value)| entry * value).sum::<f64>().round() as u64 as u8)
+                .collect());
+            let line = std::str::from_utf8(&bytes)?;
+
+            // Remove trailing '|' padding and then split by commas. The first line holds the headers.
+            let record: Vec<&str> = line.trim_end_matches('|').split(',').collect();
+            wtr.write_record(&record)?;
+            rows += 1;
+        }
+
+        if rows == 0 {
+            return Err("File does not contain a matrix".into());
+        }
+        lattice_metrics::observe_matrix("decrypt", rows, secret_key.nrows());
+        // Flush data to the CSV file
+        progress.report(Stage::Writing, 0, 1);
+        wtr.flush()?;
+        progress.report(Stage::Writing, 1, 1);
+        Ok(())
+    }
+
+    // Decrypts an encrypted matrix file and writes the recovered CSV to output_file_path
+    pub fn lattice_decrypt_csv(encrypted_matrix_path: &str, private_key_path: &str, output_file_path: &str, passphrase: Option<&str>, progress: &dyn Progress) -> io::Result<()> {        
+        // A wrong passphrase or key must fail the request rather than produce an empty output
+        let secret_key = read_secret_key(private_key_path, passphrase).map_err(|e| io::Error::other(e.to_string()))?;
+        lattice_decrypt_csv_with_key(encrypted_matrix_path, &secret_key, output_file_path, progress)
+    }
+
+    // Decrypts with a secret key rebuilt from k-of-n key share files instead of a single secret_key.key
+    pub fn lattice_decrypt_csv_with_shares<P: AsRef<Path>>(encrypted_matrix_path: &str, share_paths: &[P], output_file_path: &str, progress: &dyn Progress) -> io::Result<()> {
+        let secret_key = lattice_combine_key_share_files(share_paths).map_err(|e| io::Error::other(e.to_string()))?;
+        lattice_decrypt_csv_with_key(encrypted_matrix_path, &secret_key, output_file_path, progress)
+    }
+
+    pub fn lattice_decrypt_csv_with_key(encrypted_matrix_path: &str, secret_key: &DMatrix<f64>, output_file_path: &str, progress: &dyn Progress) -> io::Result<()> {
+        lattice_decrypt_to_writer(encrypted_matrix_path, secret_key, File::create(output_file_path)?, progress)
+    }
+
+    // Decrypts into any writer, so callers that only pass the plaintext on never have to write it to a file
+    pub fn lattice_decrypt_to_writer<W: Write>(encrypted_matrix_path: &str, secret_key: &DMatrix<f64>, output: W, progress: &dyn Progress) -> io::Result<()> {
+        check_key_fingerprint(encrypted_matrix_path, secret_key).map_err(|e| io::Error::other(e.to_string()))?;
+        progress.report(Stage::Parsing, 1, 1);
+
+        // Column-wise numeric matrices (see lattice_encrypt_numeric_csv) carry labels and are decrypted separately
+        if is_numeric_matrix(encrypted_matrix_path)? {
+            return decrypt_numeric_matrix(encrypted_matrix_path, secret_key, output, progress)
+                .map_err(|e| io::Error::other(e.to_string()));
+        }
+
+        decrypt_rows(encrypted_matrix_path, secret_key, output, progress).map_err(|e| io::Error::other(e.to_string()))
+    }
+
+    // The header lines this crate writes ahead of a matrix, key or key share. Any other line is data, even one
+    // starting with '#' such as the row of a numeric column labelled '#id'.
+    const KNOWN_HEADERS: &[&str] = &[
+        NUMERIC_MATRIX_MARKER, KEY_FINGERPRINT_HEADER, REKEY_FROM_HEADER,
+        WRAPPED_KEY_MARKER, "#kdf", "#salt", "#nonce",
+        KEY_SHARE_MARKER, "#threshold", "#share-index",
+    ];
+
+    // Whether the buffered text starts with a known header line, checked without consuming it
+    fn starts_with_header(buffer: &[u8]) -> bool {
+        KNOWN_HEADERS.iter().any(|header| {
+            buffer.starts_with(header.as_bytes()) && matches!(buffer.get(header.len()), None | Some(b' ' | b'\r' | b'\n'))
+        })
+    }
+
+    // Reads the leading header lines of a matrix file, leaving the reader at the first matrix line
+    fn read_header_lines<R: BufRead>(reader: &mut R) -> io::Result<Vec<String>> {
+        let mut headers = Vec::new();
+        while starts_with_header(reader.fill_buf()?) {
+            let mut line = String::new();
+            reader.read_line(&mut line)?;
+            headers.push(line.trim_end().to_string());
         }
+        Ok(headers)
+    }
+
+    // Returns every leading header line of a file
+    pub fn read_headers<P: AsRef<Path>>(path: P) -> io::Result<Vec<String>> {
+        read_header_lines(&mut initialize_file_reader(path)?)
+    }
+
+    // Returns every leading header line of the contents of a file
+    pub fn parse_headers(contents: &str) -> Vec<String> {
+        read_header_lines(&mut contents.as_bytes()).unwrap_or_default()
+    }
+
+    // Returns the value of a '#name value' header line of a matrix file, if present
+    pub fn read_header_value<P: AsRef<Path>>(path: P, name: &str) -> io::Result<Option<String>> {
+        let headers = read_header_lines(&mut initialize_file_reader(path)?)?;
+        Ok(headers.iter().find_map(|header| {
+            header.strip_prefix(name).filter(|rest| rest.starts_with(' ')).map(|value| value.trim().to_string())
+        }))
+    }
 
-        decrypted_matrix
+    // Returns the fingerprint of the key an encrypted matrix or re-key token was made for, if it records one
+    pub fn read_key_fingerprint<P: AsRef<Path>>(path: P) -> io::Result<Option<String>> {
+        read_header_value(path, KEY_FINGERPRINT_HEADER)
     }
 
-    fn utf8_to_string(strings: Vec<Vec<u64>>) -> Result<Vec<String>, Box<dyn Error>> {
-        let mut result_strings = Vec::new();
-        for line in strings {
-            let mut bytes = Vec::new();
-            for &val in &line {
-                bytes.push(val as u8);
+    // Fails when the encrypted matrix records a fingerprint that does not belong to the given secret key
+    pub fn check_key_fingerprint<P: AsRef<Path>>(encrypted_matrix_path: P, secret_key: &DMatrix<f64>) -> Result<(), Box<dyn Error>> {
+        if let Some(expected) = read_key_fingerprint(encrypted_matrix_path)? {
+            let actual = key_fingerprint(secret_key);
+            if expected != actual {
+                return Err(format!("Secret key {} does not match the key {} the matrix was encrypted with", actual, expected).into());
             }
-            let string = String::from_utf8(bytes)?;
-            result_strings.push(string);
         }
-        Ok(result_strings)
+        Ok(())
+    }
+
+    pub fn is_numeric_matrix<P: AsRef<Path>>(path: P) -> io::Result<bool> {
+        let mut first_line = String::new();
+        initialize_file_reader(path)?.read_line(&mut first_line)?;
+        Ok(first_line.trim_end() == NUMERIC_MATRIX_MARKER)
     }
 
-    // New function to process strings and write to a file
-    fn write_processed_strings_to_file(strings: Vec<String>, file_path: &str) -> Result<(), Box<dyn Error>> {
-        let mut wtr = Writer::from_writer(File::create(file_path)?);
+    // Reads a column-wise numeric matrix written by write_numeric_matrix back into its labels and values
+    pub fn read_numeric_matrix<P: AsRef<Path>>(path: P) -> Result<(Vec<String>, DMatrix<f64>), Box<dyn Error>> {
+        parse_numeric_matrix(initialize_file_reader(&path)?)
+    }
 
-        for (index, line) in strings.iter().enumerate() {
-            // Remove trailing '|' characters and then split by commas
-            let processed_line = line.trim_end_matches('|');
-            let record: Vec<&str> = processed_line.split(',').collect();
+    pub fn parse_numeric_matrix<R: BufRead>(mut reader: R) -> Result<(Vec<String>, DMatrix<f64>), Box<dyn Error>> {
+        let headers = read_header_lines(&mut reader)?;
+        if headers.first().map(String::as_str) != Some(NUMERIC_MATRIX_MARKER) {
+            return Err("File is not a column-wise numeric encrypted matrix".into());
+        }
 
-            if index == 0 {
-                // The first line contains headers
-                wtr.write_record(&record)?;
-            } else {
-                // Subsequent lines are data rows
-                wtr.write_record(&record)?;
+        let mut rdr = ReaderBuilder::new().has_headers(false).from_reader(reader);
+        let mut labels = Vec::new();
+        let mut rows: Vec<Vec<f64>> = Vec::new();
+        for result in rdr.records() {
+            let record = result?;
+            let mut fields = record.iter();
+            labels.push(fields.next().unwrap_or_default().to_string());
+            let row = fields.map(|value| value.parse::<f64>()).collect::<Result<Vec<f64>, _>>()?;
+            if let Some(first) = rows.first() {
+                if first.len() != row.len() {
+                    return Err("Numeric encrypted matrix rows have different lengths".into());
+                }
             }
+            rows.push(row);
         }
 
-        // Flush data to the CSV file
+        if rows.is_empty() {
+            return Err("Numeric encrypted matrix is empty".into());
+        }
+
+        let output_matrix = DMatrix::from_fn(rows.len(), rows[0].len(), |i, j| rows[i][j]);
+        Ok((labels, output_matrix))
+    }
+
+    // Decrypts a column-wise numeric matrix and writes it back out as a CSV with one column per label
+    pub fn lattice_decrypt_numeric_csv(encrypted_matrix_path: &str, private_key_path: &str, output_file_path: &str, passphrase: Option<&str>, progress: &dyn Progress) -> Result<(), Box<dyn Error>> {
+        let secret_key = read_secret_key(private_key_path, passphrase)?;
+        check_key_fingerprint(encrypted_matrix_path, &secret_key)?;
+        decrypt_numeric_matrix(encrypted_matrix_path, &secret_key, File::create(output_file_path)?, progress)
+    }
+
+    fn decrypt_numeric_matrix<W: Write>(encrypted_matrix_path: &str, secret_key: &DMatrix<f64>, output: W, progress: &dyn Progress) -> Result<(), Box<dyn Error>> {
+        let (labels, encrypted_matrix) = read_numeric_matrix(encrypted_matrix_path)?;
+
+        if encrypted_matrix.ncols() != secret_key.nrows() {
+            return Err("Incompatible dimensions for matrix multiplication".into());
+        }
+
+        lattice_metrics::observe_matrix("decrypt", encrypted_matrix.nrows(), encrypted_matrix.ncols());
+
+        // Row i of the decrypted matrix holds every value of column labels[i]
+        progress.report(Stage::Decrypting, 0, 1);
+        let decrypted_matrix = SecretMatrix::new(encrypted_matrix * secret_key);
+        progress.report(Stage::Decrypting, 1, 1);
+
+        let mut wtr = Writer::from_writer(output);
+        wtr.write_record(&labels)?;
+        let rows = decrypted_matrix.ncols() as u64;
+        for (row, column) in decrypted_matrix.column_iter().enumerate() {
+            wtr.write_record(column.iter().map(|value| format_decrypted_number(*value)))?;
+            progress.report(Stage::Writing, row as u64 + 1, rows);
+        }
         wtr.flush()?;
         Ok(())
     }
 
-    pub fn lattice_decrypt_csv(encrypted_matrix_path: &str, private_key_path: &str, public_key_path: &str) -> io::Result<()> {        
-        // Specify the path to the encrypted_matrix
-        let encrypted_matrix_path = encrypted_matrix_path;
-        let encrypted_matrix = process_file(encrypted_matrix_path);
-        // Specify
-        let secret_key_path = private_key_path;
-        let secret_key = process_file(secret_key_path);
-        
-        let output_file_path = "temp_decrypted_output/output.csv";
-
-        let result = decrypt(encrypted_matrix, secret_key);
-        // Print the shape of the decrypted matrix
-        let _num_rows = result.len();
-        let _num_cols = if let Some(row) = result.get(0) {
-            row.len()
-        } else {
-            0
-        };
-        // println!("Number of rows in decrypted matrix: {}", num_rows);
-        // println!("Number of columns in decrypted matrix: {}", num_cols);
-        // utf8_to_string(result);
-        match utf8_to_string(result) {
-            Ok(strings) => {
-                // Update the path as needed
-                if let Err(e) = write_processed_strings_to_file(strings, output_file_path) {
-                    eprintln!("Failed to write processed strings to file: {}", e);
-                }
-            },
-            Err(e) => eprintln!("Failed to convert matrix to strings: {}", e),
+    // Rounds away the floating point noise left by the key inverse so integers print as integers
+    fn format_decrypted_number(value: f64) -> String {
+        let rounded = (value * 1e9).round() / 1e9;
+        if rounded == 0.0 { "0".to_string() } else { rounded.to_string() }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        #[test]
+        fn only_known_headers_are_skipped() {
+            let text = "#lattice-columns\n#key-fingerprint 0123\n#id,5,4\nscore,50,40\n";
+            assert_eq!(parse_headers(text), vec!["#lattice-columns", "#key-fingerprint 0123"]);
+            let (labels, matrix) = parse_numeric_matrix(text.as_bytes()).unwrap();
+            assert_eq!(labels, vec!["#id", "score"]);
+            assert_eq!(matrix, DMatrix::from_row_slice(2, 2, &[5.0, 4.0, 50.0, 40.0]));
+        }
+
+        #[test]
+        fn ragged_rows_are_an_error() {
+            let error = parse_matrix("  ┌     ┐\n  │ 1 2 │\n  │ 3 │\n  └     ┘\n".as_bytes()).unwrap_err();
+            assert_eq!(error.to_string(), "Matrix row 2 has 1 values but the first row has 2");
+            assert!(parse_secret_matrix("1 2\n3 4 5\n").is_err());
         }
-        Ok(())
     }
-}
\ No newline at end of file
+}
@@ -1,74 +1,57 @@
 pub mod lattice_encrypt {
     
     use std::error::Error; //Useful for debugging
-    use std::env; // Taking command line arguments
     use csv::ReaderBuilder; //Reading input file
     use nalgebra::DMatrix;
     use std::fs::File;
-    use std::fs;
+    use std::path::Path;
     use std::io::prelude::*;
+    use std::io::BufWriter;
     use log::{info};
+    use sha2::{Digest, Sha256};
+    use zeroize::Zeroizing;
+    use crate::keyfile::lattice_keyfile::write_secret_key;
+    use crate::progress::lattice_progress::{check_cancelled, Progress, Stage};
+    use crate::secret::lattice_secret::SecretMatrix;
+    use crate::metrics::lattice_metrics::{self, KEYGEN_ATTEMPTS, KEYGEN_SINGULAR};
 
     // Function for determining longest row after utf-8 to perform padding for matrix operations
-    fn find_max_row_length(file_path: &str) -> Result<usize, Box<dyn Error>> {
+    fn find_max_row_length(file_path: &str, progress: &dyn Progress) -> Result<usize, Box<dyn Error>> {
+        let file_size = std::fs::metadata(file_path)?.len(); // Progress is counted in bytes read
         let mut rdr = ReaderBuilder::new().has_headers(true).from_path(file_path)?; // Initialize a CSV reader
         let headers = rdr.headers()?.clone(); // Clone the headers for manipulation
         let mut max_row_length = string_array_to_utf8_array(&headers)?; // Convert headers to UTF-8 and get starting max length
 
-        for result in rdr.records() { // Iterate through rows of csv file
-            let record = result?; // Assign line to record
+        let mut record = csv::StringRecord::new();
+        while rdr.read_record(&mut record)? { // Iterate through rows of csv file
             let row_length = string_array_to_utf8_array(&record)?; // Check row length
             if row_length > max_row_length {
                 max_row_length = row_length; // Replace if larger than max row length
             }
+            progress.report(Stage::Parsing, rdr.position().byte(), file_size);
+            check_cancelled(progress)?;
         }
         Ok(max_row_length)
     }
 
     // Function for converting string read from csv into utf8 ndarray to determine longest line in csv file
+    // Only the length is needed, so the bytes of the row are counted rather than copied
     fn string_array_to_utf8_array(strings: &csv::StringRecord) ->  Result<usize, Box<dyn Error>>  {
-        let mut utf8_values: Vec<u64> = Vec::new(); //Initialize utf8 values
-        for (i, s) in strings.iter().enumerate() { //Iteration through line
-            utf8_values.extend(s.bytes().map(|b| <u8 as Into<u64>>::into(b)));
-            if i < strings.len() - 1 {
-                utf8_values.push(b',' as u64); //Ensure correct conversion i.e adding with commas for correct formatting of conversion
-            }
-        }
-        // Create a DMatrix with one row and a dynamic number of columns
-        let num_cols = utf8_values.len();
+        let commas = strings.len().saturating_sub(1); // Fields are joined with commas
+        let num_cols = strings.iter().map(str::len).sum::<usize>() + commas;
 
-        Ok(num_cols) //take clones values to determine
+        Ok(num_cols)
     }
 
-    fn lattice_encrypt_func(file_path: &str, max_row_length: usize) -> Result<DMatrix<u64>, Box<dyn std::error::Error>> {
-        let mut rdr = ReaderBuilder::new().has_headers(true).from_path(file_path)?;
-        let headers = rdr.headers()?.clone(); // Clone the headers
-
-        // Initialize matrix with header as first row
-        let mut rows = vec![utf8_array_pad_to_max_length(&headers, max_row_length)?];
-
-        for result in rdr.records() {
-            let record = result?;
-            let row_array = utf8_array_pad_to_max_length(&record, max_row_length)?;
-            rows.push(row_array);
-        }
-
-        // Convert Vec<Vec<u64>> to nalgebra DMatrix
-        let num_cols = rows[0].ncols();
-        let num_rows = rows.len();
-        let mut matrix_data = Vec::new();
-        for row in &rows {
-            matrix_data.extend_from_slice(row.as_slice());
+    // The row as padded UTF-8 values. The buffer is sized up front and zeroed when dropped, so the plaintext
+    // is not left behind in freed memory.
+    fn utf8_array_pad_to_max_length(strings: &csv::StringRecord, max_length: usize) -> Result<Zeroizing<Vec<u64>>, Box<dyn Error>> {
+        if string_array_to_utf8_array(strings)? > max_length {
+            return Err("CSV file changed while it was being encrypted".into());
         }
-        let matrix = DMatrix::from_row_slice(num_rows as usize, num_cols, &matrix_data);
-
-        Ok(matrix)
-    }
-
-    fn utf8_array_pad_to_max_length(strings: &csv::StringRecord, max_length: usize) -> Result<DMatrix<u64>, Box<dyn Error>> {
-        let mut utf8_values: Vec<u64> = Vec::new();
+        let mut utf8_values = Zeroizing::new(Vec::with_capacity(max_length));
         for (i, s) in strings.iter().enumerate() {
-            utf8_values.extend(s.bytes().map(|b| <u8 as Into<u64>>::into(b)));
+            utf8_values.extend(s.bytes().map(<u8 as Into<u64>>::into));
             if i < strings.len() - 1 {
                 utf8_values.push(b',' as u64);
             }
@@ -77,47 +60,75 @@ pub mod lattice_encrypt {
         while utf8_values.len() < max_length {
             utf8_values.push(124);
         }
-        // Create a DMatrix with one row and a dynamic number of columns
-        let num_cols = utf8_values.len();
-        let matrix = DMatrix::<u64>::from_row_slice(1, num_cols, utf8_values.as_slice());
 
-        Ok(matrix)
+        Ok(utf8_values)
     }
 
-    fn generate_encrypted_matrix(original_matrix: &DMatrix<u64>) -> Option<(DMatrix<f64>, DMatrix<f64>, f64)> {
-        let size = original_matrix.ncols(); // Get the number of columns in the original matrix
-
-        // Create a secret key matrix with the same size as the original matrix
-        let mut encrypt_matrix = DMatrix::<f64>::zeros(size, size);
-        // Initialize public key, will be used to hold determinant
+    // Writes one row of original * encrypt_matrix in the bracketed layout of write_matrix_file. Rows are
+    // independent, so the encrypted matrix is written as the CSV is read and never held in memory.
+    fn write_encrypted_row<W: Write>(writer: &mut W, row: &[u64], encrypt_matrix: &DMatrix<f64>) -> Result<(), Box<dyn Error>> {
+        write!(writer, "  │")?;
+        for column in encrypt_matrix.column_iter() {
+            let value: f64 = row.iter().zip(column.iter()).map(|(&byte, &entry)| byte as f64 * entry).sum();
+            write!(writer, " {}", value)?;
+        }
+        writeln!(writer, " │")?;
+        Ok(())
+    }
 
-        // Populate the matrix with random ones
-        for i in 0..size {
-            for j in 0..size {
-                // Generate a random number between 0 and 9
-                let random_number = rand::random::<u8>() % 10;
-                // If the random number is less than 5, set the element to 1
-                if random_number < 5 {
-                    encrypt_matrix[(i, j)] = 1.0;
+    // Generates a random invertible 0/1 encrypt matrix of the given size together with its inverse, the secret key.
+    // None when it fails or is cancelled.
+    pub fn generate_key_matrices(size: usize, progress: &dyn Progress) -> Option<(SecretMatrix, SecretMatrix)> {
+        let mut attempts = 0;
+        loop {
+            if progress.is_cancelled() {
+                return None;
+            }
+            progress.report(Stage::KeyGeneration, 0, 2);
+            attempts += 1;
+
+            // Create a secret key matrix with the same size as the original matrix
+            let mut encrypt_matrix = SecretMatrix::new(DMatrix::<f64>::zeros(size, size));
+
+            // Populate the matrix with random ones
+            for i in 0..size {
+                for j in 0..size {
+                    // Generate a random number between 0 and 9
+                    let random_number = rand::random::<u8>() % 10;
+                    // If the random number is less than 5, set the element to 1
+                    if random_number < 5 {
+                        encrypt_matrix.set(i, j, 1.0);
+                    }
                 }
             }
-        }
-        
-        // Check if the determinant of the secret key is zero
-        if encrypt_matrix.determinant().abs() < f64::EPSILON {
-            // If the determinant is close to zero, recursively call the function
-            return generate_encrypted_matrix(original_matrix);
-        }
 
-        // Compute the inverse
-        let secret_key = encrypt_matrix.clone().try_inverse()?;
+            // Check if the determinant of the secret key is zero, and draw again if it is
+            if encrypt_matrix.determinant().abs() < f64::EPSILON {
+                lattice_metrics::increment(&KEYGEN_SINGULAR, &[]);
+                continue;
+            }
+            lattice_metrics::observe(&KEYGEN_ATTEMPTS, &[], attempts as f64);
+            progress.report(Stage::KeyGeneration, 1, 2);
+
+            // Compute the inverse
+            let secret_key = encrypt_matrix.copy().try_inverse()?;
+            progress.report(Stage::KeyGeneration, 2, 2);
+
+            return Some((encrypt_matrix, secret_key));
+        }
+    }
 
-        // Convert the original matrix to f64
-        let original_matrix_f64 = original_matrix.map(|elem| elem as f64);
+    // The cipher is linear: encrypted = original * encrypt_matrix, so any public operation applied on the
+    // left of the encrypted matrix (scaling, weighted sums of rows, A * encrypted) survives decryption
+    fn generate_encrypted_matrix(original_matrix_f64: &SecretMatrix, progress: &dyn Progress) -> Option<(DMatrix<f64>, SecretMatrix, f64)> {
+        let size = original_matrix_f64.ncols(); // Get the number of columns in the original matrix
 
+        let (encrypt_matrix, secret_key) = generate_key_matrices(size, progress)?;
 
         // Matrix multiplication to get the encrypted matrix
-        let encrypted_matrix = original_matrix_f64.clone() * encrypt_matrix;
+        progress.report(Stage::Encrypting, 0, 1);
+        let encrypted_matrix = &**original_matrix_f64 * &*encrypt_matrix;
+        progress.report(Stage::Encrypting, 1, 1);
 
         // Solve for the public key
         let public_key = secret_key.norm();
@@ -125,59 +136,263 @@ pub mod lattice_encrypt {
         Some((encrypted_matrix, secret_key, public_key))
     }
 
-    // Save encryption process to respective files
-    fn save_matrices_to_files(encrypted_matrix: &nalgebra::DMatrix<f64>,secret_key: &nalgebra::DMatrix<f64>,public_key: f64,) -> Result<(), Box<dyn Error>> {
-        info!("Made it to Lattice folder");
-        // Save encrypted matrix to file
-        let mut encrypted_matrix_file = File::create("temp_encrypted_output/encrypted_matrix.csv")?;
-        encrypted_matrix_file.write_all(format!("{}", encrypted_matrix).as_bytes())?;
-
-        // Save secret key to file
-        let mut secret_key_file = File::create("temp_encrypted_output/secret_key.key")?;
-        secret_key_file.write_all(format!("{}", secret_key).as_bytes())?;
+    // Header line naming the fingerprint of the secret key an encrypted matrix or re-key token belongs to
+    pub const KEY_FINGERPRINT_HEADER: &str = "#key-fingerprint";
+
+    // Identifies a key pair without revealing it: the first 16 bytes of the SHA-256 of the secret key's
+    // dimensions and values, hex encoded
+    pub fn key_fingerprint(secret_key: &DMatrix<f64>) -> String {
+        let mut hasher = Sha256::new();
+        hasher.update((secret_key.nrows() as u64).to_le_bytes());
+        hasher.update((secret_key.ncols() as u64).to_le_bytes());
+        // Row by row so the fingerprint does not depend on nalgebra's storage order
+        for row in secret_key.row_iter() {
+            for value in row.iter() {
+                hasher.update(value.to_le_bytes());
+            }
+        }
+        hasher.finalize()[..16].iter().map(|b| format!("{:02x}", b)).collect()
+    }
 
-        // Save public key to file
-        let mut public_key_file = File::create("temp_encrypted_output/public_key.key")?;
-        public_key_file.write_all(format!("{}", public_key).as_bytes())?;
+    // Writes a matrix in the bracketed layout read back by process_file, with an optional key fingerprint header
+    pub fn write_matrix_file(file_path: &str, matrix: &DMatrix<f64>, fingerprint: Option<&str>) -> Result<(), Box<dyn Error>> {
+        let mut file = File::create(file_path)?;
+        if let Some(fingerprint) = fingerprint {
+            writeln!(file, "{} {}", KEY_FINGERPRINT_HEADER, fingerprint)?;
+        }
+        file.write_all(format!("{}", matrix).as_bytes())?;
+        Ok(())
+    }
 
-        // Ignore the unused Result values
-        let _ = encrypted_matrix_file;
-        let _ = secret_key_file;
-        let _ = public_key_file;
+    // Generates a key pair of the given size without encrypting anything and writes secret_key.key and
+    // public_key.key to output_dir
+    pub fn lattice_generate_keys(size: usize, output_dir: &str, passphrase: Option<&str>, progress: &dyn Progress) -> Result<String, Box<dyn Error>> {
+        if size == 0 {
+            return Err("Key size must be at least 1".into());
+        }
+        let (_, secret_key) = match generate_key_matrices(size, progress) {
+            Some(keys) => keys,
+            None => {
+                check_cancelled(progress)?;
+                return Err("Failed to generate keys. Contact Administrator".into());
+            }
+        };
+        let output_dir = Path::new(output_dir);
+        progress.report(Stage::Writing, 0, 1);
+        write_secret_key(&output_dir.join("secret_key.key").to_string_lossy(), &secret_key, passphrase)?;
+        write_public_key_file(&output_dir.join("public_key.key").to_string_lossy(), secret_key.norm())?;
+        progress.report(Stage::Writing, 1, 1);
+        Ok(format!("Generated {}x{} key pair {}", size, size, key_fingerprint(&secret_key)))
+    }
 
+    // Writes a public key, the norm of its secret key
+    pub fn write_public_key_file(file_path: &str, public_key: f64) -> Result<(), Box<dyn Error>> {
+        let mut file = File::create(file_path)?;
+        file.write_all(format!("{}", public_key).as_bytes())?;
         Ok(())
     }
 
-    pub fn lattice_encrypt_csv(file_path: &str) -> Result<String, Box<dyn std::error::Error>> {
-        // Call find_max_row_length to get the maximum row length
-        let max_row_length = find_max_row_length(file_path)?;
+    // Encrypts a CSV file and writes encrypted_matrix.csv, secret_key.key and public_key.key to output_dir, wrapping
+    // the secret key when a passphrase is given
+    pub fn lattice_encrypt_csv(file_path: &str, output_dir: &str, passphrase: Option<&str>, progress: &dyn Progress) -> Result<String, Box<dyn std::error::Error>> {
+        let secret_key = lattice_encrypt_csv_returning_secret_key(file_path, output_dir, progress)?;
+        write_secret_key(&Path::new(output_dir).join("secret_key.key").to_string_lossy(), &secret_key, passphrase)?;
 
-        // Call fart with the maximum row length
-        let matrix = lattice_encrypt_func(file_path, max_row_length)?;
+        // Return some success message or relevant data as Ok
+        Ok("Encryption and saving successful".to_string())
+    }
 
-        // Call generate_encrypted_matrix with the fart output
-        let encryption_files = generate_encrypted_matrix(&matrix);
+    // Encrypts a CSV file and writes only encrypted_matrix.csv and public_key.key to output_dir. The secret key is
+    // returned instead of written, so the web server can hand it to the user without ever storing it.
+    pub fn lattice_encrypt_csv_returning_secret_key(file_path: &str, output_dir: &str, progress: &dyn Progress) -> Result<SecretMatrix, Box<dyn std::error::Error>> {
+        // Call find_max_row_length to get the maximum row length, which is the size of the key
+        let max_row_length = find_max_row_length(file_path, progress)?;
 
-        // Checks if the encrypted matrix and keys have a value or are None. If None it will error
-        let (encrypted_matrix, secret_key, public_key) = match encryption_files {
-            Some(data) => data,
+        // Checks if the keys have a value or are None. If None it will error
+        let (encrypt_matrix, secret_key) = match generate_key_matrices(max_row_length, progress) {
+            Some(keys) => keys,
             None => {
+                check_cancelled(progress)?;
                 return Err("Failed to generate encrypted files. Contact Administrator".into());
             }
         };
 
+        write_encrypted_csv(file_path, output_dir, &encrypt_matrix, &secret_key, progress)?;
+        Ok(secret_key)
+    }
+
+    // The encrypt matrix of an existing secret key. It is a 0/1 matrix, so rounding removes the error of inverting twice.
+    fn encrypt_matrix_of(secret_key: &DMatrix<f64>, progress: &dyn Progress) -> Result<SecretMatrix, Box<dyn Error>> {
+        progress.report(Stage::KeyGeneration, 0, 1);
+        let encrypt_matrix = SecretMatrix::new(secret_key.clone()).try_inverse().ok_or("The secret key is not invertible")?;
+        progress.report(Stage::KeyGeneration, 1, 1);
+        Ok(encrypt_matrix.map(|value| value.round()))
+    }
+
+    // Encrypts a CSV file with an existing key, writing encrypted_matrix.csv and public_key.key to output_dir. Rows
+    // are padded to the key size, so the key must be at least as large as the widest row in bytes.
+    pub fn lattice_encrypt_csv_with_key(file_path: &str, output_dir: &str, secret_key: &DMatrix<f64>, progress: &dyn Progress) -> Result<(), Box<dyn Error>> {
+        let max_row_length = find_max_row_length(file_path, progress)?;
+        if max_row_length > secret_key.nrows() {
+            return Err(format!("The widest row is {} bytes but the key only fits {}", max_row_length, secret_key.nrows()).into());
+        }
+        write_encrypted_csv(file_path, output_dir, &*encrypt_matrix_of(secret_key, progress)?, secret_key, progress)
+    }
+
+    fn write_encrypted_csv(file_path: &str, output_dir: &str, encrypt_matrix: &DMatrix<f64>, secret_key: &DMatrix<f64>, progress: &dyn Progress) -> Result<(), Box<dyn Error>> {
+        let key_size = encrypt_matrix.nrows();
+        let file_size = std::fs::metadata(file_path)?.len();
         info!("Beginning file save");
+        let output_dir = Path::new(output_dir);
+
+        // Read the CSV a second time, encrypting and writing each row as it is read. The encrypted matrix is
+        // tagged with the key it was made with.
+        let mut file = BufWriter::new(File::create(output_dir.join("encrypted_matrix.csv"))?);
+        writeln!(file, "{} {}", KEY_FINGERPRINT_HEADER, key_fingerprint(secret_key))?;
+        writeln!(file, "\n  ┌ ┐")?;
+        let mut rdr = ReaderBuilder::new().has_headers(true).from_path(file_path)?;
+        let headers = rdr.headers()?.clone();
+        write_encrypted_row(&mut file, &utf8_array_pad_to_max_length(&headers, key_size)?, encrypt_matrix)?;
+        let mut record = csv::StringRecord::new();
+        let mut rows = 1;
+        while rdr.read_record(&mut record)? {
+            write_encrypted_row(&mut file, &utf8_array_pad_to_max_length(&record, key_size)?, encrypt_matrix)?;
+            rows += 1;
+            progress.report(Stage::Encrypting, rdr.position().byte(), file_size);
+            check_cancelled(progress)?;
+        }
+        lattice_metrics::observe_matrix("encrypt", rows, key_size);
+        writeln!(file, "  └ ┘\n")?;
+        progress.report(Stage::Writing, 0, 1);
+        file.flush()?;
 
-        // Save the encrypted matrix, secret key and public key to encrypted files
-        save_matrices_to_files(&encrypted_matrix, &secret_key, public_key)?;
+        // Save public key to file
+        write_public_key_file(&output_dir.join("public_key.key").to_string_lossy(), secret_key.norm())?;
+        progress.report(Stage::Writing, 1, 1);
         println!("Successfully processed and encrypted CSV file.");
         info!("Successfully processed and encrypted CSV file.");
 
         println!("Matrices and keys saved successfully.");
         info!("Matrices and keys saved successfully.");
 
-        // Return some success message or relevant data as Ok
-        Ok("Encryption and saving successful".to_string())
+        Ok(())
+    }
+
+    // Marker on the first line of a column-wise numeric encrypted matrix so decryption can tell the two layouts apart
+    pub const NUMERIC_MATRIX_MARKER: &str = "#lattice-columns";
+
+    // Computations mix the columns of a numeric matrix by multiplying from the left, so its key multiplies from the
+    // right and is as wide as the CSV has data rows. Making and inverting a key takes time cubic in its size, so the
+    // data rows are capped the same as the API caps key sizes.
+    pub const MAX_NUMERIC_ROWS: usize = 1024;
+
+    // Reads a CSV of numbers into a matrix with one row per CSV column so that linear operations on named
+    // columns become row operations on the encrypted matrix
+    fn numeric_columns_matrix(file_path: &str, progress: &dyn Progress) -> Result<(Vec<String>, SecretMatrix), Box<dyn Error>> {
+        let file_size = std::fs::metadata(file_path)?.len();
+        let mut rdr = ReaderBuilder::new().has_headers(true).from_path(file_path)?;
+        let headers: Vec<String> = rdr.headers()?.iter().map(|h| h.trim().to_string()).collect();
+
+        let mut records = Vec::new();
+        for (row_index, result) in rdr.records().enumerate() {
+            let record = result?;
+            if row_index == MAX_NUMERIC_ROWS {
+                return Err(format!("Numeric encryption supports at most {} data rows, as the key is one row and column per data row", MAX_NUMERIC_ROWS).into());
+            }
+            let mut values = Zeroizing::new(Vec::with_capacity(headers.len()));
+            for (column_index, cell) in record.iter().enumerate() {
+                let value = cell.trim().parse::<f64>().map_err(|_| {
+                    format!("Numeric encryption requires every cell to be a number: column '{}' row {} is '{}'",
+                        headers.get(column_index).map(String::as_str).unwrap_or("?"), row_index + 1, cell)
+                })?;
+                values.push(value);
+            }
+            if values.len() != headers.len() {
+                return Err(format!("Row {} has {} cells but the header has {}", row_index + 1, values.len(), headers.len()).into());
+            }
+            records.push(values);
+            progress.report(Stage::Parsing, record.position().map_or(0, |position| position.byte()), file_size);
+            check_cancelled(progress)?;
+        }
+
+        if records.is_empty() {
+            return Err("Numeric encryption requires at least one data row".into());
+        }
+
+        // Transpose so that row i of the matrix holds every value of CSV column i
+        let matrix = SecretMatrix::new(DMatrix::from_fn(headers.len(), records.len(), |i, j| records[j][i]));
+        lattice_metrics::observe_matrix("encrypt", matrix.nrows(), matrix.ncols());
+        Ok((headers, matrix))
+    }
+
+    // Writes a column-wise encrypted matrix as CSV lines of the form label,value,value,...
+    pub fn write_numeric_matrix(file_path: &str, labels: &[String], matrix: &DMatrix<f64>, fingerprint: Option<&str>) -> Result<(), Box<dyn Error>> {
+        let mut file = File::create(file_path)?;
+        writeln!(file, "{}", NUMERIC_MATRIX_MARKER)?;
+        if let Some(fingerprint) = fingerprint {
+            writeln!(file, "{} {}", KEY_FINGERPRINT_HEADER, fingerprint)?;
+        }
+        let mut wtr = csv::Writer::from_writer(file);
+        for (label, row) in labels.iter().zip(matrix.row_iter()) {
+            let mut record = vec![label.clone()];
+            record.extend(row.iter().map(|value| value.to_string()));
+            wtr.write_record(&record)?;
+        }
+        wtr.flush()?;
+        Ok(())
+    }
+
+    // Encrypts a CSV whose cells are all numbers column by column so it can be used with lattice_compute
+    pub fn lattice_encrypt_numeric_csv(file_path: &str, output_dir: &str, passphrase: Option<&str>, progress: &dyn Progress) -> Result<String, Box<dyn std::error::Error>> {
+        let secret_key = lattice_encrypt_numeric_csv_returning_secret_key(file_path, output_dir, progress)?;
+        write_secret_key(&Path::new(output_dir).join("secret_key.key").to_string_lossy(), &secret_key, passphrase)?;
+
+        Ok("Numeric encryption and saving successful".to_string())
+    }
+
+    // Numeric version of lattice_encrypt_csv_returning_secret_key
+    pub fn lattice_encrypt_numeric_csv_returning_secret_key(file_path: &str, output_dir: &str, progress: &dyn Progress) -> Result<SecretMatrix, Box<dyn std::error::Error>> {
+        let (labels, matrix) = numeric_columns_matrix(file_path, progress)?;
+
+        let (encrypted_matrix, secret_key, public_key) = match generate_encrypted_matrix(&matrix, progress) {
+            Some(data) => data,
+            None => {
+                check_cancelled(progress)?;
+                return Err("Failed to generate encrypted files. Contact Administrator".into());
+            }
+        };
+
+        info!("Beginning numeric file save");
+        let output_dir = Path::new(output_dir);
+        progress.report(Stage::Writing, 0, 1);
+        write_public_key_file(&output_dir.join("public_key.key").to_string_lossy(), public_key)?;
+        write_numeric_matrix(&output_dir.join("encrypted_matrix.csv").to_string_lossy(), &labels, &encrypted_matrix, Some(&key_fingerprint(&secret_key)))?;
+        progress.report(Stage::Writing, 1, 1);
+        info!("Successfully processed and encrypted numeric CSV file.");
+
+        Ok(secret_key)
+    }
+
+    // Numeric version of lattice_encrypt_csv_with_key. The key multiplies each column's values, so its size must
+    // equal the number of data rows.
+    pub fn lattice_encrypt_numeric_csv_with_key(file_path: &str, output_dir: &str, secret_key: &DMatrix<f64>, progress: &dyn Progress) -> Result<(), Box<dyn Error>> {
+        let (labels, matrix) = numeric_columns_matrix(file_path, progress)?;
+        if matrix.ncols() != secret_key.nrows() {
+            return Err(format!("Numeric encryption with a {}x{} key needs exactly {} data rows, the CSV has {}",
+                secret_key.nrows(), secret_key.ncols(), secret_key.nrows(), matrix.ncols()).into());
+        }
+        let encrypt_matrix = encrypt_matrix_of(secret_key, progress)?;
+        progress.report(Stage::Encrypting, 0, 1);
+        let encrypted_matrix = &*matrix * &*encrypt_matrix;
+        progress.report(Stage::Encrypting, 1, 1);
+
+        let output_dir = Path::new(output_dir);
+        progress.report(Stage::Writing, 0, 1);
+        write_public_key_file(&output_dir.join("public_key.key").to_string_lossy(), secret_key.norm())?;
+        write_numeric_matrix(&output_dir.join("encrypted_matrix.csv").to_string_lossy(), &labels, &encrypted_matrix, Some(&key_fingerprint(secret_key)))?;
+        progress.report(Stage::Writing, 1, 1);
+        info!("Successfully encrypted numeric CSV file with an existing key.");
+        Ok(())
     }
 
-}
\ No newline at end of file
+}
@@ -0,0 +1,109 @@
+pub mod lattice_health {
+    use std::error::Error;
+    use std::fs;
+    use std::io::{self, Write};
+    use std::path::{Path, PathBuf};
+    use nalgebra::DMatrix;
+    use crate::decrypted::lattice_decrypt::{lattice_decrypt_to_writer, process_file, read_key_fingerprint};
+    use crate::encrypted::lattice_encrypt::{key_fingerprint, lattice_encrypt_csv_returning_secret_key, lattice_encrypt_csv_with_key};
+    use crate::jobs::lattice_jobs::Job;
+    use crate::progress::lattice_progress::NoProgress;
+
+    // The self-test runs in a job of its own, owned by this name
+    const SELF_TEST_OWNER: &str = "self-test";
+
+    // Known answer: the CSV is padded to 4 bytes a row with '|' and multiplied by the inverse of the secret key,
+    // the 0/1 matrix with ones on and just above the diagonal
+    const SELF_TEST_CSV: &str = "a,b\n1,2\n";
+    const SELF_TEST_SECRET_KEY: [[f64; 4]; 4] = [
+        [1.0, -1.0, 1.0, -1.0],
+        [0.0, 1.0, -1.0, 1.0],
+        [0.0, 0.0, 1.0, -1.0],
+        [0.0, 0.0, 0.0, 1.0],
+    ];
+    const SELF_TEST_FINGERPRINT: &str = "f139fc1373e45e49397be5a610992074";
+    const SELF_TEST_ENCRYPTED: [[f64; 4]; 2] = [
+        [97.0, 141.0, 142.0, 222.0],
+        [49.0, 93.0, 94.0, 174.0],
+    ];
+
+    // Encrypts and decrypts a small CSV through the same functions the endpoints use, once with a fixed key whose
+    // encrypted matrix and fingerprint are known, and once with a newly generated key
+    pub fn self_test() -> Result<(), Box<dyn Error>> {
+        let job = Job::create(SELF_TEST_OWNER)?;
+        let result = run_self_test(&job);
+        job.remove();
+        result
+    }
+
+    fn run_self_test(job: &Job) -> Result<(), Box<dyn Error>> {
+        let input_path = job.input_path("self_test.csv");
+        let encrypted_path = job.output_path("encrypted_matrix.csv");
+        fs::write(&input_path, SELF_TEST_CSV)?;
+
+        let secret_key = DMatrix::from_fn(4, 4, |i, j| SELF_TEST_SECRET_KEY[i][j]);
+        if key_fingerprint(&secret_key) != SELF_TEST_FINGERPRINT {
+            return Err("The key fingerprint does not match the known answer".into());
+        }
+        lattice_encrypt_csv_with_key(&input_path, &job.output_dir_str(), &secret_key, &NoProgress)?;
+        if read_key_fingerprint(&encrypted_path)?.as_deref() != Some(SELF_TEST_FINGERPRINT) {
+            return Err("The encrypted matrix is not tagged with the key's fingerprint".into());
+        }
+        if process_file(&encrypted_path)? != DMatrix::from_fn(2, 4, |i, j| SELF_TEST_ENCRYPTED[i][j]) {
+            return Err("Encryption does not match the known answer".into());
+        }
+        check_round_trip(&encrypted_path, &secret_key)?;
+
+        let secret_key = lattice_encrypt_csv_returning_secret_key(&input_path, &job.output_dir_str(), &NoProgress)?;
+        check_round_trip(&encrypted_path, &secret_key)
+    }
+
+    fn check_round_trip(encrypted_path: &str, secret_key: &DMatrix<f64>) -> Result<(), Box<dyn Error>> {
+        let mut decrypted = Vec::new();
+        lattice_decrypt_to_writer(encrypted_path, secret_key, &mut decrypted, &NoProgress)?;
+        if decrypted != SELF_TEST_CSV.as_bytes() {
+            return Err("Decryption does not give back the encrypted CSV".into());
+        }
+        Ok(())
+    }
+
+    // Whether the server should be sent traffic. The self-test is run once at startup, the folders are checked on
+    // every call as a full or remounted disk can turn up at any time.
+    pub struct Readiness {
+        self_test: Result<(), String>,
+        dirs: Vec<PathBuf>,
+    }
+
+    impl Readiness {
+        pub fn new(self_test: Result<(), String>, dirs: Vec<PathBuf>) -> Readiness {
+            Readiness { self_test, dirs }
+        }
+
+        pub fn self_test_passed(&self) -> bool {
+            self.self_test.is_ok()
+        }
+
+        // What keeps the server from being ready, empty when it is
+        pub fn problems(&self) -> Vec<String> {
+            let mut problems = Vec::new();
+            if let Err(error) = &self.self_test {
+                problems.push(format!("The cipher failed its self-test at startup: {}", error));
+            }
+            for dir in &self.dirs {
+                if let Err(error) = check_writable(dir) {
+                    problems.push(format!("{} is not writable: {}", dir.display(), error));
+                }
+            }
+            problems
+        }
+    }
+
+    // Writes and removes a file, as creating a folder can still succeed on a disk with no space left
+    fn check_writable(dir: &Path) -> io::Result<()> {
+        let bytes: [u8; 8] = rand::random();
+        let path = dir.join(format!(".readyz-{}", bytes.iter().map(|b| format!("{:02x}", b)).collect::<String>()));
+        let written = fs::File::create(&path).and_then(|mut file| file.write_all(b"ready").and_then(|_| file.sync_all()));
+        let _ = fs::remove_file(&path);
+        written
+    }
+}
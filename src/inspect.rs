@@ -0,0 +1,85 @@
+pub mod lattice_inspect {
+    use std::error::Error;
+    use std::fs;
+    use std::path::Path;
+    use serde::Serialize;
+    use crate::encrypted::lattice_encrypt::{key_fingerprint, KEY_FINGERPRINT_HEADER, NUMERIC_MATRIX_MARKER};
+    use zeroize::Zeroizing;
+    use crate::decrypted::lattice_decrypt::{parse_headers, parse_matrix, parse_numeric_matrix};
+    use crate::keyfile::lattice_keyfile::{is_wrapped_key, parse_secret_key};
+    use crate::sharing::lattice_sharing::{parse_key_share, KEY_SHARE_MARKER};
+    use crate::rekey::lattice_rekey::REKEY_FROM_HEADER;
+
+    // What a file produced by this crate is, worked out from its headers and shape without needing any key
+    #[derive(Debug, Serialize)]
+    pub struct FileSummary {
+        pub kind: &'static str,
+        // How an encrypted matrix encodes its data, or how a wrapped key is protected
+        pub scheme: Option<&'static str>,
+        // Fingerprint of the key the file is or belongs to, when it can be known
+        pub fingerprint: Option<String>,
+        pub rows: Option<usize>,
+        pub columns: Option<usize>,
+        // The '#' header lines of the file, without the leading '#'
+        pub headers: Vec<String>,
+    }
+
+    // Describes an encrypted matrix, key, key share or re-key token. The passphrase is only used to fingerprint
+    // a wrapped secret key.
+    pub fn lattice_inspect_file<P: AsRef<Path>>(path: P, passphrase: Option<&str>) -> Result<FileSummary, Box<dyn Error>> {
+        let contents = Zeroizing::new(fs::read_to_string(path)?);
+        inspect_contents(&contents, passphrase)
+    }
+
+    // Describes the contents of a file that was never written to disk, such as one sent to the API
+    pub fn inspect_contents(contents: &str, passphrase: Option<&str>) -> Result<FileSummary, Box<dyn Error>> {
+        let headers = parse_headers(contents);
+        let header_value = |name: &str| headers.iter().find_map(|header| {
+            header.strip_prefix(name).filter(|rest| rest.starts_with(' ')).map(|value| value.trim().to_string())
+        });
+        let mut summary = FileSummary {
+            kind: "",
+            scheme: None,
+            fingerprint: header_value(KEY_FINGERPRINT_HEADER),
+            rows: None,
+            columns: None,
+            headers: headers.iter().map(|header| header.trim_start_matches('#').to_string()).collect(),
+        };
+
+        if headers.first().map(String::as_str) == Some(KEY_SHARE_MARKER) {
+            parse_key_share(contents, "The file")?;
+            summary.kind = "key_share";
+        } else if is_wrapped_key(contents) {
+            summary.kind = "wrapped_secret_key";
+            summary.scheme = Some("argon2id-xchacha20poly1305");
+            if passphrase.is_some() {
+                let secret_key = parse_secret_key(contents, passphrase)?;
+                summary.fingerprint = Some(key_fingerprint(&secret_key));
+                (summary.rows, summary.columns) = (Some(secret_key.nrows()), Some(secret_key.ncols()));
+            }
+        } else if headers.first().map(String::as_str) == Some(NUMERIC_MATRIX_MARKER) {
+            let (labels, matrix) = parse_numeric_matrix(contents.as_bytes())?;
+            summary.kind = "numeric_encrypted_matrix";
+            summary.scheme = Some("numeric-columns");
+            (summary.rows, summary.columns) = (Some(labels.len()), Some(matrix.ncols()));
+        } else if contents.trim().parse::<f64>().is_ok() {
+            // A public key is the single number norm of its secret key
+            summary.kind = "public_key";
+        } else {
+            let matrix = parse_matrix(contents.as_bytes())?;
+            (summary.rows, summary.columns) = (Some(matrix.nrows()), Some(matrix.ncols()));
+            summary.kind = if header_value(REKEY_FROM_HEADER).is_some() {
+                "rekey_token"
+            } else if summary.fingerprint.is_some() || !matrix.is_square() {
+                // Each row holds the UTF-8 bytes of one CSV row
+                summary.scheme = Some("utf8-rows");
+                "encrypted_matrix"
+            } else {
+                // Secret keys carry no header, so their fingerprint is computed
+                summary.fingerprint = Some(key_fingerprint(&matrix));
+                "secret_key"
+            };
+        }
+        Ok(summary)
+    }
+}
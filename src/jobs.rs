@@ -0,0 +1,223 @@
+pub mod lattice_jobs {
+    use std::fs;
+    use std::io;
+    use std::path::{Path, PathBuf};
+    use std::sync::OnceLock;
+    use std::time::UNIX_EPOCH;
+
+    // Every web request works in its own <jobs dir>/<id>/input and <jobs dir>/<id>/output folders so concurrent
+    // requests never see each other's files
+    pub const JOBS_DIR: &str = "./temp_jobs";
+
+    // Set once at startup from the storage.jobs_dir setting, JOBS_DIR until then
+    static JOBS_ROOT: OnceLock<PathBuf> = OnceLock::new();
+
+    // Chooses the jobs folder. Only the first call has an effect, so it must happen before any job is created.
+    pub fn set_jobs_dir(dir: &str) {
+        let _ = JOBS_ROOT.set(PathBuf::from(dir));
+    }
+
+    pub fn jobs_dir() -> &'static Path {
+        JOBS_ROOT.get_or_init(|| PathBuf::from(JOBS_DIR))
+    }
+
+    // Tests that create jobs keep them under the system's temporary folder, never in JOBS_DIR. Job ids are random,
+    // so test runs sharing the folder can't collide.
+    #[cfg(test)]
+    pub fn use_test_jobs_dir() {
+        set_jobs_dir(&std::env::temp_dir().join("lattice-test-jobs").to_string_lossy());
+    }
+
+    // Where multipart uploads are streamed before a handler moves them into its job
+    pub fn uploads_dir() -> PathBuf {
+        jobs_dir().join("uploads")
+    }
+
+    #[derive(Clone)]
+    pub struct Job {
+        pub id: String,
+        pub input_dir: PathBuf,
+        pub output_dir: PathBuf,
+    }
+
+    // Job ids are 128 random bits in hex, so they can't be guessed or collide
+    fn new_job_id() -> String {
+        let bytes: [u8; 16] = rand::random();
+        bytes.iter().map(|b| format!("{:02x}", b)).collect()
+    }
+
+    pub fn is_valid_job_id(id: &str) -> bool {
+        id.len() == 32 && id.bytes().all(|b| b.is_ascii_digit() || (b'a'..=b'f').contains(&b))
+    }
+
+    // Device names Windows reserves in every folder, with or without an extension
+    const RESERVED_NAMES: [&str; 22] = [
+        "CON", "PRN", "AUX", "NUL", "COM1", "COM2", "COM3", "COM4", "COM5", "COM6", "COM7", "COM8", "COM9",
+        "LPT1", "LPT2", "LPT3", "LPT4", "LPT5", "LPT6", "LPT7", "LPT8", "LPT9",
+    ];
+
+    // File names sent by clients are only kept for logs and messages, never used to build paths. This reduces one
+    // to its last path component, or None when what is left is empty, hidden, reserved or not printable.
+    pub fn sanitize_file_name(name: &str) -> Option<String> {
+        let name = name.rsplit(['/', '\\']).next().unwrap_or("").trim();
+        let stem = name.split('.').next().unwrap_or("").trim_end();
+        if name.is_empty()
+            || name.starts_with('.')
+            || name.ends_with('.')
+            || name.len() > 255
+            || name.chars().any(|c| c.is_control() || matches!(c, ':' | '*' | '?' | '"' | '<' | '>' | '|'))
+            || RESERVED_NAMES.iter().any(|reserved| stem.eq_ignore_ascii_case(reserved))
+        {
+            return None;
+        }
+        Some(name.to_string())
+    }
+
+    // Only plain file names may be used inside a job, never paths
+    fn is_valid_file_name(name: &str) -> bool {
+        sanitize_file_name(name).is_some_and(|sanitized| sanitized == name)
+    }
+
+    // File in a job's folder naming the user the job belongs to
+    const OWNER_FILE: &str = "owner";
+
+    impl Job {
+        // Creates a new job owned by a user, with empty input and output folders
+        pub fn create(owner: &str) -> io::Result<Job> {
+            let id = new_job_id();
+            let job_dir = jobs_dir().join(&id);
+            let job = Job { input_dir: job_dir.join("input"), output_dir: job_dir.join("output"), id };
+            fs::create_dir_all(&job.input_dir)?;
+            fs::create_dir_all(&job.output_dir)?;
+            fs::write(job_dir.join(OWNER_FILE), owner)?;
+            Ok(job)
+        }
+
+        // Opens an existing job of a user. Jobs of other users look the same as unknown or malformed ids, None, so
+        // their ids can't be probed.
+        pub fn open(id: &str, owner: &str) -> Option<Job> {
+            if !is_valid_job_id(id) {
+                return None;
+            }
+            let job_dir = jobs_dir().join(id);
+            if fs::read_to_string(job_dir.join(OWNER_FILE)).ok()? != owner {
+                return None;
+            }
+            Some(Job { input_dir: job_dir.join("input"), output_dir: job_dir.join("output"), id: id.to_string() })
+        }
+
+        // Deletes the job's folders, used when a request fails and leaves nothing worth downloading
+        pub fn remove(&self) {
+            if let Some(job_dir) = self.output_dir.parent() {
+                let _ = fs::remove_dir_all(job_dir);
+            }
+        }
+
+        // Inputs and outputs are stored under names chosen by the server. A name that is not a plain file name is a
+        // bug in the caller, so it panics rather than write outside the job.
+        pub fn input_path(&self, name: &str) -> String {
+            assert!(is_valid_file_name(name), "job input name {:?} is not a plain file name", name);
+            self.input_dir.join(name).to_string_lossy().to_string()
+        }
+
+        pub fn output_path(&self, name: &str) -> String {
+            assert!(is_valid_file_name(name), "job output name {:?} is not a plain file name", name);
+            self.output_dir.join(name).to_string_lossy().to_string()
+        }
+
+        pub fn output_dir_str(&self) -> String {
+            self.output_dir.to_string_lossy().to_string()
+        }
+
+        // Seconds since the Unix epoch at which the job was created. The job folder itself only changes when its
+        // input and output folders and owner file are made.
+        pub fn created(&self) -> io::Result<u64> {
+            let modified = fs::metadata(jobs_dir().join(&self.id))?.modified()?;
+            Ok(modified.duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0))
+        }
+
+        // Names and sizes of the job's output files, sorted by name
+        pub fn output_files(&self) -> io::Result<Vec<(String, u64)>> {
+            let mut files = Vec::new();
+            for entry in fs::read_dir(&self.output_dir)? {
+                let entry = entry?;
+                let metadata = entry.metadata()?;
+                if metadata.is_file() {
+                    files.push((entry.file_name().to_string_lossy().to_string(), metadata.len()));
+                }
+            }
+            files.sort();
+            Ok(files)
+        }
+
+        // Path of an existing output file of this job, or None when the name is not a plain file name
+        pub fn find_output(&self, name: &str) -> Option<PathBuf> {
+            if !is_valid_file_name(name) {
+                return None;
+            }
+            let path = self.output_dir.join(name);
+            path.is_file().then_some(path)
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        #[test]
+        fn sanitize_keeps_plain_names() {
+            assert_eq!(sanitize_file_name("grades.csv").as_deref(), Some("grades.csv"));
+            assert_eq!(sanitize_file_name("secret key (1).key").as_deref(), Some("secret key (1).key"));
+            assert_eq!(sanitize_file_name("console.csv").as_deref(), Some("console.csv"));
+        }
+
+        #[test]
+        fn sanitize_strips_traversal() {
+            assert_eq!(sanitize_file_name("../../etc/cron.d/x").as_deref(), Some("x"));
+            assert_eq!(sanitize_file_name("..\\..\\windows\\system32\\evil.dll").as_deref(), Some("evil.dll"));
+            assert_eq!(sanitize_file_name(".."), None);
+            assert_eq!(sanitize_file_name("uploads/.."), None);
+            assert_eq!(sanitize_file_name("a/b/"), None);
+        }
+
+        #[test]
+        fn sanitize_strips_absolute_paths() {
+            assert_eq!(sanitize_file_name("/etc/passwd").as_deref(), Some("passwd"));
+            assert_eq!(sanitize_file_name("C:\\Users\\me\\data.csv").as_deref(), Some("data.csv"));
+            assert_eq!(sanitize_file_name("C:data.csv"), None);
+            assert_eq!(sanitize_file_name("/"), None);
+        }
+
+        #[test]
+        fn sanitize_rejects_reserved_names() {
+            for name in ["CON", "nul", "Aux.csv", "com1.key", "LPT9.tar.gz", "con .csv"] {
+                assert_eq!(sanitize_file_name(name), None, "{}", name);
+            }
+            for name in ["", " ", ".hidden", "trailing.", "tab\tname", "a|b", "what?.csv"] {
+                assert_eq!(sanitize_file_name(name), None, "{:?}", name);
+            }
+        }
+
+        #[test]
+        fn job_paths_stay_inside_the_job() {
+            use_test_jobs_dir();
+            let job = Job::create("alice").unwrap();
+            assert!(job.input_dir.starts_with(std::env::temp_dir()));
+            assert!(job.input_path("input.csv").starts_with(&*job.input_dir.to_string_lossy()));
+            assert!(job.find_output("../input").is_none());
+            assert!(job.find_output("/etc/passwd").is_none());
+            assert!(job.find_output("..").is_none());
+            assert!(Job::open("../../etc", "alice").is_none());
+            assert!(Job::open(&job.id, "bob").is_none());
+            assert!(Job::open(&job.id, "alice").is_some());
+            job.remove();
+        }
+
+        #[test]
+        #[should_panic]
+        fn job_input_path_rejects_traversal() {
+            Job { id: new_job_id(), input_dir: PathBuf::from("input"), output_dir: PathBuf::from("output") }
+                .input_path("../../etc/cron.d/x");
+        }
+    }
+}
@@ -0,0 +1,348 @@
+pub mod lattice_kdf {
+    use std::error::Error;
+    use zeroize::Zeroizing;
+
+    // Argon2id (RFC 9106, version 0x13) over BLAKE2b (RFC 7693), used to stretch passphrases into wrapping keys and
+    // to hash login passwords. Lanes are filled one after another, which gives the same output as filling them in
+    // parallel since a lane only reads other lanes' blocks from slices that are already finished.
+
+    const BLAKE2B_IV: [u64; 8] = [
+        0x6a09e667f3bcc908, 0xbb67ae8584caa73b, 0x3c6ef372fe94f82b, 0xa54ff53a5f1d36f1,
+        0x510e527fade682d1, 0x9b05688c2b3e6c1f, 0x1f83d9abfb41bd6b, 0x5be0cd19137e2179,
+    ];
+
+    const BLAKE2B_SIGMA: [[usize; 16]; 12] = [
+        [0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15],
+        [14, 10, 4, 8, 9, 15, 13, 6, 1, 12, 0, 2, 11, 7, 5, 3],
+        [11, 8, 12, 0, 5, 2, 15, 13, 10, 14, 3, 6, 7, 1, 9, 4],
+        [7, 9, 3, 1, 13, 12, 11, 14, 2, 6, 5, 10, 4, 0, 15, 8],
+        [9, 0, 5, 7, 2, 4, 10, 15, 14, 1, 11, 12, 6, 8, 3, 13],
+        [2, 12, 6, 10, 0, 11, 8, 3, 4, 13, 7, 5, 15, 14, 1, 9],
+        [12, 5, 1, 15, 14, 13, 4, 10, 0, 7, 6, 3, 9, 2, 8, 11],
+        [13, 11, 7, 14, 12, 1, 3, 9, 5, 0, 15, 4, 8, 6, 2, 10],
+        [6, 15, 14, 9, 11, 3, 0, 8, 12, 2, 13, 7, 1, 4, 10, 5],
+        [10, 2, 8, 4, 7, 6, 1, 5, 15, 11, 9, 14, 3, 12, 13, 0],
+        [0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15],
+        [14, 10, 4, 8, 9, 15, 13, 6, 1, 12, 0, 2, 11, 7, 5, 3],
+    ];
+
+    // Unkeyed BLAKE2b with an output of 1 to 64 bytes
+    struct Blake2b {
+        h: [u64; 8],
+        counter: u128,
+        buffer: Zeroizing<[u8; 128]>,
+        buffered: usize,
+        output_len: usize,
+    }
+
+    impl Blake2b {
+        fn new(output_len: usize) -> Blake2b {
+            let mut h = BLAKE2B_IV;
+            h[0] ^= 0x01010000 ^ output_len as u64;
+            Blake2b { h, counter: 0, buffer: Zeroizing::new([0; 128]), buffered: 0, output_len }
+        }
+
+        fn compress(&mut self, last: bool) {
+            let mut m = Zeroizing::new([0u64; 16]);
+            for (word, bytes) in m.iter_mut().zip(self.buffer.chunks_exact(8)) {
+                *word = u64::from_le_bytes(bytes.try_into().unwrap());
+            }
+            let mut v = Zeroizing::new([0u64; 16]);
+            v[..8].copy_from_slice(&self.h);
+            v[8..].copy_from_slice(&BLAKE2B_IV);
+            v[12] ^= self.counter as u64;
+            v[13] ^= (self.counter >> 64) as u64;
+            if last {
+                v[14] = !v[14];
+            }
+            for sigma in &BLAKE2B_SIGMA {
+                let mut g = |a: usize, b: usize, c: usize, d: usize, x: u64, y: u64| {
+                    v[a] = v[a].wrapping_add(v[b]).wrapping_add(x);
+                    v[d] = (v[d] ^ v[a]).rotate_right(32);
+                    v[c] = v[c].wrapping_add(v[d]);
+                    v[b] = (v[b] ^ v[c]).rotate_right(24);
+                    v[a] = v[a].wrapping_add(v[b]).wrapping_add(y);
+                    v[d] = (v[d] ^ v[a]).rotate_right(16);
+                    v[c] = v[c].wrapping_add(v[d]);
+                    v[b] = (v[b] ^ v[c]).rotate_right(63);
+                };
+                g(0, 4, 8, 12, m[sigma[0]], m[sigma[1]]);
+                g(1, 5, 9, 13, m[sigma[2]], m[sigma[3]]);
+                g(2, 6, 10, 14, m[sigma[4]], m[sigma[5]]);
+                g(3, 7, 11, 15, m[sigma[6]], m[sigma[7]]);
+                g(0, 5, 10, 15, m[sigma[8]], m[sigma[9]]);
+                g(1, 6, 11, 12, m[sigma[10]], m[sigma[11]]);
+                g(2, 7, 8, 13, m[sigma[12]], m[sigma[13]]);
+                g(3, 4, 9, 14, m[sigma[14]], m[sigma[15]]);
+            }
+            for i in 0..8 {
+                self.h[i] ^= v[i] ^ v[i + 8];
+            }
+        }
+
+        fn update(&mut self, mut data: &[u8]) {
+            while !data.is_empty() {
+                // The last block is held back, it has to be compressed with the final flag
+                if self.buffered == 128 {
+                    self.counter += 128;
+                    self.compress(false);
+                    self.buffered = 0;
+                }
+                let take = data.len().min(128 - self.buffered);
+                self.buffer[self.buffered..self.buffered + take].copy_from_slice(&data[..take]);
+                self.buffered += take;
+                data = &data[take..];
+            }
+        }
+
+        fn finalize(mut self, output: &mut [u8]) {
+            self.counter += self.buffered as u128;
+            self.buffer[self.buffered..].fill(0);
+            self.compress(true);
+            let mut bytes = Zeroizing::new([0u8; 64]);
+            for (chunk, word) in bytes.chunks_exact_mut(8).zip(self.h.iter()) {
+                chunk.copy_from_slice(&word.to_le_bytes());
+            }
+            output.copy_from_slice(&bytes[..self.output_len]);
+            self.h = [0; 8];
+        }
+    }
+
+    fn blake2b(output: &mut [u8], inputs: &[&[u8]]) {
+        let mut hasher = Blake2b::new(output.len());
+        for input in inputs {
+            hasher.update(input);
+        }
+        hasher.finalize(output);
+    }
+
+    // The variable length hash H' of RFC 9106, section 3.3
+    fn blake2b_long(output: &mut [u8], inputs: &[&[u8]]) {
+        let length = (output.len() as u32).to_le_bytes();
+        let mut all = vec![&length[..]];
+        all.extend_from_slice(inputs);
+        if output.len() <= 64 {
+            blake2b(output, &all);
+            return;
+        }
+        let mut block = Zeroizing::new([0u8; 64]);
+        blake2b(&mut *block, &all);
+        let mut written = 0;
+        loop {
+            output[written..written + 32].copy_from_slice(&block[..32]);
+            written += 32;
+            if output.len() - written <= 64 {
+                break;
+            }
+            let previous = Zeroizing::new(*block);
+            blake2b(&mut *block, &[&*previous]);
+        }
+        blake2b(&mut output[written..], &[&*block]);
+    }
+
+    const BLOCK_WORDS: usize = 128;
+    const SYNC_POINTS: u32 = 4;
+    const ARGON2ID: u32 = 2;
+    const VERSION: u32 = 0x13;
+
+    type Block = [u64; BLOCK_WORDS];
+
+    fn blamka(x: u64, y: u64) -> u64 {
+        x.wrapping_add(y).wrapping_add(2u64.wrapping_mul(x & 0xffffffff).wrapping_mul(y & 0xffffffff))
+    }
+
+    // The BLAKE2b round of RFC 9106, section 3.6, with the additions replaced by blamka
+    fn permute(v: &mut Block, indices: [usize; 16]) {
+        let mut g = |a: usize, b: usize, c: usize, d: usize| {
+            let (a, b, c, d) = (indices[a], indices[b], indices[c], indices[d]);
+            v[a] = blamka(v[a], v[b]);
+            v[d] = (v[d] ^ v[a]).rotate_right(32);
+            v[c] = blamka(v[c], v[d]);
+            v[b] = (v[b] ^ v[c]).rotate_right(24);
+            v[a] = blamka(v[a], v[b]);
+            v[d] = (v[d] ^ v[a]).rotate_right(16);
+            v[c] = blamka(v[c], v[d]);
+            v[b] = (v[b] ^ v[c]).rotate_right(63);
+        };
+        g(0, 4, 8, 12);
+        g(1, 5, 9, 13);
+        g(2, 6, 10, 14);
+        g(3, 7, 11, 15);
+        g(0, 5, 10, 15);
+        g(1, 6, 11, 12);
+        g(2, 7, 8, 13);
+        g(3, 4, 9, 14);
+    }
+
+    // The compression function G, XORed into the block it overwrites when xor is set
+    fn fill_block(previous: &Block, reference: &Block, next: &mut Block, xor: bool) {
+        let mut r = Zeroizing::new([0u64; BLOCK_WORDS]);
+        for i in 0..BLOCK_WORDS {
+            r[i] = previous[i] ^ reference[i];
+        }
+        let mut result = *r;
+        if xor {
+            for i in 0..BLOCK_WORDS {
+                result[i] ^= next[i];
+            }
+        }
+        for row in 0..8 {
+            permute(&mut r, std::array::from_fn(|i| row * 16 + i));
+        }
+        for column in 0..8 {
+            permute(&mut r, std::array::from_fn(|i| (i / 2) * 16 + column * 2 + i % 2));
+        }
+        for i in 0..BLOCK_WORDS {
+            next[i] = result[i] ^ r[i];
+        }
+    }
+
+    fn block_bytes(block: &Block) -> Zeroizing<Vec<u8>> {
+        Zeroizing::new(block.iter().flat_map(|word| word.to_le_bytes()).collect())
+    }
+
+    fn block_from_bytes(bytes: &[u8]) -> Block {
+        std::array::from_fn(|i| u64::from_le_bytes(bytes[i * 8..i * 8 + 8].try_into().unwrap()))
+    }
+
+    // Derives output.len() bytes from a password and salt with Argon2id, memory_kib KiB of memory, passes passes
+    // over it and lanes lanes
+    pub fn argon2id(password: &[u8], salt: &[u8], memory_kib: u32, passes: u32, lanes: u32, output: &mut [u8]) -> Result<(), Box<dyn Error>> {
+        argon2id_with_secret(password, salt, &[], &[], memory_kib, passes, lanes, output)
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn argon2id_with_secret(password: &[u8], salt: &[u8], secret: &[u8], associated: &[u8], memory_kib: u32, passes: u32, lanes: u32, output: &mut [u8]) -> Result<(), Box<dyn Error>> {
+        if !(1..=0xffffff).contains(&lanes) || passes < 1 || memory_kib < 8 * lanes {
+            return Err(format!("Invalid key derivation parameters m={} t={} p={}", memory_kib, passes, lanes).into());
+        }
+        if salt.len() < 8 || output.len() < 4 {
+            return Err("Key derivation needs a salt of at least 8 bytes and an output of at least 4".into());
+        }
+
+        let mut h0 = Zeroizing::new([0u8; 72]);
+        let le = |value: usize| (value as u32).to_le_bytes();
+        blake2b(&mut h0[..64], &[
+            &lanes.to_le_bytes(), &le(output.len()), &memory_kib.to_le_bytes(), &passes.to_le_bytes(),
+            &VERSION.to_le_bytes(), &ARGON2ID.to_le_bytes(),
+            &le(password.len()), password, &le(salt.len()), salt,
+            &le(secret.len()), secret, &le(associated.len()), associated,
+        ]);
+
+        let segment_length = (memory_kib / (lanes * SYNC_POINTS)) as usize;
+        let lane_length = segment_length * SYNC_POINTS as usize;
+        let lanes = lanes as usize;
+        let mut memory: Zeroizing<Vec<Block>> = Zeroizing::new(vec![[0u64; BLOCK_WORDS]; lane_length * lanes]);
+
+        let mut bytes = Zeroizing::new([0u8; 1024]);
+        for lane in 0..lanes {
+            for column in 0..2 {
+                h0[64..68].copy_from_slice(&(column as u32).to_le_bytes());
+                h0[68..72].copy_from_slice(&(lane as u32).to_le_bytes());
+                blake2b_long(&mut *bytes, &[&*h0]);
+                memory[lane * lane_length + column] = block_from_bytes(&*bytes);
+            }
+        }
+
+        let zero = [0u64; BLOCK_WORDS];
+        for pass in 0..passes as usize {
+            for slice in 0..SYNC_POINTS as usize {
+                for lane in 0..lanes {
+                    // The first half of the first pass picks reference blocks independently of the password
+                    let independent = pass == 0 && slice < 2;
+                    let mut input = [0u64; BLOCK_WORDS];
+                    let mut addresses = Zeroizing::new([0u64; BLOCK_WORDS]);
+                    input[..6].copy_from_slice(&[pass as u64, lane as u64, slice as u64, memory.len() as u64, passes as u64, ARGON2ID as u64]);
+                    let mut next_addresses = |addresses: &mut Block| {
+                        input[6] += 1;
+                        let mut first = [0u64; BLOCK_WORDS];
+                        fill_block(&zero, &input, &mut first, false);
+                        fill_block(&zero, &first, addresses, false);
+                    };
+
+                    let start = if pass == 0 && slice == 0 { 2 } else { 0 };
+                    if independent && start == 2 {
+                        next_addresses(&mut addresses);
+                    }
+                    for index in start..segment_length {
+                        let current = lane * lane_length + slice * segment_length + index;
+                        let previous = if current.is_multiple_of(lane_length) { current + lane_length - 1 } else { current - 1 };
+                        let pseudo_random = if independent {
+                            if index % BLOCK_WORDS == 0 {
+                                next_addresses(&mut addresses);
+                            }
+                            addresses[index % BLOCK_WORDS]
+                        } else {
+                            memory[previous][0]
+                        };
+
+                        let reference_lane = if pass == 0 && slice == 0 { lane } else { ((pseudo_random >> 32) as usize) % lanes };
+                        let same_lane = reference_lane == lane;
+                        let area = if pass == 0 {
+                            if slice == 0 {
+                                index - 1
+                            } else if same_lane {
+                                slice * segment_length + index - 1
+                            } else {
+                                slice * segment_length - usize::from(index == 0)
+                            }
+                        } else if same_lane {
+                            lane_length - segment_length + index - 1
+                        } else {
+                            lane_length - segment_length - usize::from(index == 0)
+                        } as u64;
+                        let low = pseudo_random & 0xffffffff;
+                        let relative = area - 1 - ((area * ((low * low) >> 32)) >> 32);
+                        let start_position = if pass == 0 || slice == SYNC_POINTS as usize - 1 { 0 } else { (slice + 1) * segment_length };
+                        let reference = reference_lane * lane_length + (start_position + relative as usize) % lane_length;
+
+                        let (previous_block, reference_block) = (memory[previous], memory[reference]);
+                        fill_block(&previous_block, &reference_block, &mut memory[current], pass > 0);
+                    }
+                }
+            }
+        }
+
+        let mut last = memory[lane_length - 1];
+        for lane in 1..lanes {
+            for (word, other) in last.iter_mut().zip(memory[lane * lane_length + lane_length - 1].iter()) {
+                *word ^= other;
+            }
+        }
+        blake2b_long(output, &[&block_bytes(&last)]);
+        last.fill(0);
+        Ok(())
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        fn hex(bytes: &[u8]) -> String {
+            bytes.iter().map(|b| format!("{:02x}", b)).collect()
+        }
+
+        #[test]
+        fn blake2b_matches_rfc_7693() {
+            let mut output = [0u8; 64];
+            blake2b(&mut output, &[b"abc"]);
+            assert_eq!(hex(&output), "ba80a53f981c4d0d6a2797b69f12f6e94c212f14685ac4b74b12bb6fdbffa2d1\
+                7d87c5392aab792dc252d5de4533cc9518d38aa8dbf1925ab92386edd4009923");
+        }
+
+        #[test]
+        fn argon2id_matches_rfc_9106() {
+            let mut output = [0u8; 32];
+            argon2id_with_secret(&[1; 32], &[2; 16], &[3; 8], &[4; 12], 32, 3, 4, &mut output).unwrap();
+            assert_eq!(hex(&output), "0d640df58d78766c08c037a34a8b53c9d01ef0452d75b65eb52520e96b01e659");
+        }
+
+        #[test]
+        fn invalid_parameters_are_refused() {
+            let mut output = [0u8; 32];
+            assert!(argon2id(b"password", b"somesalt", 7, 1, 1, &mut output).is_err());
+            assert!(argon2id(b"password", b"somesalt", 64, 0, 1, &mut output).is_err());
+            assert!(argon2id(b"password", b"short", 64, 1, 1, &mut output).is_err());
+        }
+    }
+}
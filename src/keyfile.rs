@@ -0,0 +1,161 @@
+pub mod lattice_keyfile {
+    use std::error::Error;
+    use std::fs;
+    use std::path::Path;
+    use nalgebra::DMatrix;
+    use zeroize::Zeroizing;
+    use crate::aead::lattice_aead::{open, seal};
+    use crate::kdf::lattice_kdf::argon2id;
+    use crate::decrypted::lattice_decrypt::parse_secret_matrix;
+    use crate::secret::lattice_secret::{secret_text, SecretMatrix};
+
+    // First line of a passphrase protected secret key file. The rest of the file is:
+    //   #kdf argon2id m=<KiB> t=<passes> p=<lanes>
+    //   #salt <hex>
+    //   #nonce <hex>
+    //   <hex of the XChaCha20-Poly1305 sealed secret key, authenticated together with the header lines>
+    pub const WRAPPED_KEY_MARKER: &str = "#lattice-wrapped-key v1";
+
+    // Argon2id settings from the OWASP password storage recommendations
+    const KDF_MEMORY_KIB: u32 = 19456;
+    const KDF_PASSES: u32 = 2;
+    const KDF_LANES: u32 = 1;
+
+    pub fn to_hex(bytes: &[u8]) -> String {
+        bytes.iter().map(|b| format!("{:02x}", b)).collect()
+    }
+
+    pub fn from_hex(text: &str) -> Result<Vec<u8>, Box<dyn Error>> {
+        if !text.is_ascii() || !text.len().is_multiple_of(2) {
+            return Err("Invalid hex string".into());
+        }
+        (0..text.len()).step_by(2)
+            .map(|i| u8::from_str_radix(&text[i..i + 2], 16).map_err(|e| e.into()))
+            .collect()
+    }
+
+    // Stretches the passphrase into a 256 bit wrapping key
+    fn derive_key(passphrase: &str, salt: &[u8], memory: u32, passes: u32, lanes: u32) -> Result<Zeroizing<[u8; 32]>, Box<dyn Error>> {
+        let mut key = Zeroizing::new([0u8; 32]);
+        argon2id(passphrase.as_bytes(), salt, memory, passes, lanes, &mut *key)?;
+        Ok(key)
+    }
+
+    pub fn is_wrapped_key(contents: &str) -> bool {
+        contents.lines().next() == Some(WRAPPED_KEY_MARKER)
+    }
+
+    // Seals the text of a secret key under a passphrase
+    pub fn wrap_secret_key(key_text: &str, passphrase: &str) -> Result<String, Box<dyn Error>> {
+        let salt: [u8; 16] = rand::random();
+        let nonce: [u8; 24] = rand::random();
+        let header = format!("{}\n#kdf argon2id m={} t={} p={}\n#salt {}\n#nonce {}\n",
+            WRAPPED_KEY_MARKER, KDF_MEMORY_KIB, KDF_PASSES, KDF_LANES, to_hex(&salt), to_hex(&nonce));
+
+        let key = derive_key(passphrase, &salt, KDF_MEMORY_KIB, KDF_PASSES, KDF_LANES)?;
+        let sealed = seal(&key, &nonce, header.as_bytes(), key_text.as_bytes());
+
+        Ok(format!("{}{}\n", header, to_hex(&sealed)))
+    }
+
+    // Opens a wrapped secret key, failing on a wrong passphrase or any change to the file
+    pub fn unwrap_secret_key(wrapped: &str, passphrase: &str) -> Result<Zeroizing<String>, Box<dyn Error>> {
+        let mut header = String::new();
+        let (mut memory, mut passes, mut lanes) = (0, 0, 0);
+        let (mut salt, mut nonce, mut sealed) = (Vec::new(), Vec::new(), Vec::new());
+
+        for line in wrapped.lines() {
+            if line.starts_with('#') {
+                header.push_str(line);
+                header.push('\n');
+            }
+            let (name, value) = line.split_once(' ').unwrap_or((line, ""));
+            match name {
+                "#lattice-wrapped-key" => {}
+                "#kdf" => {
+                    let mut parts = value.split_whitespace();
+                    if parts.next() != Some("argon2id") {
+                        return Err(format!("Unsupported key derivation function: {}", value).into());
+                    }
+                    for part in parts {
+                        match part.split_once('=') {
+                            Some(("m", v)) => memory = v.parse()?,
+                            Some(("t", v)) => passes = v.parse()?,
+                            Some(("p", v)) => lanes = v.parse()?,
+                            _ => return Err(format!("Unknown key derivation parameter: {}", part).into()),
+                        }
+                    }
+                }
+                "#salt" => salt = from_hex(value)?,
+                "#nonce" => nonce = from_hex(value)?,
+                _ if !line.is_empty() && !line.starts_with('#') => sealed = from_hex(line.trim())?,
+                _ => {}
+            }
+        }
+
+        let nonce: [u8; 24] = match nonce.try_into() {
+            Ok(nonce) if !salt.is_empty() && !sealed.is_empty() => nonce,
+            _ => return Err("Wrapped secret key file is incomplete".into()),
+        };
+        // The header is only authenticated after the key is derived, so its cost settings can't be trusted. Every
+        // file is written with the settings above, anything else would let a crafted file tie up memory and CPU.
+        if (memory, passes, lanes) != (KDF_MEMORY_KIB, KDF_PASSES, KDF_LANES) {
+            return Err(format!("Unsupported key derivation parameters m={} t={} p={}", memory, passes, lanes).into());
+        }
+
+        let key = derive_key(passphrase, &salt, memory, passes, lanes)?;
+        let opened = open(&key, &nonce, header.as_bytes(), &sealed).ok_or("Wrong passphrase or the secret key file has been modified")?;
+
+        Ok(Zeroizing::new(std::str::from_utf8(&opened)?.to_string()))
+    }
+
+    // Reads a secret key file, unwrapping it first when it is passphrase protected
+    pub fn read_secret_key<P: AsRef<Path>>(path: P, passphrase: Option<&str>) -> Result<SecretMatrix, Box<dyn Error>> {
+        let contents = Zeroizing::new(fs::read_to_string(path)?);
+        parse_secret_key(&contents, passphrase)
+    }
+
+    // Parses the contents of a secret key file that was never written to disk, such as one sent to the API
+    pub fn parse_secret_key(contents: &str, passphrase: Option<&str>) -> Result<SecretMatrix, Box<dyn Error>> {
+        if !is_wrapped_key(contents) {
+            return parse_secret_matrix(contents);
+        }
+        match passphrase {
+            Some(passphrase) => parse_secret_matrix(&unwrap_secret_key(contents, passphrase)?),
+            None => Err("Secret key file is passphrase protected; a passphrase is required".into()),
+        }
+    }
+
+    // Writes a secret key file, wrapped under the passphrase when one is given
+    pub fn write_secret_key(file_path: &str, secret_key: &DMatrix<f64>, passphrase: Option<&str>) -> Result<(), Box<dyn Error>> {
+        let key_text = secret_text(secret_key);
+        match passphrase {
+            Some(passphrase) => fs::write(file_path, wrap_secret_key(&key_text, passphrase)?)?,
+            None => fs::write(file_path, key_text.as_bytes())?,
+        }
+        Ok(())
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        #[test]
+        fn wrapped_key_round_trips() {
+            let wrapped = wrap_secret_key("1,0\n0,1\n", "correct horse").unwrap();
+            assert_eq!(unwrap_secret_key(&wrapped, "correct horse").unwrap().as_str(), "1,0\n0,1\n");
+            assert!(unwrap_secret_key(&wrapped, "wrong horse").is_err());
+        }
+
+        #[test]
+        fn oversized_kdf_header_is_rejected() {
+            let wrapped = wrap_secret_key("1,0\n0,1\n", "correct horse").unwrap();
+            let expected = format!("#kdf argon2id m={} t={} p={}", KDF_MEMORY_KIB, KDF_PASSES, KDF_LANES);
+            for header in ["#kdf argon2id m=4194304 t=2 p=1", "#kdf argon2id m=19456 t=4000 p=1", "#kdf argon2id m=19456 t=2 p=64"] {
+                let crafted = wrapped.replace(&expected, header);
+                let error = unwrap_secret_key(&crafted, "correct horse").unwrap_err();
+                assert!(error.to_string().starts_with("Unsupported key derivation parameters"), "{}", error);
+            }
+        }
+    }
+}
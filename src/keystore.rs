@@ -0,0 +1,228 @@
+pub mod lattice_keystore {
+    use std::error::Error;
+    use std::fs;
+    use std::io::Write;
+    use std::path::PathBuf;
+    use std::time::{SystemTime, UNIX_EPOCH};
+    use nalgebra::DMatrix;
+    use serde::Serialize;
+    use zeroize::Zeroizing;
+    use crate::aead::lattice_aead::{open, seal};
+    use crate::decrypted::lattice_decrypt::parse_secret_matrix;
+    use crate::encrypted::lattice_encrypt::{key_fingerprint, KEY_FINGERPRINT_HEADER};
+    use crate::keyfile::lattice_keyfile::{from_hex, to_hex};
+    use crate::secret::lattice_secret::{secret_text, SecretMatrix};
+
+    // First line of a key held in the keystore, stored as <keystore dir>/<key id>.key. The rest of the file is:
+    //   #key-fingerprint <key id>
+    //   #owner <user>
+    //   #users <user>,<user>     other users allowed to encrypt and decrypt with the key, possibly empty
+    //   #created <unix seconds>
+    //   #nonce <hex>
+    //   <hex of the XChaCha20-Poly1305 sealed secret key, authenticated together with the header lines>
+    // Sealing the header means a key can't be handed to another user, or renamed, by editing the file.
+    pub const STORED_KEY_MARKER: &str = "#lattice-stored-key v1";
+
+    // A key's id is its fingerprint, which encrypted matrices already carry, so decryption can find the key
+    // without being told its id
+    #[derive(Serialize)]
+    pub struct StoredKey {
+        pub key_id: String,
+        pub owner: String,
+        pub users: Vec<String>,
+        pub size: usize,
+        pub created: u64,
+    }
+
+    // A stored key's details together with the opened secret key
+    pub type KeyEntry = (StoredKey, SecretMatrix);
+
+    impl StoredKey {
+        pub fn can_use(&self, user: &str) -> bool {
+            self.owner == user || self.users.iter().any(|allowed| allowed == user)
+        }
+
+        fn header(&self, nonce: &[u8]) -> String {
+            format!("{}\n{} {}\n#owner {}\n#users {}\n#created {}\n#nonce {}\n",
+                STORED_KEY_MARKER, KEY_FINGERPRINT_HEADER, self.key_id, self.owner, self.users.join(","), self.created, to_hex(nonce))
+        }
+    }
+
+    fn is_valid_key_id(key_id: &str) -> bool {
+        key_id.len() == 32 && key_id.bytes().all(|b| b.is_ascii_digit() || (b'a'..=b'f').contains(&b))
+    }
+
+    // Writes a new random master key. Losing it loses every stored key, so an existing file is never replaced.
+    pub fn lattice_create_master_key(path: &str) -> Result<String, Box<dyn Error>> {
+        let master_key = Zeroizing::new(rand::random::<[u8; 32]>());
+        let mut options = fs::OpenOptions::new();
+        options.write(true).create_new(true);
+        #[cfg(unix)]
+        std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+        let mut file = options.open(path).map_err(|error| format!("Cannot create master key file {}: {}", path, error))?;
+        writeln!(file, "{}", *Zeroizing::new(to_hex(&*master_key)))?;
+        Ok(format!("Created master key {}. Back it up, stored keys can't be recovered without it.", path))
+    }
+
+    pub struct Keystore {
+        dir: PathBuf,
+        master_key: Zeroizing<[u8; 32]>,
+    }
+
+    impl Keystore {
+        pub fn open(dir: &str, master_key_file: &str) -> Result<Keystore, Box<dyn Error>> {
+            let contents = Zeroizing::new(fs::read_to_string(master_key_file).map_err(|error| format!("Cannot read master key file {}: {}", master_key_file, error))?);
+            let bytes = Zeroizing::new(from_hex(contents.trim()).unwrap_or_default());
+            let mut master_key = Zeroizing::new([0u8; 32]);
+            if bytes.len() != master_key.len() {
+                return Err(format!("Master key file {} must hold 32 bytes in hex", master_key_file).into());
+            }
+            master_key.copy_from_slice(&bytes);
+            fs::create_dir_all(dir)?;
+            Ok(Keystore { dir: PathBuf::from(dir), master_key })
+        }
+
+        fn key_path(&self, key_id: &str) -> PathBuf {
+            self.dir.join(format!("{}.key", key_id))
+        }
+
+        fn write(&self, key: &StoredKey, secret_key: &DMatrix<f64>) -> Result<(), Box<dyn Error>> {
+            let nonce: [u8; 24] = rand::random();
+            let header = key.header(&nonce);
+            let sealed = seal(&self.master_key, &nonce, header.as_bytes(), secret_text(secret_key).as_bytes());
+
+            // Written next to the key and renamed over it, so a key file is never left half written
+            let path = self.key_path(&key.key_id);
+            let temp_path = path.with_extension("tmp");
+            let mut options = fs::OpenOptions::new();
+            options.write(true).create(true).truncate(true);
+            #[cfg(unix)]
+            std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+            let mut file = options.open(&temp_path)?;
+            writeln!(file, "{}{}", header, to_hex(&sealed))?;
+            file.sync_all()?;
+            fs::rename(&temp_path, &path)?;
+            Ok(())
+        }
+
+        // Reads and opens a stored key. Fails when the file was modified or sealed under another master key.
+        fn read(&self, key_id: &str) -> Result<Option<KeyEntry>, Box<dyn Error>> {
+            if !is_valid_key_id(key_id) {
+                return Ok(None);
+            }
+            let contents = match fs::read_to_string(self.key_path(key_id)) {
+                Ok(contents) => contents,
+                Err(error) if error.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+                Err(error) => return Err(error.into()),
+            };
+            let mut lines = contents.lines();
+            if lines.next() != Some(STORED_KEY_MARKER) {
+                return Err(format!("Stored key {} is not a keystore file", key_id).into());
+            }
+            let mut fields = std::collections::HashMap::new();
+            let mut sealed = "";
+            for line in lines {
+                match line.strip_prefix('#').and_then(|field| field.split_once(' ')) {
+                    Some((name, value)) => {
+                        fields.insert(name, value);
+                    }
+                    None => sealed = line.trim(),
+                }
+            }
+            let field = |name: &str| fields.get(name).copied().ok_or(format!("Stored key {} has no {} line", key_id, name));
+            let nonce: [u8; 24] = from_hex(field("nonce")?)?.try_into().map_err(|_| format!("Stored key {} has been modified", key_id))?;
+            let mut key = StoredKey {
+                key_id: field(&KEY_FINGERPRINT_HEADER[1..])?.to_string(),
+                owner: field("owner")?.to_string(),
+                users: field("users")?.split(',').filter(|user| !user.is_empty()).map(str::to_string).collect(),
+                size: 0,
+                created: field("created")?.parse()?,
+            };
+            if key.key_id != key_id {
+                return Err(format!("Stored key {} has been modified", key_id).into());
+            }
+
+            let opened = open(&self.master_key, &nonce, key.header(&nonce).as_bytes(), &from_hex(sealed)?)
+                .ok_or_else(|| format!("Stored key {} has been modified or was sealed under another master key", key_id))?;
+            let secret_key = parse_secret_matrix(std::str::from_utf8(&opened)?)?;
+            key.size = secret_key.nrows();
+            Ok(Some((key, secret_key)))
+        }
+
+        // Stores a secret key owned by a user and returns its id
+        pub fn store(&self, owner: &str, secret_key: &DMatrix<f64>) -> Result<StoredKey, Box<dyn Error>> {
+            let key = StoredKey {
+                key_id: key_fingerprint(secret_key),
+                owner: owner.to_string(),
+                users: Vec::new(),
+                size: secret_key.nrows(),
+                created: SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0),
+            };
+            if self.key_path(&key.key_id).exists() {
+                return Err(format!("Key {} is already stored", key.key_id).into());
+            }
+            self.write(&key, secret_key)?;
+            Ok(key)
+        }
+
+        // A key the user may use. Keys of other users look the same as unknown ids, None.
+        pub fn load(&self, key_id: &str, user: &str) -> Result<Option<KeyEntry>, Box<dyn Error>> {
+            Ok(self.read(key_id)?.filter(|(key, _)| key.can_use(user)))
+        }
+
+        // Keys the user owns or was given, sorted by creation time
+        pub fn list(&self, user: &str) -> Result<Vec<StoredKey>, Box<dyn Error>> {
+            let mut keys = Vec::new();
+            for entry in fs::read_dir(&self.dir)? {
+                let path = entry?.path();
+                if path.extension().is_some_and(|extension| extension == "key") {
+                    let key_id = path.file_stem().unwrap_or_default().to_string_lossy().to_string();
+                    match self.load(&key_id, user) {
+                        Ok(Some((key, _))) => keys.push(key),
+                        Ok(None) => {}
+                        Err(error) => eprintln!("Skipping stored key {}: {}", key_id, error),
+                    }
+                }
+            }
+            keys.sort_by_key(|key| key.created);
+            Ok(keys)
+        }
+
+        // Only the owner manages a key, others get None as if it did not exist
+        fn load_owned(&self, key_id: &str, owner: &str) -> Result<Option<KeyEntry>, Box<dyn Error>> {
+            Ok(self.read(key_id)?.filter(|(key, _)| key.owner == owner))
+        }
+
+        // Lets another user encrypt and decrypt with a key
+        pub fn grant(&self, key_id: &str, owner: &str, user: &str) -> Result<Option<StoredKey>, Box<dyn Error>> {
+            let Some((mut key, secret_key)) = self.load_owned(key_id, owner)? else {
+                return Ok(None);
+            };
+            if user != key.owner && !key.users.iter().any(|allowed| allowed == user) {
+                key.users.push(user.to_string());
+                self.write(&key, &secret_key)?;
+            }
+            Ok(Some(key))
+        }
+
+        pub fn revoke(&self, key_id: &str, owner: &str, user: &str) -> Result<Option<StoredKey>, Box<dyn Error>> {
+            let Some((mut key, secret_key)) = self.load_owned(key_id, owner)? else {
+                return Ok(None);
+            };
+            if key.users.iter().any(|allowed| allowed == user) {
+                key.users.retain(|allowed| allowed != user);
+                self.write(&key, &secret_key)?;
+            }
+            Ok(Some(key))
+        }
+
+        // Deletes a key. Anything still encrypted under it can then only be decrypted with a copy of the secret key.
+        pub fn delete(&self, key_id: &str, owner: &str) -> Result<bool, Box<dyn Error>> {
+            if self.load_owned(key_id, owner)?.is_none() {
+                return Ok(false);
+            }
+            fs::remove_file(self.key_path(key_id))?;
+            Ok(true)
+        }
+    }
+}
@@ -1,19 +1,65 @@
 pub mod encrypted;
 pub mod decrypted;
 pub mod verification;
+pub mod compute;
+pub mod rekey;
+pub mod rotation;
+pub mod cli;
+pub mod keyfile;
+pub mod sharing;
+pub mod jobs;
+pub mod archive;
+pub mod inspect;
+pub mod api;
+pub mod config;
+pub mod auth;
+pub mod keystore;
+pub mod queue;
+pub mod progress;
+pub mod retention;
+pub mod secret;
+pub mod audit;
+pub mod metrics;
+pub mod health;
+pub mod tls;
+pub mod ratelimit;
+pub mod kdf;
+pub mod aead;
 
 use actix_multipart::form::tempfile::{TempFile, TempFileConfig};
-use actix_multipart::form::MultipartForm;
-use actix_web::{post, web, App, HttpRequest, HttpResponse, HttpServer, Responder, Result,Error};
-use actix_files as afs;
-use std::{fs, io::prelude::*};
+use actix_multipart::form::text::Text;
+use actix_multipart::form::{MultipartForm, MultipartFormConfig};
+use actix_multipart::MultipartError;
+use actix_web::{get, post, web, App, HttpRequest, HttpResponse, HttpServer, Result};
+use std::io::prelude::*;
 use std::fs::File;
 use std::fs::remove_file;
-use std::process::Command;
 use log::{info};
-use encrypted::lattice_encrypt::lattice_encrypt_csv;
-use decrypted::lattice_decrypt::lattice_decrypt_csv;
-use verification::lattice_verification::lattice_public_secret_verification;
+use encrypted::lattice_encrypt::{lattice_encrypt_csv_returning_secret_key, lattice_encrypt_csv_with_key, lattice_encrypt_numeric_csv_returning_secret_key, lattice_encrypt_numeric_csv_with_key};
+use keyfile::lattice_keyfile::{parse_secret_key, wrap_secret_key};
+use decrypted::lattice_decrypt::{lattice_decrypt_to_writer, read_header_value, read_key_fingerprint};
+use sharing::lattice_sharing::{combine_key_shares, parse_key_share};
+use secret::lattice_secret::SecretMatrix;
+use zeroize::Zeroizing;
+use audit::lattice_audit::{AuditEvent, AuditLog, Operation};
+use encrypted::lattice_encrypt::key_fingerprint;
+use compute::lattice_compute::lattice_compute_csv;
+use rekey::lattice_rekey::{lattice_rekey_csv, lattice_rekey_token_returning_secret_key, REKEY_FROM_HEADER};
+use jobs::lattice_jobs::{jobs_dir, sanitize_file_name, set_jobs_dir, uploads_dir, Job};
+use config::lattice_config::{Config, EndpointsConfig, LimitsConfig};
+use auth::lattice_auth::{Auth, User, SESSION_COOKIE};
+use keystore::lattice_keystore::Keystore;
+use queue::lattice_queue::{JobQueue, JobState, QueueError, TaskContext};
+use progress::lattice_progress::Progress;
+use retention::lattice_retention::{secure_delete, start_sweeper, SecureDeleteOnDrop, PLAINTEXT_OUTPUT};
+use archive::lattice_archive::{chunk_stream, read_chunks, tar_stream, ArchiveEntry};
+use actix_web::http::header::{ContentDisposition, ContentType};
+use actix_web::cookie::{Cookie, SameSite};
+use actix_web::dev::Service;
+use metrics::lattice_metrics;
+use health::lattice_health::{self_test, Readiness};
+use tls::lattice_tls::{server_config, Certificates};
+use ratelimit::lattice_ratelimit::{client_of, Endpoint, RateLimiter, Refused};
 
 
 fn read_html_file(file_path: &str) -> Result<String, std::io::Error> {
@@ -24,98 +70,707 @@ fn read_html_file(file_path: &str) -> Result<String, std::io::Error> {
 }
 
 
-async fn index(req: HttpRequest) -> Result<HttpResponse> {
-    let html_content = read_html_file("/Users/gurudeepmachupalli/Documents/Repositories/LATTICE/hello_world/src/frontend.html")?;
-    Ok(HttpResponse::Ok().content_type("text/html").body(html_content))
+// The frontend is built into the binary so the server works from any directory
+const FRONTEND_HTML: &str = include_str!("frontend.html");
+
+// Set with --static-dir <dir> or storage.static_dir during development to serve <dir>/frontend.html, re-read on
+// every request
+struct StaticDir(Option<String>);
+
+async fn index(_req: HttpRequest, static_dir: web::Data<StaticDir>) -> Result<HttpResponse> {
+    let html_content = match &static_dir.0 {
+        Some(dir) => read_html_file(&std::path::Path::new(dir).join("frontend.html").to_string_lossy())?,
+        None => FRONTEND_HTML.to_string(),
+    };
+    Ok(HttpResponse::Ok().content_type("text/html; charset=utf-8").body(html_content))
+}
+
+// Host names a --self-signed certificate is made for: the local addresses and the host the server is bound to
+fn self_signed_names(bind: &str) -> Vec<String> {
+    let mut names = vec!["localhost".to_string(), "127.0.0.1".to_string(), "::1".to_string()];
+    let host = bind.rsplit_once(':').map_or(bind, |(host, _)| host).trim_start_matches('[').trim_end_matches(']');
+    if !host.is_empty() && !names.iter().any(|name| name == host) {
+        names.push(host.to_string());
+    }
+    names
+}
+
+// The value following a server flag such as --static-dir
+fn flag_value(args: &[String], flag: &str) -> Option<String> {
+    let position = args.iter().position(|arg| arg == flag)?;
+    args.get(position + 1).filter(|value| !value.starts_with("--")).cloned()
 }
 
 // 
-async fn encrypt_file(file_path: &str) -> Result<String, Box<dyn std::error::Error>> {
+// Encrypts into output_dir and returns a new secret key rather than writing it, so it only ever leaves in the response
+fn encrypt_file(file_path: &str, output_dir: &str, numeric: bool, stored_key: Option<&SecretMatrix>, audit: &AuditLog, user: &str, progress: &dyn Progress) -> Result<Option<SecretMatrix>, Box<dyn std::error::Error>> {
     info!("File path to be encrypted: {}", file_path);
+    let mut event = AuditEvent::start(user, Operation::Encrypt);
+    event.input_file(file_path);
+    event.key(stored_key.map(|key| key_fingerprint(key)));
     
-    let output = lattice_encrypt_csv(file_path);
-
-    info!("Output of Lattice Encryption: {:?}", output);
+    // A stored key is used as is, otherwise a new key is made to fit the file
+    let output = match stored_key {
+        Some(key) if numeric => lattice_encrypt_numeric_csv_with_key(file_path, output_dir, key, progress).map(|_| None),
+        Some(key) => lattice_encrypt_csv_with_key(file_path, output_dir, key, progress).map(|_| None),
+        None if numeric => lattice_encrypt_numeric_csv_returning_secret_key(file_path, output_dir, progress).map(Some),
+        None => lattice_encrypt_csv_returning_secret_key(file_path, output_dir, progress).map(Some),
+    };
+    if let Ok(Some(secret_key)) = &output {
+        event.key(Some(key_fingerprint(secret_key)));
+    }
+    audit.record(event, &output);
 
     match output {
         Ok(result) => {
-            info!("Lattice Encryption has succeeded: {:?}", result);
+            info!("Lattice Encryption has succeeded");
             Ok(result)
         },
         Err(error) => {
             info!("Lattice Encryption has failed: {:?}", error);
-            Err(error.into())
+            Err(error)
         },
     }
 
 }
 
 #[post("/encrypt")]
-async fn encrypt_handler(MultipartForm(upload_encrypt_form): MultipartForm<UploadEncryptForm>) -> Result<HttpResponse> {
+async fn encrypt_handler(user: User, query: web::Query<BackgroundQuery>, MultipartForm(upload_encrypt_form): MultipartForm<UploadEncryptForm>, limits: web::Data<LimitsConfig>, keystore: Option<web::Data<Keystore>>, queue: web::Data<JobQueue>, audit: web::Data<AuditLog>) -> Result<HttpResponse> {
+    if let Some(response) = oversized_file(&limits, &[&upload_encrypt_form.encrypt_file]) {
+        return Ok(response);
+    }
+
+    // With a stored key the secret key never leaves the server, so none is sent back
+    let key_id = upload_encrypt_form.key_id.as_ref().map(|text| text.trim()).filter(|key_id| !key_id.is_empty());
+    let stored_key = match key_id.map(|key_id| stored_key(keystore.as_ref().map(|keystore| keystore.get_ref()), key_id, &user)).transpose() {
+        Ok(stored_key) => stored_key,
+        Err(response) => return Ok(response),
+    };
 
-    // Pulls the name of the file from the Form that's passed in from the UI
-    let filename = match upload_encrypt_form.encrypt_file.file_name {
-        Some(name) => name.to_string(),
-        None => return Ok(HttpResponse::BadRequest().body("Bad Request no CSV file is provided.")),
+    // The client's file name only tells whether a file was sent; it is never used as a path
+    if upload_encrypt_form.encrypt_file.file_name.is_none() {
+        return Ok(HttpResponse::BadRequest().body("Bad Request no CSV file is provided."));
+    }
+
+    // A new secret key the user asks to keep is sealed in the keystore under its master key, never left in the job
+    let keep_secret_key = upload_encrypt_form.keep_secret_key.is_some_and(|value| value.0 == "on" || value.0 == "true");
+    let key_keystore = match (keep_secret_key && stored_key.is_none(), &keystore) {
+        (false, _) => None,
+        (true, Some(keystore)) => Some(keystore.clone()),
+        (true, None) => return Ok(HttpResponse::BadRequest().body("The keystore is not enabled on this server")),
     };
-    let path = format!("./temp_encrypted_input/{}", filename);
+    // Nothing would hand a new secret key back from a background job
+    if query.background && stored_key.is_none() && key_keystore.is_none() {
+        return Ok(HttpResponse::BadRequest().body("A new secret key is only sent back to a request that waits for it. Encrypt without async, or keep the secret key in the keystore"));
+    }
+
+    // Every request gets its own job folders so concurrent uploads can't overwrite each other
+    let job = match create_job(&user) {
+        Ok(job) => job,
+        Err(response) => return Ok(response),
+    };
+    let path = job.input_path("input.csv");
     
-    // Will reate the CSV file to be encrypted in the temp_encrypted_input folder
+    // Will create the CSV file to be encrypted in the job's input folder
+    let original_name = upload_name(&upload_encrypt_form.encrypt_file);
     if let Err(error) = upload_encrypt_form.encrypt_file.file.persist(&path) {
         eprintln!("Failed to save CSV file: {}", error);
+        job.remove();
         return Ok(HttpResponse::InternalServerError().body("Failed to save CSV file"));
     }
-    info!("Saving CSV file to Temp Path: {}", path);
+    info!("Saved uploaded CSV file {} to Temp Path: {}", original_name, path);
+
+    // Numeric mode encrypts column by column so the result can be used with /compute
+    let numeric = upload_encrypt_form.numeric.is_some_and(|value| value.0 == "on" || value.0 == "true");
+
+    // Trigger the encrypt_file function on the job queue, and safely remove the file once it has run
+    let passphrase = form_passphrase(&upload_encrypt_form.passphrase);
+    if query.background {
+        let (output_dir, owner) = (job.output_dir_str(), user.name.clone());
+        let submitted = queue.submit(&job, &user.name, move |context| {
+            let secret_key = encrypt_file(&path, &output_dir, numeric, stored_key.as_ref(), &audit, &owner, context);
+            let _ = remove_file(&path);
+            if let (Some(secret_key), Some(keystore)) = (secret_key?, &key_keystore) {
+                keystore.store(&owner, &secret_key)?;
+            }
+            Ok::<_, Box<dyn std::error::Error>>(())
+        });
+        return Ok(queued_response(&job, submitted));
+    }
+    let (output_dir, has_stored_key, owner) = (job.output_dir_str(), stored_key.is_some(), user.name.clone());
+    let encryption_result = queue.run(&job, &user.name, move |context| {
+        let result = encrypt_file(&path, &output_dir, numeric, stored_key.as_ref(), &audit, &owner, context).map_err(|error| error.to_string());
+        let _ = remove_file(&path);
+        result
+    }).await;
+
+    let secret_key = match encryption_result {
+        Ok(Ok(secret_key)) => secret_key,
+        // A stored key fails when the file does not fit it, which the user can fix
+        Ok(Err(error)) if has_stored_key => {
+            job.remove();
+            return Ok(HttpResponse::BadRequest().body(format!("Encryption failed: {}", error)));
+        },
+        Ok(Err(_)) => {
+            job.remove();
+            return Ok(HttpResponse::InternalServerError().body("Encryption failed"));
+        },
+        Err(error) => {
+            job.remove();
+            return Ok(queue_error(error));
+        },
+    };
+    let mut archive_entries = vec![ArchiveEntry::File("encrypted_matrix.csv".to_string(), job.output_path("encrypted_matrix.csv").into())];
+    if let Some(secret_key) = secret_key {
 
-    // Trigger the encrypt_file function
-    let encryption_result = encrypt_file(&path).await;
+        let secret_key_file = match secret_key_file(&secret_key, passphrase) {
+            Ok(secret_key_file) => secret_key_file,
+            Err(error) => {
+                eprintln!("Failed to wrap secret key: {}", error);
+                job.remove();
+                return Ok(HttpResponse::InternalServerError().body("Encryption failed"));
+            },
+        };
 
-    // Will safely remove the file after the encrypt_file function is run
-    let _ = remove_file(&path);
+        if let Some(keystore) = &key_keystore {
+            if let Err(error) = keystore.store(&user.name, &secret_key) {
+                eprintln!("Failed to store secret key: {}", error);
+                job.remove();
+                return Ok(HttpResponse::InternalServerError().body("Encryption failed"));
+            }
+        }
+        archive_entries.push(ArchiveEntry::Bytes("secret_key.key".to_string(), secret_key_file));
+    }
+    archive_entries.push(ArchiveEntry::File("public_key.key".to_string(), job.output_path("public_key.key").into()));
+
+    // Everything the user needs goes back in one archive, so nothing has to be fetched from the server afterwards.
+    // The archive is streamed, so the encrypted matrix is read from the job folder a chunk at a time.
+    let archive = tar_stream(archive_entries);
+    Ok(HttpResponse::Ok()
+        .insert_header(("X-Job-Id", job.id.clone()))
+        .insert_header(ContentType::octet_stream())
+        .insert_header(ContentDisposition::attachment("encrypted_files.tar"))
+        .streaming(chunk_stream(archive)))
+}
 
-    match encryption_result {
-        Ok(_) => Ok(HttpResponse::Ok().body("File encrypted successfully")),
-        Err(_) => Ok(HttpResponse::InternalServerError().body("Encryption failed")),
+// The secret key file sent to the user, wrapped under the passphrase when one is given
+fn secret_key_file(secret_key: &SecretMatrix, passphrase: Option<&str>) -> Result<Zeroizing<Vec<u8>>, Box<dyn std::error::Error>> {
+    let mut key_text = secret_key.to_text();
+    match passphrase {
+        Some(passphrase) => Ok(Zeroizing::new(wrap_secret_key(&key_text, passphrase)?.into_bytes())),
+        // Moved out of the zeroizing string rather than copied
+        None => Ok(Zeroizing::new(std::mem::take(&mut *key_text).into_bytes())),
     }
 }
 
+#[derive(serde::Deserialize)]
+struct BackgroundQuery {
+    // With ?async=true the job is queued and the request answered at once
+    #[serde(default, rename = "async")]
+    background: bool,
+}
+
+// Answers a request whose job was queued in the background. Its progress is streamed from
+// /api/v1/jobs/{id}/events and its files are downloaded from /jobs/{id}/{file} once it has succeeded.
+fn queued_response(job: &Job, submitted: std::result::Result<(), QueueError>) -> HttpResponse {
+    match submitted {
+        Ok(()) => HttpResponse::Accepted()
+            .insert_header(("X-Job-Id", job.id.clone()))
+            .body(format!("Job {} has been queued", job.id)),
+        Err(error) => {
+            job.remove();
+            queue_error(error)
+        },
+    }
+}
 
 #[derive(MultipartForm)]
 struct UploadEncryptForm {
     // Pulls the file that is uploaded to input field with the name fileInputEncrypt
     #[multipart(rename = "fileInputEncrypt")]
     encrypt_file: TempFile,
+
+    #[multipart(rename = "encryptNumeric")]
+    numeric: Option<Text<String>>,
+
+    // When set the secret key file is wrapped under this passphrase
+    #[multipart(rename = "encryptPassphrase")]
+    passphrase: Option<Text<String>>,
+
+    // When set a new secret key is also kept in the keystore, its id being its fingerprint, besides being returned
+    // in the archive. Required to encrypt with a new key in the background.
+    #[multipart(rename = "encryptKeepSecretKey")]
+    keep_secret_key: Option<Text<String>>,
+
+    // Id of a key in the server keystore to encrypt with instead of a new key
+    #[multipart(rename = "encryptKeyId")]
+    key_id: Option<Text<String>>,
+}
+
+// Creates the job for a request, or the error response to send when its folders can't be created
+fn create_job(user: &User) -> std::result::Result<Job, HttpResponse> {
+    Job::create(&user.name).map_err(|error| {
+        eprintln!("Failed to create job folders: {}", error);
+        HttpResponse::InternalServerError().body("Failed to create job folders")
+    })
+}
+
+// Moves an upload into the job's input folder under a name chosen by the server, so work queued on it can outlive
+// the request's temp files. Returns its path, or the error response to send after the job is removed.
+fn persist_upload(job: &Job, upload: TempFile, name: &str) -> std::result::Result<String, HttpResponse> {
+    let path = job.input_path(name);
+    match upload.file.persist(&path) {
+        Ok(_) => Ok(path),
+        Err(error) => {
+            eprintln!("Failed to save uploaded {}: {}", name, error);
+            job.remove();
+            Err(HttpResponse::InternalServerError().body(format!("Failed to save {}", name)))
+        },
+    }
+}
+
+// Success response naming the job whose output files can be downloaded from /jobs/{id}/{file}
+fn job_response(job: &Job, message: &str) -> HttpResponse {
+    HttpResponse::Ok()
+        .insert_header(("X-Job-Id", job.id.clone()))
+        .body(format!("{}. Job id: {}", message, job.id))
+}
+
+// Response for a request the job queue could not run
+fn queue_error(error: QueueError) -> HttpResponse {
+    match error {
+        QueueError::Full => HttpResponse::ServiceUnavailable().insert_header(("Retry-After", "5")).body(error.to_string()),
+        QueueError::Cancelled => HttpResponse::Conflict().body(error.to_string()),
+        QueueError::Failed(message) => {
+            eprintln!("Job failed: {}", message);
+            HttpResponse::InternalServerError().body("The job failed")
+        },
+    }
+}
+
+#[get("/jobs/{id}/{file}")]
+async fn job_file_handler(user: User, request: HttpRequest, path: web::Path<(String, String)>, queue: web::Data<JobQueue>) -> Result<HttpResponse> {
+    let (id, file) = path.into_inner();
+    // The files of a queued or running job are not complete yet
+    if queue.status(&id).is_some_and(|status| status.state != JobState::Succeeded) {
+        return Err(actix_web::error::ErrorConflict("The job has not finished"));
+    }
+    let Some((job, output)) = Job::open(&id, &user.name).and_then(|job| job.find_output(&file).map(|output| (job, output))) else {
+        return Err(actix_web::error::ErrorNotFound("No such job file"));
+    };
+    // A decrypted CSV is only kept until it is downloaded
+    if file == PLAINTEXT_OUTPUT {
+        return send_plaintext_once(&job, &output).map_err(|_| actix_web::error::ErrorNotFound("No such job file"));
+    }
+    Ok(actix_files::NamedFile::open(output)?
+        .set_content_disposition(ContentDisposition::attachment(file))
+        .into_response(&request))
+}
+
+// Sends a decrypted CSV once. It is moved out of its job first, so it can't be downloaded again, and is overwritten
+// and deleted once it has been sent or the client has gone away.
+fn send_plaintext_once(job: &Job, path: &std::path::Path) -> std::io::Result<HttpResponse> {
+    let sending = SecureDeleteOnDrop(uploads_dir().join(format!("{}.csv", job.id)));
+    std::fs::rename(path, &sending.0)?;
+    let file = File::open(&sending.0)?;
+    Ok(send_plaintext(chunk_stream(read_chunks(file)).holding(sending)))
+}
+
+// Streams a decrypted CSV as output.csv
+fn send_plaintext(body: impl futures::Stream<Item = std::io::Result<web::Bytes>> + 'static) -> HttpResponse {
+    HttpResponse::Ok()
+        .insert_header(("Content-Type", "text/csv; charset=utf-8"))
+        .insert_header(ContentDisposition::attachment(PLAINTEXT_OUTPUT))
+        .streaming(body)
+}
+
+#[derive(serde::Deserialize)]
+struct LoginForm {
+    username: String,
+    password: String,
+}
+
+// Starts a session for the web page, kept in an HttpOnly cookie. API clients send a token instead.
+#[post("/login")]
+async fn login_handler(request: HttpRequest, form: web::Form<LoginForm>, auth: web::Data<Auth>) -> HttpResponse {
+    if !auth.enabled() {
+        return HttpResponse::Ok().body("Authentication is turned off");
+    }
+    // Checking the password is deliberately slow, so it runs off the async workers
+    let (username, password) = (form.username.clone(), form.password.clone());
+    let login_auth = auth.clone();
+    let session = web::block(move || login_auth.login(&username, &password)).await.ok().flatten();
+    match session {
+        Some(session) => {
+            info!("User {} logged in", form.username);
+            let cookie = Cookie::build(SESSION_COOKIE, session)
+                .path("/")
+                .http_only(true)
+                .secure(request.connection_info().scheme() == "https")
+                .same_site(SameSite::Strict)
+                .max_age(actix_web::cookie::time::Duration::seconds(auth.session_ttl().as_secs() as i64))
+                .finish();
+            HttpResponse::Ok().cookie(cookie).body(format!("Logged in as {}", form.username))
+        }
+        None => {
+            info!("Failed login for {}", form.username);
+            HttpResponse::Unauthorized().body("Invalid user name or password")
+        }
+    }
+}
+
+#[post("/logout")]
+async fn logout_handler(request: HttpRequest, auth: web::Data<Auth>) -> HttpResponse {
+    if let Some(session) = request.cookie(SESSION_COOKIE) {
+        auth.logout(session.value());
+    }
+    let mut response = HttpResponse::Ok().body("Logged out");
+    let _ = response.add_removal_cookie(&Cookie::build(SESSION_COOKIE, "").path("/").finish());
+    response
+}
+
+// Names the logged in user, so the web page knows whether to show the login form
+#[get("/session")]
+async fn session_handler(user: User) -> HttpResponse {
+    HttpResponse::Ok().body(user.name)
+}
+
+// An empty passphrase field in the form means no passphrase
+fn form_passphrase(field: &Option<Text<String>>) -> Option<&str> {
+    field.as_ref().map(|text| text.as_str()).filter(|passphrase| !passphrase.is_empty())
+}
+
+// A stored key the user may use, or the error response to send
+fn stored_key(keystore: Option<&Keystore>, key_id: &str, user: &User) -> std::result::Result<SecretMatrix, HttpResponse> {
+    let Some(keystore) = keystore else {
+        return Err(HttpResponse::BadRequest().body("The keystore is not enabled on this server"));
+    };
+    match keystore.load(key_id, &user.name) {
+        Ok(Some((_, secret_key))) => Ok(secret_key),
+        Ok(None) => Err(HttpResponse::NotFound().body(format!("No stored key {}", key_id))),
+        Err(error) => {
+            eprintln!("Failed to read stored key {}: {}", key_id, error);
+            Err(HttpResponse::InternalServerError().body("Failed to read the stored key"))
+        },
+    }
+}
+
+// The client's name for an uploaded file, cleaned up for logs and messages
+fn upload_name(file: &TempFile) -> String {
+    file.file_name.as_deref().and_then(sanitize_file_name).unwrap_or_else(|| "upload".to_string())
+}
+
+// Reads an upload that may hold key material. The buffer is sized to the upload so it never grows and leaves copies
+// behind.
+fn read_secret_upload(file: &mut TempFile) -> std::io::Result<Zeroizing<String>> {
+    let mut contents = Zeroizing::new(String::with_capacity(file.size));
+    file.file.as_file_mut().read_to_string(&mut contents)?;
+    Ok(contents)
+}
+
+// Parses an uploaded secret key or combines uploaded key shares in memory, so neither is ever copied into a job
+fn uploaded_key(secret_key_file: Option<TempFile>, key_shares: Vec<TempFile>, passphrase: Option<&str>) -> std::result::Result<Option<SecretMatrix>, Box<dyn std::error::Error>> {
+    if let Some(mut secret_key_file) = secret_key_file {
+        return parse_secret_key(&read_secret_upload(&mut secret_key_file)?, passphrase).map(Some);
+    }
+    if key_shares.is_empty() {
+        return Ok(None);
+    }
+    let mut shares = Vec::with_capacity(key_shares.len());
+    for mut share in key_shares {
+        shares.push(parse_key_share(&read_secret_upload(&mut share)?, &upload_name(&share))?);
+    }
+    info!("Decrypting with {} key shares", shares.len());
+    combine_key_shares(&shares).map(Some)
+}
+
+// Uploads are written to disk as they arrive, so a file over max_file_bytes is only rejected once it has been received
+fn oversized_file(limits: &LimitsConfig, files: &[&TempFile]) -> Option<HttpResponse> {
+    files.iter().find(|file| file.size > limits.max_file_bytes).map(|file| {
+        HttpResponse::PayloadTooLarge().body(format!(
+            "File {} is larger than the limit of {} bytes",
+            upload_name(file),
+            limits.max_file_bytes
+        ))
+    })
+}
+
+// Both multipart limits report an overflow the same way, so the message names both
+fn multipart_error(error: MultipartError, limits: &LimitsConfig) -> actix_web::Error {
+    let response = match &error {
+        MultipartError::Payload(actix_web::error::PayloadError::Overflow) => HttpResponse::PayloadTooLarge().body(format!(
+            "Upload is larger than the limit of {} bytes, or a text field is larger than {} bytes",
+            limits.max_upload_bytes, limits.max_text_field_bytes
+        )),
+        _ => HttpResponse::BadRequest().body(error.to_string()),
+    };
+    actix_web::error::InternalError::from_response(error, response).into()
+}
+
+// Registers the routes the configuration leaves enabled
+fn configure_endpoints(cfg: &mut web::ServiceConfig, endpoints: &EndpointsConfig, max_api_body_bytes: usize) {
+    if endpoints.frontend {
+        cfg.route("/", web::get().to(index));
+    }
+    cfg.service(login_handler).service(logout_handler).service(session_handler);
+    // Output files are served per job from /jobs/{id}/{file}
+    if endpoints.jobs {
+        cfg.service(job_file_handler);
+    }
+    if endpoints.encrypt {
+        cfg.service(encrypt_handler);
+    }
+    if endpoints.decrypt {
+        cfg.service(decrypt_handler);
+    }
+    if endpoints.compute {
+        cfg.service(compute_handler);
+    }
+    if endpoints.rekey {
+        cfg.service(rekey_token_handler).service(rekey_handler);
+    }
+    // JSON versions of the endpoints for automation
+    if endpoints.api {
+        cfg.service(api::lattice_api::api_scope(max_api_body_bytes));
+    }
+    if endpoints.metrics {
+        cfg.service(metrics_handler);
+    }
+}
+
+// Liveness, answered as long as the server is running
+#[get("/healthz")]
+async fn healthz_handler() -> HttpResponse {
+    HttpResponse::Ok().body("ok")
+}
+
+// Readiness, 503 with the reasons while the server should not be sent traffic
+#[get("/readyz")]
+async fn readyz_handler(readiness: web::Data<Readiness>) -> HttpResponse {
+    let problems = readiness.problems();
+    if problems.is_empty() {
+        HttpResponse::Ok().body("ready")
+    } else {
+        HttpResponse::ServiceUnavailable().body(problems.join("\n"))
+    }
+}
+
+// Every other route once the cipher has failed its self-test
+async fn self_test_failed() -> HttpResponse {
+    HttpResponse::ServiceUnavailable().body("The server failed its startup self-test and is not taking requests, see /readyz")
+}
+
+// 429 for a request the rate limiter turned away, with the API's JSON error under /api/v1
+fn too_many_requests(path: &str, refused: Refused) -> HttpResponse {
+    let mut response = if path.starts_with("/api/") {
+        actix_web::ResponseError::error_response(&api::lattice_api::ApiError::rate_limited(refused.message))
+    } else {
+        HttpResponse::TooManyRequests().body(refused.message)
+    };
+    let retry_after = refused.retry_after.as_secs_f64().ceil().max(1.0) as u64;
+    response.headers_mut().insert(actix_web::http::header::RETRY_AFTER, retry_after.into());
+    response
+}
+
+// For Prometheus to scrape. Labels hold route patterns and operation names, never user names or file names.
+#[get("/metrics")]
+async fn metrics_handler() -> HttpResponse {
+    HttpResponse::Ok().content_type("text/plain; version=0.0.4; charset=utf-8").body(lattice_metrics::render())
+}
+
+// Text fields are held in memory while files go to disk, so each has its own limit
+fn multipart_config(limits: &LimitsConfig) -> MultipartFormConfig {
+    let error_limits = limits.clone();
+    MultipartFormConfig::default()
+        .total_limit(limits.max_upload_bytes)
+        .memory_limit(limits.max_text_field_bytes)
+        .error_handler(move |error, _| multipart_error(error, &error_limits))
 }
 
 #[actix_web::main]
 async fn main() -> std::io::Result<()> {
-    env_logger::init();
+    // Subcommands run without starting the web server
+    let args: Vec<String> = std::env::args().collect();
+    if let Some(result) = cli::lattice_cli::run(&args) {
+        if let Err(error) = result {
+            eprintln!("{}", error);
+            std::process::exit(1);
+        }
+        return Ok(());
+    }
+
+    let mut config = match Config::load(flag_value(&args, "--config").as_deref()) {
+        Ok(config) => config,
+        Err(error) => {
+            eprintln!("Invalid configuration: {}", error);
+            std::process::exit(1);
+        }
+    };
+    if let Some(dir) = flag_value(&args, "--static-dir") {
+        config.storage.static_dir = Some(dir);
+    }
+    env_logger::Builder::from_env(env_logger::Env::default().default_filter_or(&config.logging.level)).init();
+
+    set_jobs_dir(&config.storage.jobs_dir);
+    log::info!("Temporary job and upload folders being created in {}.", jobs_dir().display());
+    std::fs::create_dir_all(uploads_dir())?;
+
+    if let Some(dir) = &config.storage.static_dir {
+        log::info!("Serving the frontend from {}", dir);
+    }
+    let static_dir = web::Data::new(StaticDir(config.storage.static_dir.clone()));
+    if !config.auth.enabled {
+        log::warn!("Authentication is turned off, anyone who can reach the server shares one anonymous user. Add a user with user-add and set auth.enabled = true to require logins");
+    }
+    let auth = web::Data::new(Auth::new(&config.auth));
+    // Handlers take Option<web::Data<Keystore>>, which is None while the keystore is turned off
+    let keystore = if config.keystore.enabled {
+        match Keystore::open(&config.keystore.dir, &config.keystore.master_key_file) {
+            Ok(keystore) => Some(web::Data::new(keystore)),
+            Err(error) => {
+                eprintln!("Invalid configuration: {}", error);
+                std::process::exit(1);
+            }
+        }
+    } else {
+        None
+    };
+
+    // Every cryptographic operation is recorded in the audit log, which is opened once and appended to
+    let audit = if config.audit.enabled {
+        match AuditLog::open(&config.audit.file) {
+            Ok(audit) => web::Data::new(audit),
+            Err(error) => {
+                eprintln!("Invalid configuration: {}", error);
+                std::process::exit(1);
+            }
+        }
+    } else {
+        log::warn!("The audit log is turned off, cryptographic operations are not recorded");
+        web::Data::new(AuditLog::disabled())
+    };
+
+    // Encryption, decryption and key generation run on these threads rather than the server's workers
+    let queue_workers = match config.queue.workers {
+        0 => std::thread::available_parallelism().map(|count| count.get()).unwrap_or(1),
+        workers => workers,
+    };
+    let queue = web::Data::new(JobQueue::new(queue_workers, config.queue.max_queued, std::time::Duration::from_secs(config.retention.job_ttl_secs))?);
+    log::info!("Job queue started with {} workers", queue_workers);
+
+    // Expired files are deleted in the background, except those of jobs still in the queue
+    let sweeper_queue = queue.clone();
+    start_sweeper(config.retention.clone(), move |job_id| sweeper_queue.is_busy(job_id))?;
+
+    // The cipher is checked against a known answer before any traffic is taken. A server that fails it still answers
+    // /healthz, /readyz and /metrics, so the failure can be seen, and turns everything else away.
+    let self_test = self_test().map_err(|error| error.to_string());
+    match &self_test {
+        Ok(()) => log::info!("Cipher self-test passed"),
+        Err(error) => log::error!("Cipher self-test failed, refusing requests: {}", error),
+    }
+    let mut storage_dirs = vec![jobs_dir().to_path_buf(), uploads_dir()];
+    if config.keystore.enabled {
+        storage_dirs.push(std::path::PathBuf::from(&config.keystore.dir));
+    }
+    let readiness = web::Data::new(Readiness::new(self_test, storage_dirs));
+    let rate_limiter = std::sync::Arc::new(RateLimiter::new(&config.rate_limit));
 
-    log::info!("Temporary input and output folders being created.");
-    std::fs::create_dir_all("./temp_encrypted_input")?;
-    std::fs::create_dir_all("./temp_encrypted_output")?;
-    std::fs::create_dir_all("./temp_decrypted_input")?;
-    std::fs::create_dir_all("./temp_decrypted_output")?;
+    // HTTPS with the configured certificate, or one made at startup with --self-signed
+    let self_signed = args.iter().any(|arg| arg == "--self-signed");
+    let certificates = if self_signed {
+        log::warn!("Serving HTTPS with a self-signed certificate, which is only meant for development");
+        Some(Certificates::self_signed(self_signed_names(&config.server.bind)))
+    } else if config.tls.enabled {
+        Some(Certificates::from_files(&config.tls.cert_file, &config.tls.key_file))
+    } else {
+        None
+    };
+    let certificates = match certificates.transpose() {
+        Ok(certificates) => certificates.map(std::sync::Arc::new),
+        Err(error) => {
+            eprintln!("Invalid configuration: {}", error);
+            std::process::exit(1);
+        }
+    };
+    #[cfg(unix)]
+    if let Some(certificates) = certificates.as_ref().filter(|_| !self_signed) {
+        tls::lattice_tls::reload_on_sighup(certificates.clone())?;
+    }
 
-    log::info!("HTTP server starting: http://localhost:8080");
+    let scheme = if certificates.is_some() { "https" } else { "http" };
+    log::info!("HTTP server starting: {}://{}", scheme, config.server.bind);
 
-    HttpServer::new(|| {
+    let app_config = config.clone();
+    let mut server = HttpServer::new(move || {
+        let rate_limiter = rate_limiter.clone();
         App::new()
-            // This creates temporary file storage
-            .app_data(TempFileConfig::default().directory("./temp_encrypted_input"))
-            .app_data(TempFileConfig::default().directory("./temp_encrypted_output"))
-            .app_data(TempFileConfig::default().directory("./temp_decrypted_input"))
-            .app_data(TempFileConfig::default().directory("./temp_decrypted_output"))
-            // This will path the files created files in the temp_encrypted_output folder to the /encrypted endpoint
-            .service(actix_files::Files::new("/encrypted", "./temp_encrypted_output").show_files_listing())
-            .service(actix_files::Files::new("/decrypted", "./temp_decrypted_output").show_files_listing())
-            .route("/", web::get().to(index))
-            .service(encrypt_handler)
-            .service(decrypt_handler)
-    })
-    .bind("localhost:8080")?
-    .run()
-    .await
+            // Crypto requests are limited before their uploads are read, and keep their place until they are answered. A
+            // background job gives its place back with the 202, from then on the queue's workers limit it.
+            .wrap_fn(move |request, service| {
+                let admitted = Endpoint::of(request.method(), request.path())
+                    .map(|endpoint| rate_limiter.admit(endpoint, &client_of(&request)))
+                    .transpose();
+                let response = match admitted {
+                    Ok(permit) => Ok((service.call(request), permit)),
+                    Err(refused) => {
+                        let response = too_many_requests(request.path(), refused);
+                        Err(request.into_response(response))
+                    }
+                };
+                async move {
+                    match response {
+                        Ok((response, _permit)) => response.await.map(|response| response.map_into_left_body()),
+                        Err(response) => Ok(response.map_into_right_body()),
+                    }
+                }
+            })
+            // Every request is counted by the pattern of the route it matched, so job ids don't each make a series
+            .wrap_fn(|request, service| {
+                let started = std::time::Instant::now();
+                let response = service.call(request);
+                async move {
+                    let response = response.await?;
+                    let route = response.request().match_pattern().unwrap_or_else(|| "unmatched".to_string());
+                    lattice_metrics::observe_request(&route, response.status().as_str(), started.elapsed());
+                    Ok(response)
+                }
+            })
+            .app_data(static_dir.clone())
+            .app_data(auth.clone())
+            .app_data(queue.clone())
+            .app_data(audit.clone())
+            .app_data(readiness.clone())
+            .configure(|cfg| {
+                if let Some(keystore) = &keystore {
+                    cfg.app_data(keystore.clone());
+                }
+            })
+            // This creates temporary file storage for uploads, on the same disk as the job folders they are moved into
+            .app_data(TempFileConfig::default().directory(uploads_dir()))
+            .app_data(web::Data::new(app_config.limits.clone()))
+            .app_data(multipart_config(&app_config.limits))
+            .service(healthz_handler)
+            .service(readyz_handler)
+            .configure(|cfg| {
+                if readiness.self_test_passed() {
+                    configure_endpoints(cfg, &app_config.endpoints, app_config.limits.max_api_body_bytes);
+                } else {
+                    if app_config.endpoints.metrics {
+                        cfg.service(metrics_handler);
+                    }
+                    cfg.default_service(web::to(self_test_failed));
+                }
+            })
+    });
+    if config.server.workers > 0 {
+        server = server.workers(config.server.workers);
+    }
+    server = match certificates {
+        Some(certificates) => server.bind_rustls_0_22(&config.server.bind, server_config(certificates))?,
+        None => server.bind(&config.server.bind)?,
+    };
+    server.run().await
 }
 
 #[derive(MultipartForm)]
@@ -124,88 +779,338 @@ struct UploadDecryptForm {
     #[multipart(rename = "fileInputDecryptMatrix")]
     encrypted_matrix_file: TempFile,
 
+    // Either the secret key or at least the threshold number of key shares must be uploaded
     #[multipart(rename = "fileInputDecryptSecretKey")]
-    secret_key_file: TempFile,
+    secret_key_file: Option<TempFile>,
+
+    #[multipart(rename = "fileInputDecryptKeyShares")]
+    key_shares: Vec<TempFile>,
 
+    // Accepted for older clients but not needed, decryption only uses the secret key
     #[multipart(rename = "fileInputDecryptPublicKey")]
-    public_key_file: TempFile,
+    public_key_file: Option<TempFile>,
+
+    // Required when the secret key file is passphrase protected
+    #[multipart(rename = "decryptPassphrase")]
+    passphrase: Option<Text<String>>,
 }
 
 
-async fn decrypt_file(encrypted_matrix_file_path: &str, secret_key_file_path: &str, public_key_file_path: &str) -> Result<String, Box<dyn std::error::Error>> {
-    info!("File paths to be decrypted: {encrypted_matrix_file_path}, {secret_key_file_path} and {public_key_file_path}");
-    
-    let output = lattice_decrypt_csv(encrypted_matrix_file_path, secret_key_file_path, public_key_file_path);
+#[post("/decrypt")]
+async fn decrypt_handler(user: User, query: web::Query<BackgroundQuery>, MultipartForm(upload_decrypt_form): MultipartForm<UploadDecryptForm>, limits: web::Data<LimitsConfig>, keystore: Option<web::Data<Keystore>>, queue: web::Data<JobQueue>, audit: web::Data<AuditLog>) -> Result<HttpResponse> {
+    let mut uploads = vec![&upload_decrypt_form.encrypted_matrix_file];
+    uploads.extend(upload_decrypt_form.public_key_file.iter());
+    uploads.extend(upload_decrypt_form.secret_key_file.iter());
+    uploads.extend(upload_decrypt_form.key_shares.iter());
+    if let Some(response) = oversized_file(&limits, &uploads) {
+        return Ok(response);
+    }
 
-    info!("Output of Lattice Decryption: {:?}", output);
+    // The client's file names only tell which files were sent; they are never used as paths
+    if upload_decrypt_form.encrypted_matrix_file.file_name.is_none() {
+        return Ok(HttpResponse::BadRequest().body("Bad Request no Encrypted Matrix CSV file is provided."));
+    }
 
-    match output {
-        Ok(result) => {
-            info!("Lattice Encryption has succeeded: {:?}", result);
-            Ok("Lattice Decryption has succeeded ".to_string())
+    // Browsers send an empty file part for an unused file input, so only named uploads count
+    let key_shares: Vec<TempFile> = upload_decrypt_form.key_shares.into_iter().filter(|share| share.file_name.as_ref().is_some_and(|name| !name.is_empty())).collect();
+    let secret_key_file = upload_decrypt_form.secret_key_file.filter(|file| file.file_name.as_ref().is_some_and(|name| !name.is_empty()));
+    // Without either, the key is looked up in the keystore by the fingerprint the matrix carries
+    if secret_key_file.is_none() && key_shares.is_empty() && keystore.is_none() {
+        return Ok(HttpResponse::BadRequest().body("Bad Request no Secret Key file or key shares are provided."));
+    }
+
+    // Every request gets its own job folders so concurrent uploads can't overwrite each other
+    let job = match create_job(&user) {
+        Ok(job) => job,
+        Err(response) => return Ok(response),
+    };
+    let encrypted_matrix_file_path = job.input_path("encrypted_matrix.csv");
+    let output_file_path = job.output_path("output.csv");
+
+    // Only the encrypted matrix is saved to the job's input folder, the key is kept in memory
+    let encrypted_matrix_name = upload_name(&upload_decrypt_form.encrypted_matrix_file);
+    if let Err(error) = upload_decrypt_form.encrypted_matrix_file.file.persist(&encrypted_matrix_file_path) {
+        eprintln!("Failed to save Encrypted Matrix CSV file: {}", error);
+        job.remove();
+        return Ok(HttpResponse::InternalServerError().body("Failed to Encrypted Matrix CSV file"));
+    }
+    info!("Saved Encrypted Matrix CSV file {} to Temp Path: {}", encrypted_matrix_name, encrypted_matrix_file_path);
+
+    // Unwrapping a passphrase protected key takes a slow key derivation, so the upload is parsed on the blocking
+    // thread pool. A key that can't be read is the client's to fix and is audited as a failed decryption.
+    let passphrase = form_passphrase(&upload_decrypt_form.passphrase).map(|passphrase| Zeroizing::new(passphrase.to_string()));
+    let (key_owner, key_audit, key_matrix_path) = (user.name.clone(), audit.clone(), encrypted_matrix_file_path.clone());
+    let uploaded_secret_key = web::block(move || {
+        let result = uploaded_key(secret_key_file, key_shares, passphrase.as_deref().map(String::as_str));
+        if result.is_err() {
+            let mut event = AuditEvent::start(&key_owner, Operation::Decrypt);
+            event.input_file(&key_matrix_path);
+            event.key(read_key_fingerprint(&key_matrix_path).ok().flatten());
+            key_audit.record(event, &result);
+        }
+        result.map_err(|error| error.to_string())
+    }).await;
+    let uploaded_secret_key = match uploaded_secret_key {
+        Ok(Ok(secret_key)) => secret_key,
+        Ok(Err(error)) => {
+            job.remove();
+            return Ok(HttpResponse::BadRequest().body(format!("Decryption failed: {}", error)));
         },
         Err(error) => {
-            info!("Lattice Decryption has failed: {:?}", error);
-            Err(error.into())
+            eprintln!("Failed to read the uploaded key: {}", error);
+            job.remove();
+            return Ok(HttpResponse::InternalServerError().body("Failed to read the uploaded key"));
         },
+    };
+
+    // Without an uploaded key or shares, the key the matrix was encrypted with is looked up in the keystore
+    let secret_key = if let Some(secret_key) = uploaded_secret_key {
+        secret_key
+    } else {
+        let fingerprint = match read_key_fingerprint(&encrypted_matrix_file_path) {
+            Ok(Some(fingerprint)) => fingerprint,
+            _ => {
+                job.remove();
+                return Ok(HttpResponse::BadRequest().body("The encrypted matrix names no key, upload the Secret Key file"));
+            },
+        };
+        let secret_key = match stored_key(keystore.as_ref().map(|keystore| keystore.get_ref()), &fingerprint, &user) {
+            Ok(secret_key) => secret_key,
+            Err(response) => {
+                job.remove();
+                return Ok(response);
+            },
+        };
+        info!("Decrypting {} with stored key {}", encrypted_matrix_file_path, fingerprint);
+        secret_key
+    };
+
+    // The decryption itself runs on the job queue and writes the recovered CSV to output
+    let matrix_path = encrypted_matrix_file_path.clone();
+    let owner = user.name.clone();
+    let decrypt = move |context: &TaskContext, output: &mut dyn Write| {
+        info!("File path to be decrypted: {matrix_path}");
+        let mut event = AuditEvent::start(&owner, Operation::Decrypt);
+        event.input_file(&matrix_path);
+        event.key(Some(key_fingerprint(&secret_key)));
+        let result: std::result::Result<(), Box<dyn std::error::Error>> = lattice_decrypt_to_writer(&matrix_path, &secret_key, output, context).map_err(Into::into);
+        audit.record(event, &result);
+        match &result {
+            Ok(()) => info!("Lattice Decryption has succeeded"),
+            Err(error) => info!("Lattice Decryption has failed: {:?}", error),
+        }
+
+        // Will remove the job's input file once decrypted
+        let _ = remove_file(&matrix_path);
+        result.map_err(|error| error.to_string())
+    };
+
+    // In the background the recovered CSV stays in the job until it is downloaded from /jobs/{id}/output.csv
+    if query.background {
+        let submitted = queue.submit(&job, &user.name, move |context| {
+            let mut output = File::create(&output_file_path).map_err(|error| error.to_string())?;
+            decrypt(context, &mut output)
+        });
+        return Ok(queued_response(&job, submitted));
+    }
+    // Otherwise it is only held in memory. The CSV is shorter than its encrypted matrix, so the buffer is sized to
+    // never grow and leave copies behind.
+    let capacity = std::fs::metadata(&encrypted_matrix_file_path).map_or(0, |metadata| metadata.len() as usize);
+    let decryption_result = queue.run(&job, &user.name, move |context| {
+        let mut csv = Zeroizing::new(Vec::with_capacity(capacity));
+        decrypt(context, &mut *csv).map(|_| csv)
+    }).await;
+    job.remove();
+
+    // The recovered CSV is sent straight back and not kept, so plaintext never outlives the request
+    match decryption_result {
+        Ok(Ok(csv)) => Ok(send_plaintext(chunk_stream(read_chunks(std::io::Cursor::new(csv))))),
+        // A wrong key, passphrase or share fails here, which is the client's to fix
+        Ok(Err(error)) => Ok(HttpResponse::BadRequest().body(format!("Decryption failed: {}", error))),
+        Err(error) => Ok(queue_error(error)),
     }
 }
 
-#[post("/decrypt")]
-async fn decrypt_handler(MultipartForm(upload_decrypt_form): MultipartForm<UploadDecryptForm>) -> Result<HttpResponse> {
+#[derive(MultipartForm)]
+struct UploadComputeForm {
+    // Column-wise numeric encrypted matrix produced by /encrypt with encryptNumeric set
+    #[multipart(rename = "fileInputComputeMatrix")]
+    encrypted_matrix_file: TempFile,
 
-    // Retrives the file names of the uploaded decrypt files from the Form
-    let encrypted_matrix_file_name = match upload_decrypt_form.encrypted_matrix_file.file_name {
-        Some(name) => name.to_string(),
-        None => return Ok(HttpResponse::BadRequest().body("Bad Request no Encrypted Matrix CSV file is provided."))
-    };
+    // Expressions such as `total = Math + Science; scaled = 1.5 * Math`
+    #[multipart(rename = "computeExpression")]
+    expression: Text<String>,
+}
 
-    let secret_key_file_name = match upload_decrypt_form.secret_key_file.file_name {
-        Some(name) => name.to_string(),
-        None => return Ok(HttpResponse::BadRequest().body("Bad Request no Secret Key file is provided."))
-    };
+#[post("/compute")]
+async fn compute_handler(user: User, MultipartForm(upload_compute_form): MultipartForm<UploadComputeForm>, limits: web::Data<LimitsConfig>, queue: web::Data<JobQueue>, audit: web::Data<AuditLog>) -> Result<HttpResponse> {
+    if let Some(response) = oversized_file(&limits, &[&upload_compute_form.encrypted_matrix_file]) {
+        return Ok(response);
+    }
 
-    let public_key_file_name = match upload_decrypt_form.public_key_file.file_name {
-        Some(name) => name.to_string(),
-        None => return Ok(HttpResponse::BadRequest().body("Bad Request no Public Key file is provided."))
+    let job = match create_job(&user) {
+        Ok(job) => job,
+        Err(response) => return Ok(response),
     };
+    let encrypted_matrix_file_path = match persist_upload(&job, upload_compute_form.encrypted_matrix_file, "encrypted_matrix.csv") {
+        Ok(path) => path,
+        Err(response) => return Ok(response),
+    };
+    let output_path = job.output_path("computed_matrix.csv");
 
-    let encrypted_matrix_file_path = format!("./temp_decrypted_input/{}", encrypted_matrix_file_name);
-    let secret_key_file_path = format!("./temp_decrypted_input/{}", secret_key_file_name);
-    let public_key_file_path = format!("./temp_decrypted_input/{}", public_key_file_name);
+    info!("Computing '{}' over encrypted matrix {}", upload_compute_form.expression.0, encrypted_matrix_file_path);
 
-    
-    // Will create the encrypted_matrix_file, secret_key and public_key file to be decrypted in the temp_decrypted_input folder
-    if let Err(error) = upload_decrypt_form.encrypted_matrix_file.file.persist(&encrypted_matrix_file_path) {
-        eprintln!("Failed to save Encrypted Matrix CSV file: {}", error);
-        return Ok(HttpResponse::InternalServerError().body("Failed to Encrypted Matrix CSV file"));
+    // Large matrices take a while, so the computation runs on the job queue like encryption
+    let (owner, expression) = (user.name.clone(), upload_compute_form.expression.0);
+    let result = queue.run(&job, &user.name, move |_| {
+        let mut event = AuditEvent::start(&owner, Operation::Compute);
+        event.input_file(&encrypted_matrix_file_path);
+        event.key(read_key_fingerprint(&encrypted_matrix_file_path).ok().flatten());
+        let result = lattice_compute_csv(&encrypted_matrix_file_path, &expression, &output_path);
+        audit.record(event, &result);
+        let _ = remove_file(&encrypted_matrix_file_path);
+        result.map_err(|error| error.to_string())
+    }).await;
+    match result {
+        Ok(Ok(_)) => Ok(job_response(&job, "Computation on encrypted data successful")),
+        Ok(Err(error)) => {
+            job.remove();
+            info!("Lattice Computation has failed: {:?}", error);
+            Ok(HttpResponse::BadRequest().body(format!("Computation failed: {}", error)))
+        },
+        Err(error) => {
+            job.remove();
+            Ok(queue_error(error))
+        },
     }
-    info!("Saved Encrypted Matrix CSV file to Temp Path: {}", encrypted_matrix_file_path);
+}
 
-    if let Err(error) = upload_decrypt_form.secret_key_file.file.persist(&secret_key_file_path) {
-        eprintln!("Failed to save Secret Key file: {}", error);
-        return Ok(HttpResponse::InternalServerError().body("Failed to Secret Key file"));
-    }
-    info!("Saved Secret Key file to Temp Path: {}", encrypted_matrix_file_path);
+#[derive(MultipartForm)]
+struct UploadRekeyTokenForm {
+    // The secret key the existing encrypted matrices were made with
+    #[multipart(rename = "fileInputRekeySecretKey")]
+    secret_key_file: TempFile,
 
-    if let Err(error) = upload_decrypt_form.public_key_file.file.persist(&public_key_file_path) {
-        eprintln!("Failed to save Public Key file: {}", error);
-        return Ok(HttpResponse::InternalServerError().body("Failed to Public Key file"));
+    // Unwraps the old secret key and wraps the new one
+    #[multipart(rename = "rekeyPassphrase")]
+    passphrase: Option<Text<String>>,
+}
+
+#[post("/rekey/token")]
+async fn rekey_token_handler(user: User, MultipartForm(upload_rekey_token_form): MultipartForm<UploadRekeyTokenForm>, limits: web::Data<LimitsConfig>, queue: web::Data<JobQueue>, audit: web::Data<AuditLog>) -> Result<HttpResponse> {
+    if let Some(response) = oversized_file(&limits, &[&upload_rekey_token_form.secret_key_file]) {
+        return Ok(response);
     }
-    info!("Saved Public Key file to Temp Path: {}", public_key_file_path);
 
+    // Writes rekey_token.key and new_public_key.key to the job's output folder. The new secret key is only sent back.
+    let passphrase = form_passphrase(&upload_rekey_token_form.passphrase);
+    let job = match create_job(&user) {
+        Ok(job) => job,
+        Err(response) => return Ok(response),
+    };
+    let secret_key_file_path = match persist_upload(&job, upload_rekey_token_form.secret_key_file, "secret_key.key") {
+        Ok(path) => path,
+        Err(response) => return Ok(response),
+    };
 
-    // 
-    let decryption_result = decrypt_file(&encrypted_matrix_file_path, &secret_key_file_path, &public_key_file_path).await;
+    // Generating the new key is the slow part, so it runs on the job queue
+    let (owner, output_dir, token_path) = (user.name.clone(), job.output_dir_str(), job.output_path("rekey_token.key"));
+    let task_passphrase = passphrase.map(str::to_string);
+    let result = queue.run(&job, &user.name, move |_| {
+        // The token names the key it moves away from, which is the key this was asked for
+        let mut event = AuditEvent::start(&owner, Operation::RekeyToken);
+        event.input_file(&secret_key_file_path);
+        let result = lattice_rekey_token_returning_secret_key(&secret_key_file_path, &output_dir, task_passphrase.as_deref());
+        event.key(read_header_value(&token_path, REKEY_FROM_HEADER).ok().flatten());
+        audit.record(event, &result);
+        let _ = secure_delete(std::path::Path::new(&secret_key_file_path));
+        result.map_err(|error| error.to_string())
+    }).await;
+    let new_secret_key = match result {
+        Ok(Ok(new_secret_key)) => new_secret_key,
+        Ok(Err(error)) => {
+            job.remove();
+            info!("Re-key token generation has failed: {:?}", error);
+            return Ok(HttpResponse::BadRequest().body(format!("Re-key token generation failed: {}", error)));
+        },
+        Err(error) => {
+            job.remove();
+            return Ok(queue_error(error));
+        },
+    };
+    let new_secret_key_file = match secret_key_file(&new_secret_key, passphrase) {
+        Ok(new_secret_key_file) => new_secret_key_file,
+        Err(error) => {
+            eprintln!("Failed to wrap secret key: {}", error);
+            job.remove();
+            return Ok(HttpResponse::InternalServerError().body("Re-key token generation failed"));
+        },
+    };
 
-    // Will safely remove the temp_decrypted_input files after the decrypt_file function is run
-    let _ = remove_file(&encrypted_matrix_file_path);
-    let _ = remove_file(&secret_key_file_path);
-    let _ = remove_file(&public_key_file_path);
+    // Like /encrypt, the new secret key goes back in the archive and is never written to the job folder
+    let archive = tar_stream(vec![
+        ArchiveEntry::File("rekey_token.key".to_string(), job.output_path("rekey_token.key").into()),
+        ArchiveEntry::Bytes("new_secret_key.key".to_string(), new_secret_key_file),
+        ArchiveEntry::File("new_public_key.key".to_string(), job.output_path("new_public_key.key").into()),
+    ]);
+    Ok(HttpResponse::Ok()
+        .insert_header(("X-Job-Id", job.id.clone()))
+        .insert_header(ContentType::octet_stream())
+        .insert_header(ContentDisposition::attachment("rekey_files.tar"))
+        .streaming(chunk_stream(archive)))
+}
 
+#[derive(MultipartForm)]
+struct UploadRekeyForm {
+    #[multipart(rename = "fileInputRekeyMatrix")]
+    encrypted_matrix_file: TempFile,
 
-    match decryption_result {
-        Ok(_) => Ok(HttpResponse::Ok().body("File decrypted successfully")),
-        Err(_) => Ok(HttpResponse::InternalServerError().body("Encryption failed")),
+    #[multipart(rename = "fileInputRekeyToken")]
+    token_file: TempFile,
+}
+
+#[post("/rekey")]
+async fn rekey_handler(user: User, MultipartForm(upload_rekey_form): MultipartForm<UploadRekeyForm>, limits: web::Data<LimitsConfig>, queue: web::Data<JobQueue>, audit: web::Data<AuditLog>) -> Result<HttpResponse> {
+    if let Some(response) = oversized_file(&limits, &[&upload_rekey_form.encrypted_matrix_file, &upload_rekey_form.token_file]) {
+        return Ok(response);
     }
-}
\ No newline at end of file
+
+    let job = match create_job(&user) {
+        Ok(job) => job,
+        Err(response) => return Ok(response),
+    };
+    let encrypted_matrix_file_path = match persist_upload(&job, upload_rekey_form.encrypted_matrix_file, "encrypted_matrix.csv") {
+        Ok(path) => path,
+        Err(response) => return Ok(response),
+    };
+    let token_file_path = match persist_upload(&job, upload_rekey_form.token_file, "rekey_token.key") {
+        Ok(path) => path,
+        Err(response) => return Ok(response),
+    };
+    let output_path = job.output_path("rekeyed_matrix.csv");
+
+    // The matrix is never decrypted, only multiplied by the token, on the job queue as it can be large
+    let owner = user.name.clone();
+    let result = queue.run(&job, &user.name, move |_| {
+        let mut event = AuditEvent::start(&owner, Operation::Rekey);
+        event.input_file(&encrypted_matrix_file_path);
+        event.key(read_key_fingerprint(&encrypted_matrix_file_path).ok().flatten());
+        let result = lattice_rekey_csv(&encrypted_matrix_file_path, &token_file_path, &output_path);
+        audit.record(event, &result);
+        let _ = remove_file(&encrypted_matrix_file_path);
+        let _ = secure_delete(std::path::Path::new(&token_file_path));
+        result.map_err(|error| error.to_string())
+    }).await;
+    match result {
+        Ok(Ok(_)) => Ok(job_response(&job, "Re-encryption successful")),
+        Ok(Err(error)) => {
+            job.remove();
+            info!("Re-encryption has failed: {:?}", error);
+            Ok(HttpResponse::BadRequest().body(format!("Re-encryption failed: {}", error)))
+        },
+        Err(error) => {
+            job.remove();
+            Ok(queue_error(error))
+        },
+    }
+}
@@ -0,0 +1,180 @@
+pub mod lattice_metrics {
+    use std::collections::BTreeMap;
+    use std::error::Error;
+    use std::fmt::{self, Write};
+    use std::io;
+    use std::sync::Mutex;
+    use std::time::Duration;
+
+    // Seconds, from a small key generation to a large file
+    const DURATION_BUCKETS: &[f64] = &[0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0, 60.0, 300.0];
+    // Rows or columns of a matrix. Byte matrices have one column per byte of the widest CSV row.
+    const DIMENSION_BUCKETS: &[f64] = &[1.0, 4.0, 16.0, 64.0, 256.0, 1024.0, 4096.0, 16384.0, 65536.0, 262144.0, 1048576.0];
+    // Random matrices drawn before one is invertible
+    const ATTEMPT_BUCKETS: &[f64] = &[1.0, 2.0, 3.0, 5.0, 10.0, 20.0, 50.0];
+
+    enum Kind {
+        Counter,
+        Histogram(&'static [f64]),
+    }
+
+    // A metric family, one line of HELP and TYPE followed by a series per set of labels
+    pub struct Metric {
+        name: &'static str,
+        help: &'static str,
+        kind: Kind,
+    }
+
+    pub const HTTP_REQUESTS: Metric = Metric { name: "lattice_http_requests_total", help: "HTTP requests by route pattern and status code", kind: Kind::Counter };
+    pub const HTTP_DURATION: Metric = Metric { name: "lattice_http_request_duration_seconds", help: "Time to answer an HTTP request by route pattern", kind: Kind::Histogram(DURATION_BUCKETS) };
+    pub const OPERATIONS: Metric = Metric { name: "lattice_operations_total", help: "Cryptographic operations by operation and result", kind: Kind::Counter };
+    pub const OPERATION_FAILURES: Metric = Metric { name: "lattice_operation_failures_total", help: "Failed cryptographic operations by operation and error kind", kind: Kind::Counter };
+    pub const OPERATION_DURATION: Metric = Metric { name: "lattice_operation_duration_seconds", help: "Time taken by a cryptographic operation, including time waiting in the job queue", kind: Kind::Histogram(DURATION_BUCKETS) };
+    pub const BYTES_PROCESSED: Metric = Metric { name: "lattice_bytes_processed_total", help: "Bytes of CSV, encrypted matrix or key given to cryptographic operations", kind: Kind::Counter };
+    pub const MATRIX_ROWS: Metric = Metric { name: "lattice_matrix_rows", help: "Rows of the matrices encrypted or decrypted", kind: Kind::Histogram(DIMENSION_BUCKETS) };
+    pub const MATRIX_COLUMNS: Metric = Metric { name: "lattice_matrix_columns", help: "Columns of the matrices encrypted or decrypted, which is the key size", kind: Kind::Histogram(DIMENSION_BUCKETS) };
+    pub const KEYGEN_ATTEMPTS: Metric = Metric { name: "lattice_keygen_attempts", help: "Random matrices drawn per key generation until one was invertible", kind: Kind::Histogram(ATTEMPT_BUCKETS) };
+    pub const KEYGEN_SINGULAR: Metric = Metric { name: "lattice_keygen_singular_total", help: "Random matrices drawn for a key and thrown away as singular", kind: Kind::Counter };
+    pub const RATE_LIMITED: Metric = Metric { name: "lattice_rate_limited_total", help: "Requests turned away with 429 by endpoint and reason, rate or concurrency", kind: Kind::Counter };
+
+    // In the order they are written to /metrics
+    const METRICS: &[&Metric] = &[
+        &HTTP_REQUESTS, &HTTP_DURATION, &OPERATIONS, &OPERATION_FAILURES, &OPERATION_DURATION, &BYTES_PROCESSED,
+        &MATRIX_ROWS, &MATRIX_COLUMNS, &KEYGEN_ATTEMPTS, &KEYGEN_SINGULAR, &RATE_LIMITED,
+    ];
+
+    type Labels = Vec<(&'static str, String)>;
+
+    enum Value {
+        Counter(f64),
+        // Count per bucket, not cumulative, then the sum and count of every observation
+        Histogram(Vec<u64>, f64, u64),
+    }
+
+    // Every series recorded since the server started, by metric name and labels
+    static SERIES: Mutex<BTreeMap<&'static str, BTreeMap<Labels, Value>>> = Mutex::new(BTreeMap::new());
+
+    fn update(metric: &'static Metric, labels: &[(&'static str, &str)], value: f64) {
+        let labels = labels.iter().map(|(name, value)| (*name, value.to_string())).collect();
+        let mut series = SERIES.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        let entry = series.entry(metric.name).or_default().entry(labels).or_insert_with(|| match metric.kind {
+            Kind::Counter => Value::Counter(0.0),
+            Kind::Histogram(bounds) => Value::Histogram(vec![0; bounds.len()], 0.0, 0),
+        });
+        match (entry, &metric.kind) {
+            (Value::Counter(total), _) => *total += value,
+            (Value::Histogram(buckets, sum, count), Kind::Histogram(bounds)) => {
+                if let Some(bucket) = bounds.iter().position(|bound| value <= *bound) {
+                    buckets[bucket] += 1;
+                }
+                *sum += value;
+                *count += 1;
+            }
+            (Value::Histogram(..), Kind::Counter) => {}
+        }
+    }
+
+    pub fn increment(metric: &'static Metric, labels: &[(&'static str, &str)]) {
+        add(metric, labels, 1.0);
+    }
+
+    pub fn add(metric: &'static Metric, labels: &[(&'static str, &str)], value: f64) {
+        update(metric, labels, value);
+    }
+
+    pub fn observe(metric: &'static Metric, labels: &[(&'static str, &str)], value: f64) {
+        update(metric, labels, value);
+    }
+
+    pub fn observe_duration(metric: &'static Metric, labels: &[(&'static str, &str)], duration: Duration) {
+        update(metric, labels, duration.as_secs_f64());
+    }
+
+    // The size of a matrix an operation worked on
+    pub fn observe_matrix(operation: &str, rows: usize, columns: usize) {
+        observe(&MATRIX_ROWS, &[("operation", operation)], rows as f64);
+        observe(&MATRIX_COLUMNS, &[("operation", operation)], columns as f64);
+    }
+
+    // An answered HTTP request. Streamed responses are timed to their headers, not to their last byte.
+    pub fn observe_request(route: &str, status: &str, duration: Duration) {
+        increment(&HTTP_REQUESTS, &[("route", route), ("status", status)]);
+        observe_duration(&HTTP_DURATION, &[("route", route)], duration);
+    }
+
+    // A short, fixed name for an error, used as the kind label of lattice_operation_failures_total. Messages can't be
+    // used as they would make a new series for every file name or number in them.
+    pub trait ErrorKind: fmt::Display {
+        fn kind(&self) -> &'static str;
+    }
+
+    impl ErrorKind for Box<dyn Error> {
+        fn kind(&self) -> &'static str {
+            match self.downcast_ref::<io::Error>() {
+                Some(error) if error.kind() != io::ErrorKind::Other => "io_error",
+                _ => "failed",
+            }
+        }
+    }
+
+    // Label values may hold any text, so backslashes, quotes and newlines are escaped
+    fn escape(value: &str) -> String {
+        value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+    }
+
+    fn write_labels(out: &mut String, labels: &Labels, le: Option<&str>) {
+        let mut pairs: Vec<String> = labels.iter().map(|(name, value)| format!("{}=\"{}\"", name, escape(value))).collect();
+        if let Some(le) = le {
+            pairs.push(format!("le=\"{}\"", le));
+        }
+        if !pairs.is_empty() {
+            let _ = write!(out, "{{{}}}", pairs.join(","));
+        }
+    }
+
+    // Every metric in the Prometheus text exposition format, version 0.0.4
+    pub fn render() -> String {
+        let series = SERIES.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        let mut out = String::new();
+        for metric in METRICS {
+            let kind = match metric.kind {
+                Kind::Counter => "counter",
+                Kind::Histogram(_) => "histogram",
+            };
+            let _ = writeln!(out, "# HELP {} {}", metric.name, metric.help);
+            let _ = writeln!(out, "# TYPE {} {}", metric.name, kind);
+            let Some(values) = series.get(metric.name) else {
+                continue;
+            };
+            for (labels, value) in values {
+                match (value, &metric.kind) {
+                    (Value::Counter(total), _) => {
+                        out.push_str(metric.name);
+                        write_labels(&mut out, labels, None);
+                        let _ = writeln!(out, " {}", total);
+                    }
+                    (Value::Histogram(buckets, sum, count), Kind::Histogram(bounds)) => {
+                        let mut cumulative = 0;
+                        for (bound, bucket) in bounds.iter().zip(buckets) {
+                            cumulative += bucket;
+                            let _ = write!(out, "{}_bucket", metric.name);
+                            write_labels(&mut out, labels, Some(&bound.to_string()));
+                            let _ = writeln!(out, " {}", cumulative);
+                        }
+                        let _ = write!(out, "{}_bucket", metric.name);
+                        write_labels(&mut out, labels, Some("+Inf"));
+                        let _ = writeln!(out, " {}", count);
+                        let _ = write!(out, "{}_sum", metric.name);
+                        write_labels(&mut out, labels, None);
+                        let _ = writeln!(out, " {}", sum);
+                        let _ = write!(out, "{}_count", metric.name);
+                        write_labels(&mut out, labels, None);
+                        let _ = writeln!(out, " {}", count);
+                    }
+                    (Value::Histogram(..), Kind::Counter) => {}
+                }
+            }
+        }
+        out
+    }
+}
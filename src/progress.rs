@@ -0,0 +1,64 @@
+pub mod lattice_progress {
+    use std::error::Error;
+    use serde::Serialize;
+
+    // Stages of encryption, decryption and key generation, reported in this order. Decryption has no key
+    // generation stage.
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+    #[serde(rename_all = "snake_case")]
+    pub enum Stage {
+        // Reading the input to size the key, or reading the key
+        Parsing,
+        // Drawing a random matrix and inverting it, O(n³) in the key size
+        KeyGeneration,
+        Encrypting,
+        Decrypting,
+        Writing,
+    }
+
+    impl Stage {
+        // The part of the whole operation a stage covers, in percent
+        fn range(self) -> (u64, u64) {
+            match self {
+                Stage::Parsing => (0, 20),
+                Stage::KeyGeneration => (20, 40),
+                Stage::Encrypting | Stage::Decrypting => (40, 95),
+                Stage::Writing => (95, 100),
+            }
+        }
+
+        // Percent of the whole operation done when done of total units of this stage are
+        pub fn percent(self, done: u64, total: u64) -> u8 {
+            let (start, end) = self.range();
+            let share = ((end - start) * done.min(total)).checked_div(total).unwrap_or(0);
+            (start + share) as u8
+        }
+    }
+
+    // Watches a long running operation. done and total count the units of the current stage, such as bytes or
+    // rows, with total 0 when it is not known.
+    pub trait Progress {
+        fn report(&self, stage: Stage, done: u64, total: u64);
+
+        // Checked between units of work, an operation stops with an error once this returns true
+        fn is_cancelled(&self) -> bool {
+            false
+        }
+    }
+
+    // For callers that don't watch
+    pub struct NoProgress;
+
+    impl Progress for NoProgress {
+        fn report(&self, _: Stage, _: u64, _: u64) {}
+    }
+
+    pub const CANCELLED: &str = "The operation was cancelled";
+
+    pub fn check_cancelled(progress: &dyn Progress) -> Result<(), Box<dyn Error>> {
+        if progress.is_cancelled() {
+            return Err(CANCELLED.into());
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,392 @@
+pub mod lattice_queue {
+    use std::collections::HashMap;
+    use std::fmt;
+    use std::io;
+    use std::panic::{catch_unwind, AssertUnwindSafe};
+    use std::sync::atomic::{AtomicBool, AtomicU8, Ordering};
+    use std::sync::mpsc::{self, Receiver, SyncSender, TrySendError};
+    use std::sync::{Arc, Mutex, MutexGuard};
+    use std::thread;
+    use std::time::{Duration, Instant};
+    use futures::channel::oneshot;
+    use serde::Serialize;
+    use crate::jobs::lattice_jobs::Job;
+    use crate::progress::lattice_progress::{Progress, Stage};
+
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+    #[serde(rename_all = "lowercase")]
+    pub enum JobState {
+        Queued,
+        Running,
+        // Cancelled while running, the task has not noticed yet
+        Cancelling,
+        Succeeded,
+        Failed,
+        Cancelled,
+        // The queue has no record of the job, because it finished longer than status_ttl ago or before a restart
+        Unknown,
+    }
+
+    impl JobState {
+        // Whether the job has stopped for good
+        pub fn is_finished(self) -> bool {
+            matches!(self, JobState::Succeeded | JobState::Failed | JobState::Cancelled | JobState::Unknown)
+        }
+    }
+
+    #[derive(Debug, Clone, PartialEq, Serialize)]
+    pub struct JobStatus {
+        pub state: JobState,
+        // Percent done, 100 once the job has succeeded
+        pub progress: u8,
+        // What a running job is doing
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub stage: Option<Stage>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub error: Option<String>,
+    }
+
+    // Handed to a running task so it can report how far it has got and notice when it is cancelled
+    #[derive(Clone, Default)]
+    pub struct TaskContext {
+        progress: Arc<AtomicU8>,
+        stage: Arc<Mutex<Option<Stage>>>,
+        cancelled: Arc<AtomicBool>,
+    }
+
+    impl TaskContext {
+        pub fn set_progress(&self, percent: u8) {
+            self.progress.store(percent.min(100), Ordering::Relaxed);
+        }
+
+        pub fn progress(&self) -> u8 {
+            self.progress.load(Ordering::Relaxed)
+        }
+
+        pub fn stage(&self) -> Option<Stage> {
+            *lock(&self.stage)
+        }
+
+        pub fn is_cancelled(&self) -> bool {
+            self.cancelled.load(Ordering::Relaxed)
+        }
+    }
+
+    impl Progress for TaskContext {
+        fn report(&self, stage: Stage, done: u64, total: u64) {
+            *lock(&self.stage) = Some(stage);
+            self.set_progress(stage.percent(done, total));
+        }
+
+        fn is_cancelled(&self) -> bool {
+            TaskContext::is_cancelled(self)
+        }
+    }
+
+    #[derive(Debug)]
+    pub enum QueueError {
+        // Every worker is busy and max_queued jobs are already waiting
+        Full,
+        Cancelled,
+        Failed(String),
+    }
+
+    impl fmt::Display for QueueError {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            match self {
+                QueueError::Full => write!(f, "The server is busy, try again later"),
+                QueueError::Cancelled => write!(f, "The job was cancelled"),
+                QueueError::Failed(message) => write!(f, "{}", message),
+            }
+        }
+    }
+
+    enum Outcome {
+        Succeeded,
+        Failed(String),
+        // Nobody is waiting for the result any more
+        Abandoned,
+    }
+
+    type Task = Box<dyn FnOnce(&TaskContext) -> Outcome + Send>;
+
+    struct Queued {
+        job_id: String,
+        owner: String,
+        context: TaskContext,
+        task: Task,
+    }
+
+    struct Entry {
+        owner: String,
+        state: JobState,
+        error: Option<String>,
+        context: TaskContext,
+        finished: Option<Instant>,
+    }
+
+    impl Entry {
+        fn status(&self) -> JobStatus {
+            let stage = if self.state.is_finished() { None } else { self.context.stage() };
+            JobStatus { state: self.state, progress: self.context.progress(), stage, error: self.error.clone() }
+        }
+    }
+
+    type Entries = Mutex<HashMap<String, Entry>>;
+
+    fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+        mutex.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    // Runs the heavy work of requests, encryption, decryption and key generation, on a fixed number of threads so
+    // it never holds up the web server's workers. The status of each job is kept in memory for status_ttl after it
+    // finishes, so a restart forgets it.
+    pub struct JobQueue {
+        sender: SyncSender<Queued>,
+        entries: Arc<Entries>,
+        status_ttl: Duration,
+    }
+
+    impl JobQueue {
+        // Starts the worker threads. At most max_queued jobs wait for a free worker, further jobs are turned away.
+        pub fn new(workers: usize, max_queued: usize, status_ttl: Duration) -> io::Result<JobQueue> {
+            let (sender, receiver) = mpsc::sync_channel(max_queued);
+            let receiver = Arc::new(Mutex::new(receiver));
+            let entries: Arc<Entries> = Arc::default();
+            for index in 0..workers.max(1) {
+                let (receiver, entries) = (receiver.clone(), entries.clone());
+                thread::Builder::new()
+                    .name(format!("lattice-job-{}", index))
+                    .spawn(move || work(&receiver, &entries))?;
+            }
+            Ok(JobQueue { sender, entries, status_ttl })
+        }
+
+        fn enqueue(&self, job: &Job, owner: &str, task: Task) -> Result<(), QueueError> {
+            let context = TaskContext::default();
+            let mut entries = lock(&self.entries);
+            let now = Instant::now();
+            entries.retain(|_, entry| entry.finished.is_none_or(|finished| now.duration_since(finished) < self.status_ttl));
+            // The entry is added under the same lock a worker needs to start the job, so it is always there first
+            match self.sender.try_send(Queued { job_id: job.id.clone(), owner: owner.to_string(), context: context.clone(), task }) {
+                Ok(()) => {}
+                Err(TrySendError::Full(_)) => return Err(QueueError::Full),
+                Err(TrySendError::Disconnected(_)) => return Err(QueueError::Failed("The job workers have stopped".to_string())),
+            }
+            entries.insert(job.id.clone(), Entry { owner: owner.to_string(), state: JobState::Queued, error: None, context, finished: None });
+            Ok(())
+        }
+
+        // Queues a task in the job and waits for its result without blocking the calling thread. When the returned
+        // future is dropped before the result arrives, the job is cancelled: a queued task never starts and its
+        // files are removed, a running one stops at its next check of the context.
+        pub async fn run<T, E>(&self, job: &Job, owner: &str, task: impl FnOnce(&TaskContext) -> Result<T, E> + Send + 'static) -> Result<Result<T, E>, QueueError>
+        where
+            T: Send + 'static,
+            E: fmt::Display + Send + 'static,
+        {
+            let (sender, receiver) = oneshot::channel();
+            self.enqueue(job, owner, Box::new(move |context| {
+                if sender.is_canceled() {
+                    return Outcome::Abandoned;
+                }
+                let result = task(context);
+                let outcome = match &result {
+                    Ok(_) => Outcome::Succeeded,
+                    Err(error) => Outcome::Failed(error.to_string()),
+                };
+                if context.is_cancelled() || sender.send(result).is_err() {
+                    return Outcome::Abandoned;
+                }
+                outcome
+            }))?;
+            let mut waiting = CancelOnDrop { entries: &self.entries, job_id: &job.id, owner, armed: true };
+            let received = receiver.await;
+            waiting.armed = false;
+            match received {
+                Ok(result) => Ok(result),
+                // The task never sent a result, because it was cancelled or panicked
+                Err(_) => Err(self.status(&job.id).and_then(|status| status.error).map_or(QueueError::Cancelled, QueueError::Failed)),
+            }
+        }
+
+        // Queues a task in the job and returns at once. The task leaves its results in the job's output folder.
+        pub fn submit<E: fmt::Display>(&self, job: &Job, owner: &str, task: impl FnOnce(&TaskContext) -> Result<(), E> + Send + 'static) -> Result<(), QueueError> {
+            self.enqueue(job, owner, Box::new(move |context| match task(context) {
+                Ok(()) => Outcome::Succeeded,
+                Err(error) => Outcome::Failed(error.to_string()),
+            }))
+        }
+
+        // Status of a job that went through the queue, None for other jobs and ones that finished long ago
+        pub fn status(&self, job_id: &str) -> Option<JobStatus> {
+            lock(&self.entries).get(job_id).map(Entry::status)
+        }
+
+        // Status of a user's job, also after a failed or cancelled job's files are gone
+        pub fn owned_status(&self, job_id: &str, owner: &str) -> Option<JobStatus> {
+            lock(&self.entries).get(job_id).filter(|entry| entry.owner == owner).map(Entry::status)
+        }
+
+        // Whether a job is queued or running, so its files are still in use
+        pub fn is_busy(&self, job_id: &str) -> bool {
+            lock(&self.entries).get(job_id).is_some_and(|entry| !entry.state.is_finished())
+        }
+
+        // Status of any job, Unknown for one the queue has no record of
+        pub fn status_or_unknown(&self, job_id: &str) -> JobStatus {
+            self.status(job_id).unwrap_or(JobStatus { state: JobState::Unknown, progress: 0, stage: None, error: None })
+        }
+
+        // Cancels a queued or running job of a user and returns its new status, None when it is neither. A queued
+        // job is dropped and its files removed at once; a running one is stopped when its task next checks, or
+        // when it ends, and its files removed then.
+        pub fn cancel(&self, job_id: &str, owner: &str) -> Option<JobStatus> {
+            cancel_entry(&self.entries, job_id, owner)
+        }
+    }
+
+    // Cancels the job run is waiting for when the wait is dropped before the job has sent its result
+    struct CancelOnDrop<'a> {
+        entries: &'a Entries,
+        job_id: &'a str,
+        owner: &'a str,
+        // Cleared once the result has arrived
+        armed: bool,
+    }
+
+    impl Drop for CancelOnDrop<'_> {
+        fn drop(&mut self) {
+            if self.armed {
+                cancel_entry(self.entries, self.job_id, self.owner);
+            }
+        }
+    }
+
+    fn cancel_entry(entries: &Entries, job_id: &str, owner: &str) -> Option<JobStatus> {
+        let mut entries = lock(entries);
+        let entry = entries.get_mut(job_id).filter(|entry| entry.owner == owner)?;
+        match entry.state {
+            JobState::Queued => {
+                entry.context.cancelled.store(true, Ordering::Relaxed);
+                entry.state = JobState::Cancelled;
+                entry.finished = Some(Instant::now());
+                if let Some(job) = Job::open(job_id, owner) {
+                    job.remove();
+                }
+            }
+            JobState::Running => {
+                entry.context.cancelled.store(true, Ordering::Relaxed);
+                entry.state = JobState::Cancelling;
+            }
+            _ => return None,
+        }
+        Some(entry.status())
+    }
+
+    fn work(receiver: &Mutex<Receiver<Queued>>, entries: &Entries) {
+        loop {
+            // Idle workers take turns waiting, so each job goes to exactly one of them
+            let Ok(Queued { job_id, owner, context, task }) = lock(receiver).recv() else {
+                return;
+            };
+            {
+                let mut entries = lock(entries);
+                if context.is_cancelled() {
+                    continue;
+                }
+                if let Some(entry) = entries.get_mut(&job_id) {
+                    entry.state = JobState::Running;
+                }
+            }
+
+            // A panicking task fails its job rather than taking the worker down with it
+            let outcome = catch_unwind(AssertUnwindSafe(|| task(&context))).unwrap_or_else(|_| {
+                log::error!("Job {} panicked", job_id);
+                Outcome::Failed("The job failed unexpectedly".to_string())
+            });
+            let (state, error) = match outcome {
+                _ if context.is_cancelled() => (JobState::Cancelled, None),
+                Outcome::Succeeded => (JobState::Succeeded, None),
+                Outcome::Failed(error) => (JobState::Failed, Some(error)),
+                Outcome::Abandoned => (JobState::Cancelled, None),
+            };
+            // Only a successful job has files worth keeping
+            if state == JobState::Succeeded {
+                context.set_progress(100);
+            } else if let Some(job) = Job::open(&job_id, &owner) {
+                job.remove();
+            }
+
+            let mut entries = lock(entries);
+            if let Some(entry) = entries.get_mut(&job_id) {
+                entry.state = state;
+                entry.error = error;
+                entry.finished = Some(Instant::now());
+            }
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+        use futures::FutureExt;
+        use crate::jobs::lattice_jobs::use_test_jobs_dir;
+
+        fn wait_until_finished(queue: &JobQueue, job_id: &str) -> JobState {
+            for _ in 0..500 {
+                match queue.status(job_id) {
+                    Some(status) if status.state.is_finished() => return status.state,
+                    _ => thread::sleep(Duration::from_millis(10)),
+                }
+            }
+            panic!("job {} did not finish", job_id);
+        }
+
+        #[test]
+        fn dropping_the_wait_cancels_a_queued_job() {
+            use_test_jobs_dir();
+            let queue = JobQueue::new(1, 4, Duration::from_secs(60)).unwrap();
+            // Holds the only worker so the next job stays queued
+            let (release, released) = mpsc::channel::<()>();
+            let busy = Job::create("alice").unwrap();
+            queue.submit(&busy, "alice", move |_| released.recv().map_err(|error| error.to_string())).unwrap();
+
+            let job = Job::create("alice").unwrap();
+            assert!(queue.run(&job, "alice", |_| Ok::<_, String>(())).now_or_never().is_none());
+            assert_eq!(queue.status(&job.id).map(|status| status.state), Some(JobState::Cancelled));
+            assert!(Job::open(&job.id, "alice").is_none());
+
+            release.send(()).unwrap();
+            assert_eq!(wait_until_finished(&queue, &busy.id), JobState::Succeeded);
+            busy.remove();
+        }
+
+        #[test]
+        fn dropping_the_wait_cancels_a_running_job() {
+            use_test_jobs_dir();
+            let queue = JobQueue::new(1, 4, Duration::from_secs(60)).unwrap();
+            let (started, has_started) = mpsc::channel();
+            let job = Job::create("alice").unwrap();
+            let mut waiting = Box::pin(queue.run(&job, "alice", move |context| {
+                let _ = started.send(());
+                while !context.is_cancelled() {
+                    thread::sleep(Duration::from_millis(1));
+                }
+                Err::<(), _>("stopped")
+            }));
+            assert!((&mut waiting).now_or_never().is_none());
+            has_started.recv().unwrap();
+            drop(waiting);
+
+            assert_eq!(wait_until_finished(&queue, &job.id), JobState::Cancelled);
+            assert!(Job::open(&job.id, "alice").is_none());
+        }
+
+        #[test]
+        fn unknown_jobs_are_not_reported_as_succeeded() {
+            let queue = JobQueue::new(1, 4, Duration::from_secs(60)).unwrap();
+            assert_eq!(queue.status_or_unknown("0123456789abcdef0123456789abcdef").state, JobState::Unknown);
+        }
+    }
+}
@@ -0,0 +1,285 @@
+pub mod lattice_ratelimit {
+    use std::collections::HashMap;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::{Arc, Mutex};
+    use std::time::{Duration, Instant};
+    use actix_web::dev::ServiceRequest;
+    use actix_web::http::Method;
+    use actix_web::web;
+    use crate::auth::lattice_auth::{Auth, ANONYMOUS_USER};
+    use crate::config::lattice_config::{EndpointLimit, RateLimitConfig};
+    use crate::metrics::lattice_metrics::{self, RATE_LIMITED};
+
+    // How long a client turned away because every place is taken is asked to wait
+    const BUSY_RETRY_AFTER: Duration = Duration::from_secs(1);
+
+    // Buckets that have filled back up are forgotten this often, so clients that have gone don't pile up
+    const PRUNE_INTERVAL: Duration = Duration::from_secs(60);
+
+    // The endpoints that run a cryptographic operation or check a password, each limited on its own
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+    pub enum Endpoint {
+        Keygen,
+        Encrypt,
+        Decrypt,
+        Compute,
+        Rekey,
+        Verify,
+        Inspect,
+        Login,
+    }
+
+    impl Endpoint {
+        // The limited endpoint a request is for, the web and API versions alike, or None when it is not limited
+        pub fn of(method: &Method, path: &str) -> Option<Endpoint> {
+            if method != Method::POST {
+                return None;
+            }
+            match path.strip_prefix("/api/v1").unwrap_or(path) {
+                "/keygen" => Some(Endpoint::Keygen),
+                "/encrypt" => Some(Endpoint::Encrypt),
+                "/decrypt" => Some(Endpoint::Decrypt),
+                "/compute" => Some(Endpoint::Compute),
+                "/rekey" | "/rekey/token" => Some(Endpoint::Rekey),
+                "/verify" => Some(Endpoint::Verify),
+                "/inspect" => Some(Endpoint::Inspect),
+                "/login" => Some(Endpoint::Login),
+                _ => None,
+            }
+        }
+
+        pub fn name(&self) -> &'static str {
+            match self {
+                Endpoint::Keygen => "keygen",
+                Endpoint::Encrypt => "encrypt",
+                Endpoint::Decrypt => "decrypt",
+                Endpoint::Compute => "compute",
+                Endpoint::Rekey => "rekey",
+                Endpoint::Verify => "verify",
+                Endpoint::Inspect => "inspect",
+                Endpoint::Login => "login",
+            }
+        }
+    }
+
+    struct Bucket {
+        tokens: f64,
+        updated: Instant,
+    }
+
+    impl Bucket {
+        // Adds the tokens earned since the last request, up to the burst
+        fn refill(&mut self, limit: EndpointLimit, now: Instant) {
+            let earned = now.duration_since(self.updated).as_secs_f64() * limit.per_minute as f64 / 60.0;
+            self.tokens = (self.tokens + earned).min(limit.burst as f64);
+            self.updated = now;
+        }
+    }
+
+    struct Buckets {
+        by_client: HashMap<(Endpoint, String), Bucket>,
+        pruned: Instant,
+    }
+
+    // Why a request was turned away, and how long the client should wait before trying again
+    pub struct Refused {
+        pub message: String,
+        pub retry_after: Duration,
+    }
+
+    // A place among the requests being served at once, given back when dropped. Requests for a background job drop it
+    // once the job is queued, so only the requests answered directly are counted.
+    pub struct Permit(Option<Arc<AtomicUsize>>);
+
+    impl Drop for Permit {
+        fn drop(&mut self) {
+            if let Some(in_flight) = &self.0 {
+                in_flight.fetch_sub(1, Ordering::SeqCst);
+            }
+        }
+    }
+
+    pub struct RateLimiter {
+        config: RateLimitConfig,
+        buckets: Mutex<Buckets>,
+        in_flight: Arc<AtomicUsize>,
+    }
+
+    impl RateLimiter {
+        pub fn new(config: &RateLimitConfig) -> RateLimiter {
+            RateLimiter {
+                config: config.clone(),
+                buckets: Mutex::new(Buckets { by_client: HashMap::new(), pruned: Instant::now() }),
+                in_flight: Arc::new(AtomicUsize::new(0)),
+            }
+        }
+
+        fn limit(&self, endpoint: Endpoint) -> EndpointLimit {
+            match endpoint {
+                Endpoint::Keygen => self.config.keygen,
+                Endpoint::Encrypt => self.config.encrypt,
+                Endpoint::Decrypt => self.config.decrypt,
+                Endpoint::Compute => self.config.compute,
+                Endpoint::Rekey => self.config.rekey,
+                Endpoint::Verify => self.config.verify,
+                Endpoint::Inspect => self.config.inspect,
+                Endpoint::Login => self.config.login,
+            }
+        }
+
+        // Lets a client's request in, holding its place among the requests being served at once until the permit is
+        // dropped, or turns it away when the client has no tokens left or every place is taken
+        pub fn admit(&self, endpoint: Endpoint, client: &str) -> Result<Permit, Refused> {
+            self.admit_at(endpoint, client, Instant::now())
+        }
+
+        fn admit_at(&self, endpoint: Endpoint, client: &str, now: Instant) -> Result<Permit, Refused> {
+            if !self.config.enabled {
+                return Ok(Permit(None));
+            }
+            let permit = self.acquire().ok_or_else(|| {
+                lattice_metrics::increment(&RATE_LIMITED, &[("endpoint", endpoint.name()), ("reason", "concurrency")]);
+                Refused { message: "The server is serving as many requests as it can, try again shortly".to_string(), retry_after: BUSY_RETRY_AFTER }
+            })?;
+            self.take_token(endpoint, client, now).map_err(|retry_after| {
+                lattice_metrics::increment(&RATE_LIMITED, &[("endpoint", endpoint.name()), ("reason", "rate")]);
+                let limit = self.limit(endpoint);
+                Refused {
+                    message: format!("Too many {} requests, the limit is {} a minute with bursts of {}", endpoint.name(), limit.per_minute, limit.burst),
+                    retry_after,
+                }
+            })?;
+            Ok(permit)
+        }
+
+        fn acquire(&self) -> Option<Permit> {
+            let max = self.config.max_concurrent;
+            self.in_flight.fetch_update(Ordering::SeqCst, Ordering::SeqCst, |count| (max == 0 || count < max).then_some(count + 1)).ok()?;
+            Some(Permit(Some(self.in_flight.clone())))
+        }
+
+        // Takes one token from the client's bucket, or returns how long until one is earned
+        fn take_token(&self, endpoint: Endpoint, client: &str, now: Instant) -> Result<(), Duration> {
+            let limit = self.limit(endpoint);
+            if limit.per_minute == 0 {
+                return Ok(());
+            }
+            let mut buckets = self.buckets.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+            if now.duration_since(buckets.pruned) >= PRUNE_INTERVAL {
+                buckets.by_client.retain(|(endpoint, _), bucket| {
+                    bucket.refill(self.limit(*endpoint), now);
+                    bucket.tokens < self.limit(*endpoint).burst as f64
+                });
+                buckets.pruned = now;
+            }
+
+            let bucket = buckets.by_client.entry((endpoint, client.to_string()))
+                .or_insert(Bucket { tokens: limit.burst as f64, updated: now });
+            bucket.refill(limit, now);
+            if bucket.tokens >= 1.0 {
+                bucket.tokens -= 1.0;
+                return Ok(());
+            }
+            Err(Duration::from_secs_f64((1.0 - bucket.tokens) * 60.0 / limit.per_minute as f64))
+        }
+    }
+
+    // Clients are told apart by the user they authenticate as, so users behind one address don't share a limit, and
+    // by IP address when there is no user. The address is the connection's, as forwarding headers can be made up.
+    pub fn client_of(request: &ServiceRequest) -> String {
+        let user = request.app_data::<web::Data<Auth>>().and_then(|auth| auth.authenticate(request.request()));
+        match user {
+            Some(user) if user.name != ANONYMOUS_USER => format!("user:{}", user.name),
+            _ => format!("ip:{}", request.peer_addr().map(|address| address.ip().to_string()).unwrap_or_default()),
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        fn limiter(per_minute: u32, burst: u32, max_concurrent: usize) -> RateLimiter {
+            let limit = EndpointLimit { per_minute, burst };
+            RateLimiter::new(&RateLimitConfig {
+                enabled: true, max_concurrent, keygen: limit, encrypt: limit, decrypt: limit, compute: limit, rekey: limit,
+                verify: limit, inspect: limit, login: limit,
+            })
+        }
+
+        #[test]
+        fn password_and_key_checking_endpoints_are_limited() {
+            assert_eq!(Endpoint::of(&Method::POST, "/login"), Some(Endpoint::Login));
+            assert_eq!(Endpoint::of(&Method::POST, "/api/v1/verify"), Some(Endpoint::Verify));
+            assert_eq!(Endpoint::of(&Method::POST, "/api/v1/inspect"), Some(Endpoint::Inspect));
+            assert_eq!(Endpoint::of(&Method::GET, "/login"), None);
+        }
+
+        fn retry_after(result: Result<Permit, Refused>) -> Duration {
+            match result {
+                Ok(_) => panic!("the request was let in"),
+                Err(refused) => refused.retry_after,
+            }
+        }
+
+        #[test]
+        fn burst_is_used_up_then_refused_with_retry_after() {
+            let limiter = limiter(30, 3, 0);
+            let now = Instant::now();
+            for _ in 0..3 {
+                assert!(limiter.admit_at(Endpoint::Encrypt, "ip:1", now).is_ok());
+            }
+            // 30 a minute earns a token every 2 seconds
+            assert_eq!(retry_after(limiter.admit_at(Endpoint::Encrypt, "ip:1", now)), Duration::from_secs(2));
+            // Other clients and other endpoints have buckets of their own
+            assert!(limiter.admit_at(Endpoint::Encrypt, "ip:2", now).is_ok());
+            assert!(limiter.admit_at(Endpoint::Decrypt, "ip:1", now).is_ok());
+        }
+
+        #[test]
+        fn tokens_are_earned_back_over_time() {
+            let limiter = limiter(60, 2, 0);
+            let now = Instant::now();
+            assert!(limiter.admit_at(Endpoint::Compute, "ip:1", now).is_ok());
+            assert!(limiter.admit_at(Endpoint::Compute, "ip:1", now).is_ok());
+            let wait = retry_after(limiter.admit_at(Endpoint::Compute, "ip:1", now + Duration::from_millis(250)));
+            assert!((wait.as_secs_f64() - 0.75).abs() < 1e-9, "{:?}", wait);
+            assert!(limiter.admit_at(Endpoint::Compute, "ip:1", now + Duration::from_secs(1)).is_ok());
+            assert!(limiter.admit_at(Endpoint::Compute, "ip:1", now + Duration::from_secs(1)).is_err());
+            // An idle client earns no more than its burst
+            let later = now + Duration::from_secs(3600);
+            assert!(limiter.admit_at(Endpoint::Compute, "ip:1", later).is_ok());
+            assert!(limiter.admit_at(Endpoint::Compute, "ip:1", later).is_ok());
+            assert!(limiter.admit_at(Endpoint::Compute, "ip:1", later).is_err());
+        }
+
+        #[test]
+        fn zero_per_minute_is_not_limited() {
+            let limiter = limiter(0, 1, 0);
+            let now = Instant::now();
+            for _ in 0..100 {
+                assert!(limiter.admit_at(Endpoint::Rekey, "ip:1", now).is_ok());
+            }
+        }
+
+        #[test]
+        fn places_are_given_back_when_permits_are_dropped() {
+            let limiter = limiter(0, 1, 2);
+            let now = Instant::now();
+            let first = limiter.admit_at(Endpoint::Keygen, "ip:1", now).ok();
+            let second = limiter.admit_at(Endpoint::Keygen, "ip:2", now).ok();
+            assert!(first.is_some() && second.is_some());
+            assert_eq!(retry_after(limiter.admit_at(Endpoint::Keygen, "ip:3", now)), BUSY_RETRY_AFTER);
+            drop(first);
+            assert!(limiter.admit_at(Endpoint::Keygen, "ip:3", now).is_ok());
+        }
+
+        #[test]
+        fn refused_requests_do_not_keep_a_place() {
+            let limiter = limiter(60, 1, 1);
+            let now = Instant::now();
+            drop(limiter.admit_at(Endpoint::Decrypt, "ip:1", now));
+            assert!(limiter.admit_at(Endpoint::Decrypt, "ip:1", now).is_err());
+            assert!(limiter.admit_at(Endpoint::Decrypt, "ip:2", now).is_ok());
+        }
+    }
+}
@@ -0,0 +1,160 @@
+pub mod lattice_rekey {
+    use std::error::Error;
+    use std::path::Path;
+    use nalgebra::DMatrix;
+    use log::info;
+    use std::fs::File;
+    use std::io::prelude::*;
+    use crate::encrypted::lattice_encrypt::{generate_key_matrices, key_fingerprint, write_matrix_file, write_numeric_matrix, write_public_key_file, KEY_FINGERPRINT_HEADER};
+    use crate::keyfile::lattice_keyfile::{read_secret_key, write_secret_key};
+    use crate::progress::lattice_progress::NoProgress;
+    use crate::secret::lattice_secret::SecretMatrix;
+    use crate::decrypted::lattice_decrypt::{is_numeric_matrix, process_file, read_header_value, read_key_fingerprint, read_numeric_matrix};
+
+    // Header line of a re-key token naming the fingerprint of the key it moves ciphertexts away from
+    pub const REKEY_FROM_HEADER: &str = "#rekey-from";
+
+    // Re-encryption works because encrypted = original * encrypt_matrix and the secret key is the inverse of
+    // encrypt_matrix: encrypted * old_secret_key * new_encrypt_matrix = original * new_encrypt_matrix.
+    // The token old_secret_key * new_encrypt_matrix moves ciphertexts to the new key without decrypting them.
+    // Whoever applies the token should not also hold the old secret key, since the two together give the new key.
+
+    // Generates a new key of the same size as the old secret key and the token that moves ciphertexts onto it.
+    // Returns (token, new_secret_key, new_public_key).
+    pub fn generate_rekey_token(old_secret_key: &DMatrix<f64>) -> Option<(DMatrix<f64>, SecretMatrix, f64)> {
+        if !old_secret_key.is_square() {
+            return None;
+        }
+        let (new_encrypt_matrix, new_secret_key) = generate_key_matrices(old_secret_key.nrows(), &NoProgress)?;
+        let token = old_secret_key * &*new_encrypt_matrix;
+        let new_public_key = new_secret_key.norm();
+        Some((token, new_secret_key, new_public_key))
+    }
+
+    // Moves an encrypted matrix onto the key the token was generated for
+    pub fn apply_rekey_token(encrypted_matrix: &DMatrix<f64>, token: &DMatrix<f64>) -> Result<DMatrix<f64>, Box<dyn Error>> {
+        if encrypted_matrix.ncols() != token.nrows() {
+            return Err(format!("Re-key token is {}x{} but the encrypted matrix has {} columns",
+                token.nrows(), token.ncols(), encrypted_matrix.ncols()).into());
+        }
+        Ok(encrypted_matrix * token)
+    }
+
+    // Writes a re-key token tagged with the fingerprints of the key it moves from and the key it moves to
+    pub fn write_rekey_token_file(file_path: &str, token: &DMatrix<f64>, old_secret_key: &DMatrix<f64>, new_secret_key: &DMatrix<f64>) -> Result<(), Box<dyn Error>> {
+        let mut file = File::create(file_path)?;
+        writeln!(file, "{} {}", REKEY_FROM_HEADER, key_fingerprint(old_secret_key))?;
+        writeln!(file, "{} {}", KEY_FINGERPRINT_HEADER, key_fingerprint(new_secret_key))?;
+        file.write_all(format!("{}", token).as_bytes())?;
+        Ok(())
+    }
+
+    // Reads an old secret key and writes rekey_token.key, new_secret_key.key and new_public_key.key to output_dir.
+    // The passphrase unwraps the old key and wraps the new one.
+    pub fn lattice_rekey_token_files(old_secret_key_path: &str, output_dir: &str, passphrase: Option<&str>) -> Result<String, Box<dyn Error>> {
+        // The new key of an earlier token may be the only copy of it
+        let new_secret_key_path = Path::new(output_dir).join("new_secret_key.key");
+        if new_secret_key_path.exists() {
+            return Err(format!("{} already exists; move it away or choose another output folder", new_secret_key_path.display()).into());
+        }
+        let new_secret_key = lattice_rekey_token_returning_secret_key(old_secret_key_path, output_dir, passphrase)?;
+        write_secret_key(&new_secret_key_path.to_string_lossy(), &new_secret_key, passphrase)?;
+        Ok("Re-key token generated successfully".to_string())
+    }
+
+    // Reads an old secret key and writes only rekey_token.key and new_public_key.key to output_dir. The new secret
+    // key is returned instead of written, so the web server can hand it to the user without ever storing it.
+    pub fn lattice_rekey_token_returning_secret_key(old_secret_key_path: &str, output_dir: &str, passphrase: Option<&str>) -> Result<SecretMatrix, Box<dyn Error>> {
+        let old_secret_key = read_secret_key(old_secret_key_path, passphrase)?;
+        let (token, new_secret_key, new_public_key) = match generate_rekey_token(&old_secret_key) {
+            Some(data) => data,
+            None => return Err("Failed to generate re-key token. The secret key must be a square invertible matrix".into()),
+        };
+
+        let output_dir = Path::new(output_dir);
+        write_rekey_token_file(&output_dir.join("rekey_token.key").to_string_lossy(), &token, &old_secret_key, &new_secret_key)?;
+        write_public_key_file(&output_dir.join("new_public_key.key").to_string_lossy(), new_public_key)?;
+        info!("Generated {}x{} re-key token", token.nrows(), token.ncols());
+
+        Ok(new_secret_key)
+    }
+
+    // Applies a re-key token to an encrypted matrix file, keeping its layout, and writes the result to output_path
+    pub fn lattice_rekey_csv(encrypted_matrix_path: &str, token_path: &str, output_path: &str) -> Result<String, Box<dyn Error>> {
+        let token = process_file(token_path)?;
+
+        // Refuse to move a matrix that was not encrypted with the key the token starts from
+        let matrix_fingerprint = read_key_fingerprint(encrypted_matrix_path)?;
+        if let (Some(from), Some(matrix_fingerprint)) = (read_header_value(token_path, REKEY_FROM_HEADER)?, &matrix_fingerprint) {
+            if from != *matrix_fingerprint {
+                return Err(format!("Re-key token starts from key {} but the matrix was encrypted with key {}", from, matrix_fingerprint).into());
+            }
+        }
+        let new_fingerprint = read_key_fingerprint(token_path)?;
+
+        if is_numeric_matrix(encrypted_matrix_path)? {
+            let (labels, encrypted_matrix) = read_numeric_matrix(encrypted_matrix_path)?;
+            let rekeyed_matrix = apply_rekey_token(&encrypted_matrix, &token)?;
+            write_numeric_matrix(output_path, &labels, &rekeyed_matrix, new_fingerprint.as_deref())?;
+        } else {
+            let encrypted_matrix = process_file(encrypted_matrix_path)?;
+            let rekeyed_matrix = apply_rekey_token(&encrypted_matrix, &token)?;
+            write_matrix_file(output_path, &rekeyed_matrix, new_fingerprint.as_deref())?;
+        }
+        info!("Re-encrypted {} under the new key", encrypted_matrix_path);
+
+        Ok("Re-encryption successful".to_string())
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+        use std::fs;
+        use crate::encrypted::lattice_encrypt::lattice_encrypt_csv_with_key;
+        use crate::decrypted::lattice_decrypt::lattice_decrypt_to_writer;
+
+        #[test]
+        fn rekeyed_matrix_decrypts_to_the_same_plaintext_under_the_new_key() {
+            let (encrypt_matrix, old_secret_key) = generate_key_matrices(4, &NoProgress).unwrap();
+            let plaintext = DMatrix::from_row_slice(2, 4, &[104.0, 105.0, 0.0, 0.0, 50.0, 48.0, 49.0, 0.0]);
+            let encrypted = &plaintext * &*encrypt_matrix;
+
+            let (token, new_secret_key, _) = generate_rekey_token(&old_secret_key).unwrap();
+            let rekeyed = apply_rekey_token(&encrypted, &token).unwrap();
+            assert_ne!(key_fingerprint(&new_secret_key), key_fingerprint(&old_secret_key));
+            let decrypted = &rekeyed * &*new_secret_key;
+            assert!(decrypted.iter().zip(plaintext.iter()).all(|(a, b)| (a - b).abs() < 1e-6), "{}", decrypted);
+
+            let error = apply_rekey_token(&DMatrix::zeros(2, 3), &token).unwrap_err().to_string();
+            assert_eq!(error, "Re-key token is 4x4 but the encrypted matrix has 3 columns");
+        }
+
+        #[test]
+        fn rekeyed_csv_decrypts_and_a_token_from_another_key_is_refused() {
+            let dir = std::env::temp_dir().join(format!("lattice-test-rekey-{}", std::process::id()));
+            let _ = fs::remove_dir_all(&dir);
+            fs::create_dir_all(&dir).unwrap();
+            let path = |name: &str| dir.join(name).to_string_lossy().to_string();
+            let csv = "name,score\nann,91\nbob,78\n";
+            fs::write(path("input.csv"), csv).unwrap();
+            let (_, old_secret_key) = generate_key_matrices(12, &NoProgress).unwrap();
+            write_secret_key(&path("old_secret_key.key"), &old_secret_key, None).unwrap();
+            lattice_encrypt_csv_with_key(&path("input.csv"), &dir.to_string_lossy(), &old_secret_key, &NoProgress).unwrap();
+
+            let new_secret_key = lattice_rekey_token_returning_secret_key(&path("old_secret_key.key"), &dir.to_string_lossy(), None).unwrap();
+            lattice_rekey_csv(&path("encrypted_matrix.csv"), &path("rekey_token.key"), &path("rekeyed_matrix.csv")).unwrap();
+            assert_eq!(read_key_fingerprint(path("rekeyed_matrix.csv")).unwrap(), Some(key_fingerprint(&new_secret_key)));
+            let mut output = Vec::new();
+            lattice_decrypt_to_writer(&path("rekeyed_matrix.csv"), &new_secret_key, &mut output, &NoProgress).unwrap();
+            assert_eq!(String::from_utf8(output).unwrap(), csv);
+
+            // The token moves away from the new key, not the key the matrix was encrypted with
+            write_secret_key(&path("other_secret_key.key"), &new_secret_key, None).unwrap();
+            lattice_rekey_token_returning_secret_key(&path("other_secret_key.key"), &dir.to_string_lossy(), None).unwrap();
+            let error = lattice_rekey_csv(&path("encrypted_matrix.csv"), &path("rekey_token.key"), &path("wrong_matrix.csv")).unwrap_err().to_string();
+            assert!(error.starts_with("Re-key token starts from key"), "{}", error);
+            assert!(!Path::new(&path("wrong_matrix.csv")).exists());
+            let _ = fs::remove_dir_all(&dir);
+        }
+    }
+}
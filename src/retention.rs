@@ -0,0 +1,160 @@
+pub mod lattice_retention {
+    use std::fs::{self, OpenOptions};
+    use std::io::{self, Write};
+    use std::path::{Path, PathBuf};
+    use std::thread;
+    use std::time::{Duration, SystemTime};
+    use log::{info, warn};
+    use crate::config::lattice_config::RetentionConfig;
+    use crate::jobs::lattice_jobs::{is_valid_job_id, jobs_dir, uploads_dir};
+
+    // What a file left on the server holds, which decides how long it is kept
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub enum Artifact {
+        // Uploaded files and job inputs
+        Upload,
+        // Encrypted matrices, public keys and other outputs that reveal nothing without a secret key
+        Output,
+        // Secret keys, key shares and re-key tokens
+        SecretKey,
+        // Decrypted CSVs
+        Plaintext,
+    }
+
+    // The decrypted CSV a decrypt job leaves for download
+    pub const PLAINTEXT_OUTPUT: &str = "output.csv";
+
+    // Job outputs that hold a secret key, or something as good as one
+    const SECRET_KEY_OUTPUTS: [&str; 3] = ["secret_key.key", "new_secret_key.key", "rekey_token.key"];
+
+    impl Artifact {
+        // Kind of a file in a job's output folder
+        pub fn of_output(name: &str) -> Artifact {
+            if name == PLAINTEXT_OUTPUT {
+                Artifact::Plaintext
+            } else if SECRET_KEY_OUTPUTS.contains(&name) || name.ends_with(".share") {
+                Artifact::SecretKey
+            } else {
+                Artifact::Output
+            }
+        }
+
+        pub fn ttl(self, config: &RetentionConfig) -> Duration {
+            Duration::from_secs(match self {
+                Artifact::Upload => config.upload_ttl_secs,
+                Artifact::Output => config.job_ttl_secs,
+                Artifact::SecretKey => config.secret_key_ttl_secs,
+                Artifact::Plaintext => config.plaintext_ttl_secs,
+            })
+        }
+    }
+
+    // Overwrites a file with zeros before deleting it, so its contents can't be read back from the blocks it used.
+    // Filesystems that copy on write, and SSDs that remap blocks, may still hold an old copy.
+    pub fn secure_delete(path: &Path) -> io::Result<()> {
+        let metadata = fs::symlink_metadata(path)?;
+        if metadata.is_file() {
+            let mut file = OpenOptions::new().write(true).open(path)?;
+            let zeros = [0u8; 64 * 1024];
+            let mut left = metadata.len();
+            while left > 0 {
+                let length = left.min(zeros.len() as u64) as usize;
+                file.write_all(&zeros[..length])?;
+                left -= length as u64;
+            }
+            file.sync_all()?;
+        }
+        fs::remove_file(path)
+    }
+
+    // Securely deletes a file once it is dropped, such as a download when the response is done with it
+    pub struct SecureDeleteOnDrop(pub PathBuf);
+
+    impl Drop for SecureDeleteOnDrop {
+        fn drop(&mut self) {
+            if let Err(error) = secure_delete(&self.0) {
+                warn!("Failed to delete {}: {}", self.0.display(), error);
+            }
+        }
+    }
+
+    #[derive(Debug, Default)]
+    pub struct Swept {
+        pub files: usize,
+        pub jobs: usize,
+    }
+
+    fn is_expired(path: &Path, ttl: Duration, now: SystemTime) -> bool {
+        fs::symlink_metadata(path)
+            .and_then(|metadata| metadata.modified())
+            .is_ok_and(|modified| now.duration_since(modified).is_ok_and(|age| age >= ttl))
+    }
+
+    // Securely deletes the files of a folder that are older than the TTL of their kind
+    fn sweep_folder(dir: &Path, kind: impl Fn(&str) -> Artifact, config: &RetentionConfig, now: SystemTime, swept: &mut Swept) -> io::Result<()> {
+        let entries = match fs::read_dir(dir) {
+            Ok(entries) => entries,
+            Err(error) if error.kind() == io::ErrorKind::NotFound => return Ok(()),
+            Err(error) => return Err(error),
+        };
+        for entry in entries {
+            let entry = entry?;
+            let name = entry.file_name().to_string_lossy().to_string();
+            if entry.file_type()?.is_dir() || !is_expired(&entry.path(), kind(&name).ttl(config), now) {
+                continue;
+            }
+            secure_delete(&entry.path())?;
+            swept.files += 1;
+        }
+        Ok(())
+    }
+
+    fn is_empty(dir: &Path) -> bool {
+        fs::read_dir(dir).map_or(true, |mut entries| entries.next().is_none())
+    }
+
+    // Deletes every expired file in the jobs folder, and the folders of jobs with nothing left once job_ttl_secs has
+    // passed. Jobs is_busy says are queued or running are left alone.
+    pub fn sweep(config: &RetentionConfig, is_busy: impl Fn(&str) -> bool) -> io::Result<Swept> {
+        let now = SystemTime::now();
+        let mut swept = Swept::default();
+        // Uploads the server never moved into a job, and downloads cut off by a crash
+        sweep_folder(&uploads_dir(), |_| Artifact::Upload, config, now, &mut swept)?;
+
+        let entries = match fs::read_dir(jobs_dir()) {
+            Ok(entries) => entries,
+            Err(error) if error.kind() == io::ErrorKind::NotFound => return Ok(swept),
+            Err(error) => return Err(error),
+        };
+        for entry in entries {
+            let entry = entry?;
+            let id = entry.file_name().to_string_lossy().to_string();
+            if !is_valid_job_id(&id) || is_busy(&id) {
+                continue;
+            }
+            let job_dir = entry.path();
+            let (input_dir, output_dir) = (job_dir.join("input"), job_dir.join("output"));
+            sweep_folder(&input_dir, |_| Artifact::Upload, config, now, &mut swept)?;
+            sweep_folder(&output_dir, Artifact::of_output, config, now, &mut swept)?;
+            // The job folder was last changed when the job was created
+            if is_empty(&input_dir) && is_empty(&output_dir) && is_expired(&job_dir, Artifact::Output.ttl(config), now) {
+                fs::remove_dir_all(&job_dir)?;
+                swept.jobs += 1;
+            }
+        }
+        Ok(swept)
+    }
+
+    // Sweeps the jobs folder every sweep_interval_secs on its own thread for as long as the server runs
+    pub fn start_sweeper(config: RetentionConfig, is_busy: impl Fn(&str) -> bool + Send + 'static) -> io::Result<()> {
+        thread::Builder::new().name("lattice-sweeper".to_string()).spawn(move || loop {
+            match sweep(&config, &is_busy) {
+                Ok(Swept { files: 0, jobs: 0 }) => {}
+                Ok(swept) => info!("Deleted {} expired files and {} empty jobs", swept.files, swept.jobs),
+                Err(error) => warn!("Sweeping {} failed: {}", jobs_dir().display(), error),
+            }
+            thread::sleep(Duration::from_secs(config.sweep_interval_secs));
+        })?;
+        Ok(())
+    }
+}
@@ -0,0 +1,385 @@
+pub mod lattice_rotation {
+    use std::error::Error;
+    use std::fs::{self, File, OpenOptions};
+    use std::io::{self, BufRead, Write};
+    use std::path::{Path, PathBuf};
+    use nalgebra::DMatrix;
+    use log::info;
+    use crate::encrypted::lattice_encrypt::{generate_key_matrices, key_fingerprint, write_matrix_file, write_numeric_matrix, write_public_key_file};
+    use crate::decrypted::lattice_decrypt::{is_numeric_matrix, process_file, read_key_fingerprint, read_numeric_matrix};
+    use crate::rekey::lattice_rekey::apply_rekey_token;
+    use crate::keyfile::lattice_keyfile::{read_secret_key, write_secret_key};
+    use crate::progress::lattice_progress::NoProgress;
+
+    // Name of the manifest kept in the rotated directory so an interrupted rotation can be resumed
+    pub const ROTATION_MANIFEST: &str = "rotation_manifest.txt";
+
+    // Decrypted values may differ by floating point noise between the old and new key, but never by more than this
+    const VERIFY_TOLERANCE: f64 = 1e-6;
+
+    // Progress of one rotation, read from and appended to the manifest:
+    //   #rotation-from <old fingerprint>
+    //   #rotation-to <new fingerprint>
+    //   #new-secret-key <path>
+    //   done <file>
+    //   #retired
+    struct Manifest {
+        path: PathBuf,
+        old_fingerprint: String,
+        new_fingerprint: String,
+        new_secret_key_path: String,
+        done: Vec<String>,
+        retired: bool,
+    }
+
+    impl Manifest {
+        fn read(path: &Path) -> Result<Option<Manifest>, Box<dyn Error>> {
+            if !path.exists() {
+                return Ok(None);
+            }
+            let mut manifest = Manifest {
+                path: path.to_path_buf(),
+                old_fingerprint: String::new(),
+                new_fingerprint: String::new(),
+                new_secret_key_path: String::new(),
+                done: Vec::new(),
+                retired: false,
+            };
+            for line in io::BufReader::new(File::open(path)?).lines() {
+                let line = line?;
+                let (name, value) = line.split_once(' ').unwrap_or((line.as_str(), ""));
+                match name {
+                    "#rotation-from" => manifest.old_fingerprint = value.to_string(),
+                    "#rotation-to" => manifest.new_fingerprint = value.to_string(),
+                    "#new-secret-key" => manifest.new_secret_key_path = value.to_string(),
+                    "#retired" => manifest.retired = true,
+                    "done" => manifest.done.push(value.to_string()),
+                    _ => return Err(format!("Unrecognised line in rotation manifest: {}", line).into()),
+                }
+            }
+            Ok(Some(manifest))
+        }
+
+        fn create(path: &Path, old_fingerprint: &str, new_fingerprint: &str, new_secret_key_path: &str) -> Result<Manifest, Box<dyn Error>> {
+            let mut file = File::create(path)?;
+            writeln!(file, "#rotation-from {}", old_fingerprint)?;
+            writeln!(file, "#rotation-to {}", new_fingerprint)?;
+            writeln!(file, "#new-secret-key {}", new_secret_key_path)?;
+            file.sync_all()?;
+            Ok(Manifest {
+                path: path.to_path_buf(),
+                old_fingerprint: old_fingerprint.to_string(),
+                new_fingerprint: new_fingerprint.to_string(),
+                new_secret_key_path: new_secret_key_path.to_string(),
+                done: Vec::new(),
+                retired: false,
+            })
+        }
+
+        // Appends a line and syncs it so a crash never loses a completed file
+        fn append(&mut self, line: &str) -> io::Result<()> {
+            let mut file = OpenOptions::new().append(true).open(&self.path)?;
+            writeln!(file, "{}", line)?;
+            file.sync_all()
+        }
+    }
+
+    // An encrypted matrix in either layout
+    struct EncryptedFile {
+        labels: Option<Vec<String>>,
+        matrix: DMatrix<f64>,
+    }
+
+    fn read_encrypted_file(path: &Path) -> Result<EncryptedFile, Box<dyn Error>> {
+        if is_numeric_matrix(path)? {
+            let (labels, matrix) = read_numeric_matrix(path)?;
+            Ok(EncryptedFile { labels: Some(labels), matrix })
+        } else {
+            Ok(EncryptedFile { labels: None, matrix: process_file(path)? })
+        }
+    }
+
+    fn write_encrypted_file(path: &Path, file: &EncryptedFile, fingerprint: &str) -> Result<(), Box<dyn Error>> {
+        let path = path.to_string_lossy();
+        match &file.labels {
+            Some(labels) => write_numeric_matrix(&path, labels, &file.matrix, Some(fingerprint)),
+            None => write_matrix_file(&path, &file.matrix, Some(fingerprint)),
+        }
+    }
+
+    // Checks that the old and new encrypted matrices decrypt to the same values under their own keys
+    fn decrypts_identically(old: &DMatrix<f64>, old_secret_key: &DMatrix<f64>, new: &DMatrix<f64>, new_secret_key: &DMatrix<f64>) -> bool {
+        let old_plain = old * old_secret_key;
+        let new_plain = new * new_secret_key;
+        old_plain.shape() == new_plain.shape()
+            && old_plain.iter().zip(new_plain.iter())
+                .all(|(a, b)| (a - b).abs() <= VERIFY_TOLERANCE * a.abs().max(1.0))
+    }
+
+    // Every regular file below dir except the manifest and left over temporary files, in a stable order
+    fn collect_files(dir: &Path, files: &mut Vec<PathBuf>) -> io::Result<()> {
+        let mut entries = fs::read_dir(dir)?.collect::<io::Result<Vec<_>>>()?;
+        entries.sort_by_key(|entry| entry.path());
+        for entry in entries {
+            let path = entry.path();
+            if path.is_dir() {
+                collect_files(&path, files)?;
+            } else if path.file_name().is_some_and(|name| name != ROTATION_MANIFEST)
+                && path.extension().is_none_or(|extension| extension != "rotating") {
+                files.push(path);
+            }
+        }
+        Ok(())
+    }
+
+    // Fails rather than replace a key file, which may be the only copy of a key
+    fn refuse_existing(path: &Path) -> Result<(), Box<dyn Error>> {
+        if path.exists() {
+            return Err(format!("{} already exists; move it away or choose another key output folder", path.display()).into());
+        }
+        Ok(())
+    }
+
+    // Re-encrypts every file in dir that references the old key onto a newly generated key, verifying each one
+    // before it replaces the original, then retires the old key by renaming it to <name>.retired. The new key is
+    // written to key_output_dir as secret_key_<fingerprint>.key and public_key_<fingerprint>.key. Progress is
+    // recorded in rotation_manifest.txt so running the same command again resumes an interrupted rotation.
+    // With dry_run the files that would be rotated are listed and nothing is written. The passphrase unwraps the
+    // old key and wraps the new one. Files that can't be read as text are skipped and listed in the result.
+    pub fn lattice_rotate_directory(dir: &str, old_secret_key_path: &str, key_output_dir: &str, dry_run: bool, passphrase: Option<&str>) -> Result<String, Box<dyn Error>> {
+        let dir = Path::new(dir);
+        if !Path::new(old_secret_key_path).exists() && Path::new(&format!("{}.retired", old_secret_key_path)).exists() {
+            return Err(format!("{} was already retired by a completed rotation", old_secret_key_path).into());
+        }
+        let old_secret_key = read_secret_key(old_secret_key_path, passphrase)?;
+        let old_fingerprint = key_fingerprint(&old_secret_key);
+        let manifest_path = dir.join(ROTATION_MANIFEST);
+
+        let mut files = Vec::new();
+        collect_files(dir, &mut files)?;
+
+        if dry_run {
+            let mut report = format!("Dry run: rotating key {}\n", old_fingerprint);
+            for file in &files {
+                match read_key_fingerprint(file) {
+                    Ok(fingerprint) if fingerprint.as_deref() == Some(old_fingerprint.as_str()) => {
+                        report.push_str(&format!("would rotate {}\n", file.display()));
+                    }
+                    Ok(_) => {}
+                    Err(error) => report.push_str(&format!("would skip {}: {}\n", file.display(), error)),
+                }
+            }
+            return Ok(report);
+        }
+
+        // Resume with the key an earlier run generated, or start a new rotation
+        let (mut manifest, new_secret_key) = match Manifest::read(&manifest_path)? {
+            Some(manifest) if manifest.old_fingerprint == old_fingerprint => {
+                let new_secret_key = read_secret_key(&manifest.new_secret_key_path, passphrase)?;
+                if key_fingerprint(&new_secret_key) != manifest.new_fingerprint {
+                    return Err(format!("New secret key {} does not match the rotation manifest", manifest.new_secret_key_path).into());
+                }
+                info!("Resuming rotation of key {} with {} files already done", old_fingerprint, manifest.done.len());
+                (manifest, new_secret_key)
+            }
+            Some(manifest) => {
+                return Err(format!("{} belongs to a rotation of key {}; finish or remove it first", manifest_path.display(), manifest.old_fingerprint).into());
+            }
+            None => {
+                let (_, new_secret_key) = match generate_key_matrices(old_secret_key.nrows(), &NoProgress) {
+                    Some(keys) => keys,
+                    None => return Err("Failed to generate a new key. Contact Administrator".into()),
+                };
+                // Named after the new key so a later rotation into the same folder can't replace it
+                let new_fingerprint = key_fingerprint(&new_secret_key);
+                let key_output_dir = Path::new(key_output_dir);
+                let new_secret_key_path = key_output_dir.join(format!("secret_key_{}.key", new_fingerprint));
+                let new_public_key_path = key_output_dir.join(format!("public_key_{}.key", new_fingerprint));
+                refuse_existing(&new_secret_key_path)?;
+                refuse_existing(&new_public_key_path)?;
+                let new_secret_key_path = new_secret_key_path.to_string_lossy().to_string();
+                write_secret_key(&new_secret_key_path, &new_secret_key, passphrase)?;
+                write_public_key_file(&new_public_key_path.to_string_lossy(), new_secret_key.norm())?;
+                let manifest = Manifest::create(&manifest_path, &old_fingerprint, &new_fingerprint, &new_secret_key_path)?;
+                (manifest, new_secret_key)
+            }
+        };
+
+        let new_encrypt_matrix = match new_secret_key.copy().try_inverse() {
+            Some(matrix) => matrix,
+            None => return Err("New secret key is not invertible".into()),
+        };
+        let token = &*old_secret_key * &*new_encrypt_matrix;
+
+        let mut rotated = 0;
+        let mut failed = Vec::new();
+        let mut skipped = Vec::new();
+        for file in &files {
+            let name = file.to_string_lossy().to_string();
+            if manifest.done.contains(&name) {
+                continue;
+            }
+            // A file that is not text, such as an image kept next to the matrices, can't reference any key
+            let fingerprint = match read_key_fingerprint(file) {
+                Ok(fingerprint) => fingerprint,
+                Err(error) => {
+                    skipped.push(format!("{} ({})", name, error));
+                    continue;
+                }
+            };
+            match fingerprint {
+                Some(fingerprint) if fingerprint == old_fingerprint => {}
+                // Replaced by an earlier run that stopped before recording it
+                Some(fingerprint) if fingerprint == manifest.new_fingerprint => {
+                    manifest.append(&format!("done {}", name))?;
+                    continue;
+                }
+                _ => continue,
+            }
+
+            let old = read_encrypted_file(file)?;
+            let new = EncryptedFile { labels: old.labels.clone(), matrix: apply_rekey_token(&old.matrix, &token)? };
+            if !decrypts_identically(&old.matrix, &old_secret_key, &new.matrix, &new_secret_key) {
+                failed.push(name);
+                continue;
+            }
+
+            // Write next to the original and rename over it so a crash never leaves a half written file
+            let temporary = PathBuf::from(format!("{}.rotating", name));
+            write_encrypted_file(&temporary, &new, &manifest.new_fingerprint)?;
+            fs::rename(&temporary, file)?;
+            manifest.append(&format!("done {}", name))?;
+            rotated += 1;
+            info!("Rotated {}", name);
+        }
+
+        if !failed.is_empty() {
+            return Err(format!("Rotated {} files but {} failed verification and were left unchanged: {}",
+                rotated, failed.len(), failed.join(", ")).into());
+        }
+
+        if !manifest.retired {
+            fs::rename(old_secret_key_path, format!("{}.retired", old_secret_key_path))?;
+            manifest.append("#retired")?;
+        }
+
+        let mut report = format!("Rotated {} files from key {} to key {}; old key retired", rotated, old_fingerprint, manifest.new_fingerprint);
+        if !skipped.is_empty() {
+            report.push_str(&format!("\nSkipped {} files that could not be read: {}", skipped.len(), skipped.join(", ")));
+        }
+        Ok(report)
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+        use crate::encrypted::lattice_encrypt::lattice_encrypt_csv_with_key;
+        use crate::decrypted::lattice_decrypt::lattice_decrypt_to_writer;
+
+        const CSVS: [(&str, &str); 2] = [("grades.csv", "name,score\nann,91\nbob,78\n"), ("ages.csv", "name,age\ncarol,34\n")];
+
+        // A folder of matrices encrypted under one key, with that key in old_secret_key.key next to the folder
+        fn encrypted_dir(name: &str) -> (PathBuf, String) {
+            let root = std::env::temp_dir().join(format!("lattice-test-rotation-{}-{}", name, std::process::id()));
+            let _ = fs::remove_dir_all(&root);
+            fs::create_dir_all(root.join("data")).unwrap();
+            fs::create_dir_all(root.join("keys")).unwrap();
+            let (_, secret_key) = generate_key_matrices(16, &NoProgress).unwrap();
+            let old_secret_key_path = root.join("old_secret_key.key").to_string_lossy().to_string();
+            write_secret_key(&old_secret_key_path, &secret_key, None).unwrap();
+            for (file, csv) in CSVS {
+                let csv_path = root.join(file).to_string_lossy().to_string();
+                fs::write(&csv_path, csv).unwrap();
+                lattice_encrypt_csv_with_key(&csv_path, &root.to_string_lossy(), &secret_key, &NoProgress).unwrap();
+                fs::rename(root.join("encrypted_matrix.csv"), root.join("data").join(file)).unwrap();
+            }
+            fs::write(root.join("data").join("notes.txt"), "not a matrix\n").unwrap();
+            (root, old_secret_key_path)
+        }
+
+        fn rotate(root: &Path, old_secret_key_path: &str) -> Result<String, Box<dyn Error>> {
+            lattice_rotate_directory(&root.join("data").to_string_lossy(), old_secret_key_path, &root.join("keys").to_string_lossy(), false, None)
+        }
+
+        fn manifest(root: &Path) -> Manifest {
+            Manifest::read(&root.join("data").join(ROTATION_MANIFEST)).unwrap().unwrap()
+        }
+
+        // Every matrix must carry the new key's fingerprint and decrypt under it to the CSV it was made from
+        fn assert_rotated(root: &Path) {
+            let manifest = manifest(root);
+            let new_secret_key = read_secret_key(&manifest.new_secret_key_path, None).unwrap();
+            for (file, csv) in CSVS {
+                let path = root.join("data").join(file);
+                assert_eq!(read_key_fingerprint(&path).unwrap(), Some(manifest.new_fingerprint.clone()));
+                let mut output = Vec::new();
+                lattice_decrypt_to_writer(&path.to_string_lossy(), &new_secret_key, &mut output, &NoProgress).unwrap();
+                assert_eq!(String::from_utf8(output).unwrap(), csv);
+            }
+            assert_eq!(fs::read_to_string(root.join("data").join("notes.txt")).unwrap(), "not a matrix\n");
+        }
+
+        // Huge values overflow when decrypted, so this matrix can never verify
+        fn write_unverifiable_matrix(root: &Path, old_secret_key_path: &str) -> PathBuf {
+            let fingerprint = key_fingerprint(&read_secret_key(old_secret_key_path, None).unwrap());
+            let path = root.join("data").join("overflow.csv");
+            write_matrix_file(&path.to_string_lossy(), &DMatrix::from_element(2, 16, f64::MAX), Some(&fingerprint)).unwrap();
+            path
+        }
+
+        #[test]
+        fn rotation_rekeys_every_file_and_retires_the_old_key() {
+            let (root, old_secret_key_path) = encrypted_dir("complete");
+            let report = rotate(&root, &old_secret_key_path).unwrap();
+            assert!(report.starts_with("Rotated 2 files"), "{}", report);
+            assert_rotated(&root);
+            assert!(manifest(&root).retired);
+            assert!(!Path::new(&old_secret_key_path).exists());
+            assert!(Path::new(&format!("{}.retired", old_secret_key_path)).exists());
+
+            let error = rotate(&root, &old_secret_key_path).unwrap_err().to_string();
+            assert!(error.contains("already retired"), "{}", error);
+            let _ = fs::remove_dir_all(&root);
+        }
+
+        #[test]
+        fn failed_verification_leaves_the_file_and_the_old_key() {
+            let (root, old_secret_key_path) = encrypted_dir("verification");
+            let overflow = write_unverifiable_matrix(&root, &old_secret_key_path);
+            let before = fs::read_to_string(&overflow).unwrap();
+
+            let error = rotate(&root, &old_secret_key_path).unwrap_err().to_string();
+            assert!(error.contains("Rotated 2 files but 1 failed verification"), "{}", error);
+            assert_eq!(fs::read_to_string(&overflow).unwrap(), before);
+            assert!(Path::new(&old_secret_key_path).exists());
+            assert!(!manifest(&root).retired);
+            assert_rotated(&root);
+            let _ = fs::remove_dir_all(&root);
+        }
+
+        #[test]
+        fn interrupted_rotation_resumes_with_the_same_key() {
+            let (root, old_secret_key_path) = encrypted_dir("resume");
+            let overflow = write_unverifiable_matrix(&root, &old_secret_key_path);
+            assert!(rotate(&root, &old_secret_key_path).is_err());
+            let interrupted = manifest(&root);
+            assert_eq!(interrupted.done.len(), 2);
+
+            // As if the last file was replaced but the run stopped before recording it
+            let manifest_path = root.join("data").join(ROTATION_MANIFEST);
+            let contents = fs::read_to_string(&manifest_path).unwrap();
+            let last_done = contents.lines().rfind(|line| line.starts_with("done ")).unwrap().to_string();
+            fs::write(&manifest_path, contents.replace(&format!("{}\n", last_done), "")).unwrap();
+            fs::remove_file(&overflow).unwrap();
+
+            let report = rotate(&root, &old_secret_key_path).unwrap();
+            assert!(report.starts_with("Rotated 0 files"), "{}", report);
+            let resumed = manifest(&root);
+            assert_eq!(resumed.new_fingerprint, interrupted.new_fingerprint);
+            assert_eq!(resumed.done.len(), 2);
+            assert!(resumed.retired);
+            assert_rotated(&root);
+            let _ = fs::remove_dir_all(&root);
+        }
+    }
+}
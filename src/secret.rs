@@ -0,0 +1,83 @@
+pub mod lattice_secret {
+    use std::fmt::{self, Write};
+    use std::ops::Deref;
+    use nalgebra::DMatrix;
+    use zeroize::{Zeroize, Zeroizing};
+
+    // A matrix holding key material or plaintext: a secret key, the encrypt matrix it is the inverse of, or numbers
+    // read from a CSV before they are encrypted. Its entries are overwritten with zeros when it is dropped. It is not
+    // Clone, so every copy is made on purpose with copy(), and Debug leaves the entries out.
+    pub struct SecretMatrix(DMatrix<f64>);
+
+    impl SecretMatrix {
+        pub fn new(matrix: DMatrix<f64>) -> SecretMatrix {
+            SecretMatrix(matrix)
+        }
+
+        pub fn set(&mut self, row: usize, column: usize, value: f64) {
+            self.0[(row, column)] = value;
+        }
+
+        pub fn copy(&self) -> SecretMatrix {
+            SecretMatrix(self.0.clone())
+        }
+
+        // Inverts the matrix where it is, so no unprotected copy is left behind. None when it is not invertible.
+        // nalgebra's LU decomposition still works on a temporary copy it does not overwrite.
+        pub fn try_inverse(mut self) -> Option<SecretMatrix> {
+            if !self.0.is_square() || !self.0.try_inverse_mut() {
+                return None;
+            }
+            Some(self)
+        }
+
+        pub fn map(&self, f: impl FnMut(f64) -> f64) -> SecretMatrix {
+            SecretMatrix(self.0.map(f))
+        }
+
+        // The matrix as written to secret key files
+        pub fn to_text(&self) -> Zeroizing<String> {
+            secret_text(&self.0)
+        }
+    }
+
+    impl Deref for SecretMatrix {
+        type Target = DMatrix<f64>;
+
+        fn deref(&self) -> &DMatrix<f64> {
+            &self.0
+        }
+    }
+
+    impl Drop for SecretMatrix {
+        fn drop(&mut self) {
+            self.0.as_mut_slice().zeroize();
+        }
+    }
+
+    impl fmt::Debug for SecretMatrix {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            write!(f, "SecretMatrix({}x{})", self.0.nrows(), self.0.ncols())
+        }
+    }
+
+    // Counts the bytes a value formats to
+    struct Length(usize);
+
+    impl Write for Length {
+        fn write_str(&mut self, text: &str) -> fmt::Result {
+            self.0 += text.len();
+            Ok(())
+        }
+    }
+
+    // Formats a matrix holding key material. The string is sized up front, since growing it would leave copies
+    // behind in freed memory.
+    pub fn secret_text(matrix: &DMatrix<f64>) -> Zeroizing<String> {
+        let mut length = Length(0);
+        let _ = write!(length, "{}", matrix);
+        let mut text = Zeroizing::new(String::with_capacity(length.0));
+        let _ = write!(*text, "{}", matrix);
+        text
+    }
+}
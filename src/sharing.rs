@@ -0,0 +1,305 @@
+pub mod lattice_sharing {
+    use std::error::Error;
+    use std::fmt;
+    use std::fs;
+    use std::path::Path;
+    use nalgebra::DMatrix;
+    use log::info;
+    use zeroize::Zeroizing;
+    use crate::encrypted::lattice_encrypt::{key_fingerprint, KEY_FINGERPRINT_HEADER};
+    use crate::decrypted::lattice_decrypt::parse_secret_matrix;
+    use crate::keyfile::lattice_keyfile::read_secret_key;
+    use crate::secret::lattice_secret::{secret_text, SecretMatrix};
+
+    // First line of a key share file. The rest of the file is:
+    //   #key-fingerprint <fingerprint of the whole secret key>
+    //   #threshold <shares needed>
+    //   #share-index <x coordinate, 1 to 255>
+    //   <hex of the share bytes>
+    pub const KEY_SHARE_MARKER: &str = "#lattice-key-share v1";
+
+    // One k-of-n Shamir share of the text of a secret key. The share bytes are zeroed when it is dropped.
+    #[derive(Clone)]
+    pub struct KeyShare {
+        pub fingerprint: String,
+        pub threshold: u8,
+        pub index: u8,
+        pub data: Zeroizing<Vec<u8>>,
+    }
+
+    // Leaves out the share bytes so a share can't end up in a log
+    impl fmt::Debug for KeyShare {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            write!(f, "KeyShare({} of key {}, threshold {})", self.index, self.fingerprint, self.threshold)
+        }
+    }
+
+    // Arithmetic in GF(2^8) with the AES polynomial x^8 + x^4 + x^3 + x + 1, using log tables with generator 3
+    struct Field {
+        exp: [u8; 510],
+        log: [u8; 256],
+    }
+
+    impl Field {
+        fn new() -> Field {
+            let mut field = Field { exp: [0; 510], log: [0; 256] };
+            let mut value: u8 = 1;
+            for power in 0..255 {
+                field.exp[power] = value;
+                field.exp[power + 255] = value;
+                field.log[value as usize] = power as u8;
+                // Multiply by the generator 3 = x + 1
+                let doubled = (value << 1) ^ if value & 0x80 != 0 { 0x1b } else { 0 };
+                value ^= doubled;
+            }
+            field
+        }
+
+        fn mul(&self, a: u8, b: u8) -> u8 {
+            if a == 0 || b == 0 {
+                return 0;
+            }
+            self.exp[self.log[a as usize] as usize + self.log[b as usize] as usize]
+        }
+
+        fn div(&self, a: u8, b: u8) -> u8 {
+            if a == 0 {
+                return 0;
+            }
+            self.exp[self.log[a as usize] as usize + 255 - self.log[b as usize] as usize]
+        }
+    }
+
+    // Splits bytes into `shares` shares so that any `threshold` of them recover the secret and fewer reveal nothing
+    fn split_bytes(secret: &[u8], threshold: u8, shares: u8) -> Vec<(u8, Zeroizing<Vec<u8>>)> {
+        let field = Field::new();
+        let mut output: Vec<(u8, Zeroizing<Vec<u8>>)> = (1..=shares).map(|x| (x, Zeroizing::new(Vec::with_capacity(secret.len())))).collect();
+        let mut coefficients = Zeroizing::new(vec![0u8; threshold as usize]);
+        for &byte in secret {
+            // Random polynomial of degree threshold - 1 whose constant term is the secret byte
+            coefficients[0] = byte;
+            for coefficient in coefficients.iter_mut().skip(1) {
+                *coefficient = rand::random();
+            }
+            for (x, data) in output.iter_mut() {
+                // Horner evaluation at x
+                let y = coefficients.iter().rev().fold(0u8, |acc, &c| field.mul(acc, *x) ^ c);
+                data.push(y);
+            }
+        }
+        output
+    }
+
+    // Lagrange interpolation at zero over exactly the given shares
+    fn combine_bytes(shares: &[(u8, &[u8])]) -> Zeroizing<Vec<u8>> {
+        let field = Field::new();
+        let length = shares[0].1.len();
+        Zeroizing::new((0..length).map(|position| {
+            shares.iter().enumerate().fold(0u8, |secret, (i, &(xi, data))| {
+                let basis = shares.iter().enumerate().filter(|&(j, _)| j != i)
+                    .fold(1u8, |product, (_, &(xj, _))| field.mul(product, field.div(xj, xj ^ xi)));
+                secret ^ field.mul(data[position], basis)
+            })
+        }).collect())
+    }
+
+    // Splits a secret key into `shares` shares of which any `threshold` can rebuild it
+    pub fn split_secret_key(secret_key: &DMatrix<f64>, threshold: u8, shares: u8) -> Result<Vec<KeyShare>, Box<dyn Error>> {
+        if threshold < 2 || threshold > shares {
+            return Err(format!("Threshold must be between 2 and the number of shares, got {} of {}", threshold, shares).into());
+        }
+        let fingerprint = key_fingerprint(secret_key);
+        let key_text = secret_text(secret_key);
+        Ok(split_bytes(key_text.as_bytes(), threshold, shares).into_iter()
+            .map(|(index, data)| KeyShare { fingerprint: fingerprint.clone(), threshold, index, data })
+            .collect())
+    }
+
+    // Rebuilds a secret key from at least `threshold` shares of the same key, rejecting mixed or repeated shares
+    pub fn combine_key_shares(shares: &[KeyShare]) -> Result<SecretMatrix, Box<dyn Error>> {
+        let first = shares.first().ok_or("No key shares were given")?;
+        for share in shares {
+            if share.fingerprint != first.fingerprint {
+                return Err(format!("Key share {} belongs to key {} but share {} belongs to key {}",
+                    share.index, share.fingerprint, first.index, first.fingerprint).into());
+            }
+            if share.threshold != first.threshold || share.data.len() != first.data.len() || share.index == 0 {
+                return Err(format!("Key share {} does not match the other shares of key {}", share.index, first.fingerprint).into());
+            }
+        }
+
+        let mut points: Vec<(u8, &[u8])> = Vec::new();
+        for share in shares {
+            if points.iter().any(|&(index, _)| index == share.index) {
+                return Err(format!("Key share {} was given more than once", share.index).into());
+            }
+            points.push((share.index, &share.data));
+        }
+        if points.len() < first.threshold as usize {
+            return Err(format!("Key {} needs {} shares but only {} were given", first.fingerprint, first.threshold, points.len()).into());
+        }
+        points.truncate(first.threshold as usize);
+
+        // A corrupted share still interpolates to something, so check the result against the fingerprint
+        let key_text = combine_bytes(&points);
+        let secret_key = std::str::from_utf8(&key_text).map_err(|e| e.into()).and_then(parse_secret_matrix)
+            .map_err(|_| format!("Key shares do not rebuild key {}", first.fingerprint))?;
+        if key_fingerprint(&secret_key) != first.fingerprint {
+            return Err(format!("Key shares do not rebuild key {}", first.fingerprint).into());
+        }
+        Ok(secret_key)
+    }
+
+    pub fn write_key_share(file_path: &str, share: &KeyShare) -> Result<(), Box<dyn Error>> {
+        let data = Zeroizing::new(share.data.iter().map(|b| format!("{:02x}", b)).collect::<String>());
+        fs::write(file_path, format!("{}\n{} {}\n#threshold {}\n#share-index {}\n{}\n",
+            KEY_SHARE_MARKER, KEY_FINGERPRINT_HEADER, share.fingerprint, share.threshold, share.index, *data))?;
+        Ok(())
+    }
+
+    pub fn read_key_share<P: AsRef<Path>>(path: P) -> Result<KeyShare, Box<dyn Error>> {
+        let contents = Zeroizing::new(fs::read_to_string(&path)?);
+        parse_key_share(&contents, &path.as_ref().display().to_string())
+    }
+
+    // Parses the contents of a key share file, named in errors by name
+    pub fn parse_key_share(contents: &str, name: &str) -> Result<KeyShare, Box<dyn Error>> {
+        let mut lines = contents.lines();
+        if lines.next() != Some(KEY_SHARE_MARKER) {
+            return Err(format!("{} is not a key share file", name).into());
+        }
+
+        let mut share = KeyShare { fingerprint: String::new(), threshold: 0, index: 0, data: Zeroizing::new(Vec::new()) };
+        for line in lines {
+            let (name, value) = line.split_once(' ').unwrap_or((line, ""));
+            match name {
+                KEY_FINGERPRINT_HEADER => share.fingerprint = value.to_string(),
+                "#threshold" => share.threshold = value.parse()?,
+                "#share-index" => share.index = value.parse()?,
+                _ if !line.trim().is_empty() => {
+                    let line = line.trim();
+                    if !line.is_ascii() || !line.len().is_multiple_of(2) {
+                        return Err("Invalid share data".into());
+                    }
+                    let mut data = Zeroizing::new(Vec::with_capacity(line.len() / 2));
+                    for i in (0..line.len()).step_by(2) {
+                        data.push(u8::from_str_radix(&line[i..i + 2], 16)?);
+                    }
+                    share.data = data;
+                }
+                _ => {}
+            }
+        }
+        if share.fingerprint.is_empty() || share.threshold == 0 || share.index == 0 || share.data.is_empty() {
+            return Err(format!("Key share file {} is incomplete", name).into());
+        }
+        Ok(share)
+    }
+
+    // Splits a secret key file into key_share_<i>.share files in output_dir, to be handed to different people
+    pub fn lattice_split_key_files(secret_key_path: &str, threshold: u8, shares: u8, output_dir: &str, passphrase: Option<&str>) -> Result<String, Box<dyn Error>> {
+        let secret_key = read_secret_key(secret_key_path, passphrase)?;
+        let key_shares = split_secret_key(&secret_key, threshold, shares)?;
+        let output_dir = Path::new(output_dir);
+        for share in &key_shares {
+            write_key_share(&output_dir.join(format!("key_share_{}.share", share.index)).to_string_lossy(), share)?;
+        }
+        info!("Split key {} into {} shares with threshold {}", key_fingerprint(&secret_key), shares, threshold);
+
+        Ok(format!("Split key {} into {} shares, any {} of which can decrypt", key_fingerprint(&secret_key), shares, threshold))
+    }
+
+    // Rebuilds a secret key from share files
+    pub fn lattice_combine_key_share_files<P: AsRef<Path>>(share_paths: &[P]) -> Result<SecretMatrix, Box<dyn Error>> {
+        let shares = share_paths.iter().map(read_key_share).collect::<Result<Vec<KeyShare>, _>>()?;
+        combine_key_shares(&shares)
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        fn test_key(seed: f64) -> DMatrix<f64> {
+            DMatrix::from_fn(3, 3, |i, j| seed + (i * 3 + j) as f64 * 1.5 - 4.0)
+        }
+
+        fn error_of(shares: &[KeyShare]) -> String {
+            combine_key_shares(shares).unwrap_err().to_string()
+        }
+
+        #[test]
+        fn field_division_undoes_multiplication() {
+            let field = Field::new();
+            for a in 0..=255u8 {
+                for b in 1..=255u8 {
+                    assert_eq!(field.div(field.mul(a, b), b), a);
+                }
+            }
+        }
+
+        #[test]
+        fn any_threshold_shares_in_any_order_rebuild_the_key() {
+            let key = test_key(0.25);
+            let shares = split_secret_key(&key, 3, 5).unwrap();
+            for subset in [[0, 1, 2], [4, 2, 0], [3, 4, 1], [2, 3, 4], [1, 0, 4]] {
+                let picked: Vec<KeyShare> = subset.iter().map(|&i| shares[i].clone()).collect();
+                assert_eq!(*combine_key_shares(&picked).unwrap(), key, "shares {:?}", subset);
+            }
+            // More shares than needed also rebuild the key
+            assert_eq!(*combine_key_shares(&shares).unwrap(), key);
+        }
+
+        #[test]
+        fn shares_round_trip_through_their_file_format() {
+            let key = test_key(7.0);
+            let shares = split_secret_key(&key, 2, 3).unwrap();
+            let path = std::env::temp_dir().join(format!("lattice-test-share-{}.share", std::process::id())).to_string_lossy().into_owned();
+            write_key_share(&path, &shares[2]).unwrap();
+            let read = read_key_share(&path).unwrap();
+            let _ = fs::remove_file(&path);
+            assert_eq!((read.index, read.threshold, &read.fingerprint, &read.data), (3, 2, &shares[2].fingerprint, &shares[2].data));
+            assert_eq!(*combine_key_shares(&[read, shares[0].clone()]).unwrap(), key);
+        }
+
+        #[test]
+        fn fewer_than_threshold_shares_are_refused() {
+            let shares = split_secret_key(&test_key(1.0), 3, 5).unwrap();
+            assert!(error_of(&shares[1..3]).contains("needs 3 shares but only 2 were given"));
+            assert!(error_of(&[]).contains("No key shares were given"));
+        }
+
+        #[test]
+        fn duplicate_shares_are_refused() {
+            let shares = split_secret_key(&test_key(1.0), 3, 5).unwrap();
+            let repeated = [shares[0].clone(), shares[1].clone(), shares[0].clone()];
+            assert!(error_of(&repeated).contains("Key share 1 was given more than once"));
+        }
+
+        #[test]
+        fn shares_of_different_keys_are_refused() {
+            let first = split_secret_key(&test_key(1.0), 2, 3).unwrap();
+            let second = split_secret_key(&test_key(2.0), 2, 3).unwrap();
+            let error = error_of(&[first[0].clone(), second[1].clone()]);
+            assert!(error.contains("Key share 2 belongs to key"), "{}", error);
+        }
+
+        #[test]
+        fn corrupted_share_is_refused() {
+            let mut shares = split_secret_key(&test_key(1.0), 2, 3).unwrap();
+            shares[1].data[0] ^= 0x01;
+            assert!(error_of(&shares[..2]).contains("Key shares do not rebuild key"));
+        }
+
+        #[test]
+        fn debug_leaves_out_the_share_bytes() {
+            let share = KeyShare { fingerprint: "ab12".to_string(), threshold: 2, index: 3, data: Zeroizing::new(vec![0xfe; 4]) };
+            assert_eq!(format!("{:?}", share), "KeyShare(3 of key ab12, threshold 2)");
+        }
+
+        #[test]
+        fn threshold_out_of_range_is_refused() {
+            assert!(split_secret_key(&test_key(1.0), 1, 3).is_err());
+            assert!(split_secret_key(&test_key(1.0), 4, 3).is_err());
+        }
+    }
+}
@@ -0,0 +1,107 @@
+pub mod lattice_tls {
+    use std::error::Error;
+    use std::fmt;
+    use std::fs::File;
+    use std::io::BufReader;
+    use std::sync::{Arc, RwLock};
+    use log::{error, info};
+    use rustls::crypto::ring::sign::any_supported_type;
+    use rustls::pki_types::{PrivateKeyDer, PrivatePkcs8KeyDer};
+    use rustls::server::{ClientHello, ResolvesServerCert};
+    use rustls::sign::CertifiedKey;
+    use rustls::ServerConfig;
+    use sha2::{Digest, Sha256};
+    use crate::keyfile::lattice_keyfile::to_hex;
+
+    // Where the certificate came from, which decides whether it can be reloaded
+    enum Source {
+        Files { cert_file: String, key_file: String },
+        SelfSigned,
+    }
+
+    // The server's certificate. Every new connection is handed the current one, connections already open keep the
+    // one they started with.
+    pub struct Certificates {
+        source: Source,
+        current: RwLock<Arc<CertifiedKey>>,
+    }
+
+    impl fmt::Debug for Certificates {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            match &self.source {
+                Source::Files { cert_file, key_file } => write!(f, "Certificates({}, {})", cert_file, key_file),
+                Source::SelfSigned => write!(f, "Certificates(self-signed)"),
+            }
+        }
+    }
+
+    impl ResolvesServerCert for Certificates {
+        fn resolve(&self, _: ClientHello) -> Option<Arc<CertifiedKey>> {
+            Some(self.current.read().unwrap_or_else(|poisoned| poisoned.into_inner()).clone())
+        }
+    }
+
+    impl Certificates {
+        pub fn from_files(cert_file: &str, key_file: &str) -> Result<Certificates, Box<dyn Error>> {
+            let source = Source::Files { cert_file: cert_file.to_string(), key_file: key_file.to_string() };
+            Ok(Certificates { source, current: RwLock::new(Arc::new(read_certified_key(cert_file, key_file)?)) })
+        }
+
+        // A certificate for the given host names made at startup and kept in memory, for development only. Clients
+        // don't trust it, so they have to be told to skip verification or to pin the logged fingerprint.
+        pub fn self_signed(names: Vec<String>) -> Result<Certificates, Box<dyn Error>> {
+            let generated = rcgen::generate_simple_self_signed(names.clone())?;
+            let key = PrivateKeyDer::Pkcs8(PrivatePkcs8KeyDer::from(generated.key_pair.serialize_der()));
+            let certified_key = CertifiedKey::new(vec![generated.cert.der().clone()], any_supported_type(&key)?);
+            info!("Generated a self-signed certificate for {}, SHA-256 fingerprint {}", names.join(", "), to_hex(&Sha256::digest(generated.cert.der())));
+            Ok(Certificates { source: Source::SelfSigned, current: RwLock::new(Arc::new(certified_key)) })
+        }
+
+        // Reads the certificate and key files again. The current certificate stays in use when they can't be read.
+        pub fn reload(&self) -> Result<(), Box<dyn Error>> {
+            let Source::Files { cert_file, key_file } = &self.source else {
+                return Err("A self-signed certificate has no files to reload".into());
+            };
+            let certified_key = read_certified_key(cert_file, key_file)?;
+            *self.current.write().unwrap_or_else(|poisoned| poisoned.into_inner()) = Arc::new(certified_key);
+            Ok(())
+        }
+    }
+
+    // Reads a PEM certificate chain, the server's own certificate first, and the PEM private key it was issued for
+    fn read_certified_key(cert_file: &str, key_file: &str) -> Result<CertifiedKey, Box<dyn Error>> {
+        let certs = File::open(cert_file)
+            .and_then(|file| rustls_pemfile::certs(&mut BufReader::new(file)).collect::<Result<Vec<_>, _>>())
+            .map_err(|error| format!("Cannot read TLS certificate {}: {}", cert_file, error))?;
+        if certs.is_empty() {
+            return Err(format!("TLS certificate {} holds no PEM certificate", cert_file).into());
+        }
+        let key = File::open(key_file)
+            .and_then(|file| rustls_pemfile::private_key(&mut BufReader::new(file)))
+            .map_err(|error| format!("Cannot read TLS key {}: {}", key_file, error))?
+            .ok_or_else(|| format!("TLS key {} holds no PEM private key", key_file))?;
+        let signing_key = any_supported_type(&key).map_err(|error| format!("TLS key {} can't be used: {}", key_file, error))?;
+        Ok(CertifiedKey::new(certs, signing_key))
+    }
+
+    pub fn server_config(certificates: Arc<Certificates>) -> ServerConfig {
+        ServerConfig::builder().with_no_client_auth().with_cert_resolver(certificates)
+    }
+
+    // Reloads the certificate files whenever the server is sent SIGHUP, so a renewed certificate is picked up
+    // without dropping connections. Must be called from within the server's runtime.
+    #[cfg(unix)]
+    pub fn reload_on_sighup(certificates: Arc<Certificates>) -> std::io::Result<()> {
+        use actix_web::rt::signal::unix::{signal, SignalKind};
+        let mut hangups = signal(SignalKind::hangup())?;
+        actix_web::rt::spawn(async move {
+            while hangups.recv().await.is_some() {
+                match certificates.reload() {
+                    Ok(()) => info!("Reloaded the TLS certificate"),
+                    Err(error) => error!("Failed to reload the TLS certificate, still serving the previous one: {}", error),
+                }
+            }
+        });
+        Ok(())
+    }
+}
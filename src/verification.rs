@@ -4,6 +4,8 @@ pub mod lattice_verification {
     use std::io::{self, BufRead};
     use std::path::Path;
     use std::env;
+    use std::error::Error;
+    use crate::keyfile::lattice_keyfile::{parse_secret_key, read_secret_key};
 
     fn initialize_file_reader<P: AsRef<Path>>(path: P) -> io::Result<io::BufReader<File>> {
         let file = File::open(path)?;
@@ -20,7 +22,7 @@ pub mod lattice_verification {
             let mut current_number = String::new();
             
             for fart in line.chars() {
-                if fart.is_digit(10) || fart == '.' || fart == '-' {
+                if fart.is_ascii_digit() || fart == '.' || fart == '-' {
                     current_number.push(fart);
                 } else if !current_number.is_empty() {
                     if let Ok(num) = current_number.parse::<f64>() {
@@ -42,8 +44,14 @@ pub mod lattice_verification {
         // Convert pushed numbers array to matrix<f64>
         // Convert Vec<Vec<u64>> to nalgebra DMatrix
         let rows = matrix.len();
+        if rows == 0 {
+            return Err("File does not contain a matrix".into());
+        }
 
-        let cols = matrix[0].len(); // Assuming all inner vectors have the same length
+        let cols = matrix[0].len();
+        if matrix.iter().any(|numbers| numbers.len() != cols) {
+            return Err("Matrix rows have different lengths".into());
+        }
         let output_matrix = DMatrix::from_fn(rows, cols, |i, j| matrix[i][j]);
 
         Ok(output_matrix)
@@ -51,8 +59,28 @@ pub mod lattice_verification {
     // Function to read a number from a file.
     fn read_number<P: AsRef<Path>>(filename: P) -> io::Result<f64> {
         let file = File::open(filename)?;
-        let line = io::BufReader::new(file).lines().next().ok_or(io::Error::new(io::ErrorKind::Other, "No line found"))??;
-        line.parse().map_err(|_| io::Error::new(io::ErrorKind::Other, "Failed to parse number"))
+        let line = io::BufReader::new(file).lines().next().ok_or(io::Error::other("No line found"))??;
+        line.parse().map_err(|_| io::Error::other("Failed to parse number"))
+    }
+
+    // A public key is the norm of its secret key
+    pub fn verify_key_pair(secret_key: &DMatrix<f64>, public_key: f64) -> bool {
+        secret_key.norm() == public_key
+    }
+
+    // Checks a secret key file, wrapped or not, against a public key file
+    pub fn lattice_verify_key_files<P: AsRef<Path>>(secret_key_path: P, public_key_path: P, passphrase: Option<&str>) -> Result<bool, Box<dyn Error>> {
+        let secret_key = read_secret_key(secret_key_path, passphrase)?;
+        let public_key = read_number(public_key_path)?;
+        Ok(verify_key_pair(&secret_key, public_key))
+    }
+
+    // Checks the contents of a secret key file, wrapped or not, against the contents of a public key file, for keys
+    // that were never written to disk such as ones sent to the API
+    pub fn verify_key_contents(secret_key: &str, public_key: &str, passphrase: Option<&str>) -> Result<bool, Box<dyn Error>> {
+        let secret_key = parse_secret_key(secret_key, passphrase)?;
+        let public_key = public_key.lines().next().ok_or("No line found")?.trim().parse().map_err(|_| "Failed to parse number")?;
+        Ok(verify_key_pair(&secret_key, public_key))
     }
 
     pub fn lattice_public_secret_verification() {
@@ -73,7 +101,7 @@ pub mod lattice_verification {
 
         let trace = matrix.norm();
 
-        if trace == number {
+        if verify_key_pair(&matrix, number) {
             println!("The norm of the matrix equals the number: {}", number);
         } else {
             println!("The norm of the matrix does not equal the number: {} != {}", trace, number);